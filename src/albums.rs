@@ -0,0 +1,457 @@
+use crate::tags::TrackMetadata;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+/// Album artist values that mark a various-artists release.
+const VARIOUS_ARTISTS: &[&str] = &["various artists", "various", "va", "v.a.", "v/a"];
+
+/// Distinct track artists on one album (in one album directory, without an
+/// album artist) from which the album is treated as a compilation.
+pub const MANY_ARTISTS_THRESHOLD: usize = 3;
+
+/// Why an album was treated as a compilation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompilationRule {
+    /// The compilation flag (TCMP / cpil / COMPILATION) is set
+    Flag,
+    /// The album artist is "Various Artists" or similar
+    VariousArtists,
+    /// Many distinct artists share the album within one directory
+    ManyArtists,
+}
+
+impl fmt::Display for CompilationRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompilationRule::Flag => f.write_str("compilation flag set"),
+            CompilationRule::VariousArtists => f.write_str("album artist is Various Artists"),
+            CompilationRule::ManyArtists => {
+                write!(f, "{}+ artists share the album", MANY_ARTISTS_THRESHOLD)
+            }
+        }
+    }
+}
+
+/// Album tag (case-insensitive) plus MusicBrainz release id.
+pub(crate) type AlbumKey = (String, Option<String>);
+
+/// Key that identifies "the same album" within a batch: the album tag
+/// (case-insensitive) plus the MusicBrainz release, if tagged.
+pub(crate) fn album_key(meta: &TrackMetadata) -> AlbumKey {
+    (normalize(&meta.album), meta.musicbrainz.release_id.clone())
+}
+
+fn normalize(s: &str) -> String {
+    s.trim().to_lowercase()
+}
+
+/// The artist without featured guests: "Band feat. Guest", "Band ft Guest",
+/// "Band (feat. Guest)" and "Band with Guest" all give "Band".
+fn main_artist(artist: &str) -> &str {
+    const FEATURING: &[&str] = &[
+        " feat.",
+        " feat ",
+        " ft.",
+        " ft ",
+        " featuring ",
+        " with ",
+        " (feat",
+        " (ft",
+    ];
+    // ASCII lowercasing keeps the byte offsets of `artist`
+    let lower = artist.to_ascii_lowercase();
+    match FEATURING.iter().filter_map(|f| lower.find(f)).min() {
+        Some(end) if !artist[..end].trim().is_empty() => artist[..end].trim(),
+        _ => artist.trim(),
+    }
+}
+
+/// Tracks grouped by album: per album directory (see [`album_dir`]) and
+/// [`album_key`], so same-named albums elsewhere in the batch stay apart.
+fn group_albums<'a, I>(tracks: I) -> Vec<Vec<&'a mut TrackMetadata>>
+where
+    I: IntoIterator<Item = (&'a Path, &'a mut TrackMetadata)>,
+{
+    let mut albums: HashMap<(Option<&Path>, AlbumKey), Vec<&mut TrackMetadata>> = HashMap::new();
+    for (path, meta) in tracks {
+        albums
+            .entry((album_dir(path), album_key(meta)))
+            .or_default()
+            .push(meta);
+    }
+    albums.into_values().collect()
+}
+
+/// The directory an album lives in: the file's directory, or its parent if
+/// that is a disc folder like `CD1` or `Disc 2`.
+fn album_dir(path: &Path) -> Option<&Path> {
+    let dir = path.parent()?;
+    let name = dir.file_name()?.to_string_lossy().to_lowercase();
+    let number = ["cd", "disc", "disk"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .map(|rest| rest.trim_start_matches([' ', '-', '_']));
+    match number {
+        Some(n) if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => dir.parent(),
+        _ => Some(dir),
+    }
+}
+
+/// Fill in missing album artists so that every track of an album groups
+/// into the same folder.
+///
+/// For each album (tracks in one directory sharing an album tag), tracks
+/// without an album artist take the album artist used by the other tracks,
+/// if any. Otherwise the track artist (without featured guests) that a
+/// strict majority of the album's tracks share is used, so "Artist feat.
+/// Guest" tracks stay with their album. Albums without a clear majority are
+/// left alone.
+pub fn resolve_album_artists<'a, I>(tracks: I)
+where
+    I: IntoIterator<Item = (&'a Path, &'a mut TrackMetadata)>,
+{
+    for mut group in group_albums(tracks) {
+        if group.iter().all(|m| m.album_artist.is_some()) {
+            continue;
+        }
+
+        let tagged: Vec<&str> = group
+            .iter()
+            .filter_map(|m| m.album_artist.as_deref())
+            .collect();
+        let resolved = most_common(&tagged, false).or_else(|| {
+            let artists: Vec<&str> = group.iter().map(|m| main_artist(&m.artist)).collect();
+            most_common(&artists, true)
+        });
+
+        if let Some(album_artist) = resolved {
+            for meta in group.iter_mut().filter(|m| m.album_artist.is_none()) {
+                meta.album_artist = Some(album_artist.clone());
+            }
+        }
+    }
+}
+
+/// Fill in missing disc totals for albums whose tracks span more than one
+/// disc number, so multi-disc albums are recognized even when only the disc
+/// number is tagged. Albums are grouped as in [`resolve_album_artists`].
+pub fn resolve_disc_totals<'a, I>(tracks: I)
+where
+    I: IntoIterator<Item = (&'a Path, &'a mut TrackMetadata)>,
+{
+    for mut group in group_albums(tracks) {
+        let discs: HashSet<u32> = group.iter().filter_map(|m| m.disc_number).collect();
+        let tagged_total = group.iter().filter_map(|m| m.disc_total).max();
+        let total = discs.iter().copied().chain(tagged_total).max();
+
+        if let Some(total) = total.filter(|_| discs.len() > 1 || tagged_total.is_some()) {
+            for meta in group.iter_mut().filter(|m| m.disc_total.is_none()) {
+                meta.disc_total = Some(total);
+            }
+        }
+    }
+}
+
+/// Detect compilation albums. Returns one entry per track, in order.
+///
+/// Tracks are grouped per album directory (see [`album_dir`]) and album; if
+/// any track of a group is flagged, has a various-artists album artist, or
+/// the group has many distinct artists (not counting featured guests) and no
+/// album artist, the whole group is a compilation.
+pub fn detect_compilations(tracks: &[(&Path, &TrackMetadata)]) -> Vec<Option<CompilationRule>> {
+    let mut groups: HashMap<(Option<&Path>, AlbumKey), Vec<usize>> = HashMap::new();
+    for (i, (path, meta)) in tracks.iter().enumerate() {
+        groups
+            .entry((album_dir(path), album_key(meta)))
+            .or_default()
+            .push(i);
+    }
+
+    let mut rules = vec![None; tracks.len()];
+    for indices in groups.values() {
+        let metas: Vec<&TrackMetadata> = indices.iter().map(|&i| tracks[i].1).collect();
+        let rule = compilation_rule(&metas);
+        for &i in indices {
+            rules[i] = rule;
+        }
+    }
+    rules
+}
+
+fn compilation_rule(metas: &[&TrackMetadata]) -> Option<CompilationRule> {
+    if metas.iter().any(|m| m.compilation) {
+        return Some(CompilationRule::Flag);
+    }
+
+    if metas.iter().any(|m| {
+        m.album_artist
+            .as_deref()
+            .is_some_and(|a| VARIOUS_ARTISTS.contains(&normalize(a).as_str()))
+    }) {
+        return Some(CompilationRule::VariousArtists);
+    }
+
+    let without_album_artist: HashSet<String> = metas
+        .iter()
+        .filter(|m| m.album_artist.is_none())
+        .map(|m| normalize(main_artist(&m.artist)))
+        .collect();
+    if without_album_artist.len() >= MANY_ARTISTS_THRESHOLD {
+        return Some(CompilationRule::ManyArtists);
+    }
+
+    None
+}
+
+/// Most common value (compared case-insensitively), returned in its most
+/// common spelling. With `strict`, the value must cover more than half of
+/// `values`.
+fn most_common(values: &[&str], strict: bool) -> Option<String> {
+    let mut counts: Vec<(String, usize, HashMap<&str, usize>)> = Vec::new();
+    for v in values {
+        let key = normalize(v);
+        if key.is_empty() {
+            continue;
+        }
+        match counts.iter_mut().find(|(k, _, _)| *k == key) {
+            Some((_, n, spellings)) => {
+                *n += 1;
+                *spellings.entry(v).or_default() += 1;
+            }
+            None => counts.push((key, 1, HashMap::from([(*v, 1)]))),
+        }
+    }
+
+    // Earliest value wins ties, keeping the result deterministic
+    let (_, count, spellings) = counts.iter().rev().max_by_key(|(_, n, _)| *n)?;
+    if strict && count * 2 <= values.len() {
+        return None;
+    }
+
+    let mut spellings: Vec<(&&str, &usize)> = spellings.iter().collect();
+    spellings.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    spellings.first().map(|(s, _)| s.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(artist: &str, album: &str, album_artist: Option<&str>) -> TrackMetadata {
+        TrackMetadata {
+            artist: artist.to_string(),
+            album: album.to_string(),
+            album_artist: album_artist.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    /// Tracks in one directory.
+    fn in_dir(tracks: &mut [TrackMetadata]) -> Vec<(&Path, &mut TrackMetadata)> {
+        tracks
+            .iter_mut()
+            .map(|t| (Path::new("/d/1.mp3"), t))
+            .collect()
+    }
+
+    fn at_paths<'a>(
+        tracks: &'a mut [(&str, TrackMetadata)],
+    ) -> Vec<(&'a Path, &'a mut TrackMetadata)> {
+        tracks.iter_mut().map(|(p, t)| (Path::new(*p), t)).collect()
+    }
+
+    #[test]
+    fn majority_artist_becomes_album_artist() {
+        let mut tracks = [
+            track("Band", "Album", None),
+            track("Band feat. Guest", "Album", None),
+            track("band", "album", None),
+        ];
+        resolve_album_artists(in_dir(&mut tracks));
+        for t in &tracks {
+            assert_eq!(t.album_artist.as_deref(), Some("Band"));
+        }
+    }
+
+    #[test]
+    fn existing_album_artist_tag_is_preferred() {
+        let mut tracks = [
+            track("Guest", "Album", Some("Band")),
+            track("Other Guest", "Album", None),
+        ];
+        resolve_album_artists(in_dir(&mut tracks));
+        assert_eq!(tracks[1].album_artist.as_deref(), Some("Band"));
+    }
+
+    #[test]
+    fn no_majority_leaves_album_artist_unset() {
+        let mut tracks = [
+            track("Queen", "Greatest Hits", None),
+            track("ABBA", "Greatest Hits", None),
+        ];
+        resolve_album_artists(in_dir(&mut tracks));
+        assert!(tracks.iter().all(|t| t.album_artist.is_none()));
+    }
+
+    fn detect(tracks: &[(&str, TrackMetadata)]) -> Vec<Option<CompilationRule>> {
+        let refs: Vec<(&Path, &TrackMetadata)> =
+            tracks.iter().map(|(p, m)| (Path::new(*p), m)).collect();
+        detect_compilations(&refs)
+    }
+
+    #[test]
+    fn compilation_flag_applies_to_whole_album() {
+        let mut flagged = track("A", "Hits", None);
+        flagged.compilation = true;
+        let rules = detect(&[
+            ("/d/1.mp3", flagged),
+            ("/d/2.mp3", track("B", "Hits", None)),
+        ]);
+        assert_eq!(rules, vec![Some(CompilationRule::Flag); 2]);
+    }
+
+    #[test]
+    fn various_artists_album_artist() {
+        let rules = detect(&[("/d/1.mp3", track("A", "OST", Some("VA")))]);
+        assert_eq!(rules, vec![Some(CompilationRule::VariousArtists)]);
+    }
+
+    #[test]
+    fn many_artists_within_one_directory() {
+        let rules = detect(&[
+            ("/d/1.mp3", track("A", "OST", None)),
+            ("/d/2.mp3", track("B", "OST", None)),
+            ("/d/3.mp3", track("C", "OST", None)),
+            ("/e/1.mp3", track("D", "OST", None)),
+        ]);
+        assert_eq!(
+            rules,
+            vec![
+                Some(CompilationRule::ManyArtists),
+                Some(CompilationRule::ManyArtists),
+                Some(CompilationRule::ManyArtists),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn featured_guests_do_not_make_a_compilation() {
+        let mut tracks = [
+            track("Band", "Album", None),
+            track("Band", "Album", None),
+            track("Band feat. X", "Album", None),
+            track("Band (ft. Y)", "Album", None),
+        ];
+        let rules = detect(&tracks.clone().map(|t| ("/d/1.mp3", t)));
+        assert_eq!(rules, vec![None; 4]);
+
+        resolve_album_artists(in_dir(&mut tracks));
+        for t in &tracks {
+            assert_eq!(t.album_artist.as_deref(), Some("Band"));
+        }
+        assert_eq!(main_artist("Band with Guest"), "Band");
+        assert_eq!(main_artist("Featuring"), "Featuring");
+    }
+
+    #[test]
+    fn compilation_split_across_disc_folders() {
+        let rules = detect(&[
+            ("/d/OST/CD1/1.mp3", track("A", "OST", None)),
+            ("/d/OST/CD1/2.mp3", track("B", "OST", None)),
+            ("/d/OST/CD2/1.mp3", track("C", "OST", None)),
+            ("/d/OST/CD2/2.mp3", track("C", "OST", None)),
+        ]);
+        assert_eq!(rules, vec![Some(CompilationRule::ManyArtists); 4]);
+    }
+
+    #[test]
+    fn regular_album_is_not_a_compilation() {
+        let rules = detect(&[
+            ("/d/1.mp3", track("A", "Album", Some("A"))),
+            ("/d/2.mp3", track("A feat. B", "Album", Some("A"))),
+            ("/d/3.mp3", track("A feat. C", "Album", Some("A"))),
+        ]);
+        assert_eq!(rules, vec![None; 3]);
+    }
+
+    #[test]
+    fn disc_total_inferred_from_disc_numbers() {
+        let mut tracks = [
+            track("A", "Album", None),
+            track("A", "Album", None),
+            track("A", "Single", None),
+        ];
+        tracks[0].disc_number = Some(1);
+        tracks[1].disc_number = Some(2);
+        tracks[2].disc_number = Some(1);
+        resolve_disc_totals(in_dir(&mut tracks));
+        assert_eq!(tracks[0].disc_total, Some(2));
+        assert_eq!(tracks[1].disc_total, Some(2));
+        assert!(tracks[0].is_multi_disc());
+        assert_eq!(tracks[2].disc_total, None);
+        assert!(!tracks[2].is_multi_disc());
+    }
+
+    #[test]
+    fn disc_total_shared_across_album() {
+        let mut tracks = [track("A", "Album", None), track("A", "Album", None)];
+        tracks[0].disc_number = Some(1);
+        tracks[0].disc_total = Some(2);
+        tracks[1].disc_number = Some(1);
+        resolve_disc_totals(in_dir(&mut tracks));
+        assert_eq!(tracks[1].disc_total, Some(2));
+    }
+
+    #[test]
+    fn albums_are_voted_separately() {
+        let mut tracks = [
+            track("A", "One", None),
+            track("B", "Two", None),
+            track("B", "Two", None),
+        ];
+        resolve_album_artists(in_dir(&mut tracks));
+        assert_eq!(tracks[0].album_artist.as_deref(), Some("A"));
+        assert_eq!(tracks[1].album_artist.as_deref(), Some("B"));
+    }
+
+    #[test]
+    fn same_named_albums_in_other_directories_stay_apart() {
+        let mut tracks = [
+            ("/abba/1.mp3", track("ABBA", "Greatest Hits", Some("ABBA"))),
+            ("/queen/1.mp3", track("Queen", "Greatest Hits", None)),
+            ("/queen/2.mp3", track("Queen", "Greatest Hits", None)),
+            ("/other/1.mp3", track("Band", "Album", None)),
+            ("/other/2.mp3", track("Band", "Album", None)),
+            ("/more/1.mp3", track("Someone", "Album", None)),
+        ];
+        tracks[0].1.disc_number = Some(2);
+        tracks[1].1.disc_number = Some(1);
+        resolve_album_artists(at_paths(&mut tracks));
+        resolve_disc_totals(at_paths(&mut tracks));
+
+        assert_eq!(tracks[1].1.album_artist.as_deref(), Some("Queen"));
+        assert_eq!(tracks[2].1.album_artist.as_deref(), Some("Queen"));
+        assert_eq!(tracks[1].1.disc_total, None);
+        // Each directory has its own majority
+        assert_eq!(tracks[5].1.album_artist.as_deref(), Some("Someone"));
+    }
+
+    #[test]
+    fn disc_folders_belong_to_one_album() {
+        let mut tracks = [
+            ("/a/Album/CD1/1.mp3", track("A", "Album", Some("A"))),
+            ("/a/Album/Disc 2/1.mp3", track("A feat. B", "Album", None)),
+        ];
+        tracks[0].1.disc_number = Some(1);
+        tracks[1].1.disc_number = Some(2);
+        resolve_album_artists(at_paths(&mut tracks));
+        resolve_disc_totals(at_paths(&mut tracks));
+
+        assert_eq!(tracks[1].1.album_artist.as_deref(), Some("A"));
+        assert!(tracks.iter().all(|(_, t)| t.disc_total == Some(2)));
+    }
+}
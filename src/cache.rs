@@ -0,0 +1,242 @@
+use crate::tags::{TrackMetadata, UnsortedReason};
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// `$XDG_CACHE_HOME/tagmv`, falling back to `~/.cache`.
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))?;
+    Some(base.join("tagmv"))
+}
+
+/// Delete every cache tagmv keeps in the [`cache_dir`]. Returns the files
+/// that were removed.
+pub fn clear() -> Result<Vec<PathBuf>> {
+    let Some(dir) = cache_dir() else {
+        return Ok(Vec::new());
+    };
+    let mut removed = Vec::new();
+    for name in [PayloadInfo::FILE_NAME, TagCache::FILE_NAME] {
+        let path = dir.join(name);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove cache: {}", path.display()))?;
+            removed.push(path);
+        }
+    }
+    // Only if nothing else was put there
+    let _ = fs::remove_dir(&dir);
+    Ok(removed)
+}
+
+/// What the dedupe pass knows about a file's audio payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadInfo {
+    /// Number of audio bytes, i.e. the file without tags and metadata.
+    pub len: u64,
+    /// SHA-256 of the audio bytes, once it was needed.
+    pub hash: Option<String>,
+}
+
+/// A value worth keeping between runs for every file.
+pub trait CacheValue: Clone + Serialize + DeserializeOwned {
+    /// File name in the [`cache_dir`].
+    const FILE_NAME: &'static str;
+    /// Bumped whenever the cached values change meaning, which drops old
+    /// caches.
+    const VERSION: u32;
+}
+
+impl CacheValue for PayloadInfo {
+    const FILE_NAME: &'static str = "hashes.json";
+    const VERSION: u32 = 2;
+}
+
+/// The tags read from a file, or why they are not usable.
+impl CacheValue for Result<TrackMetadata, UnsortedReason> {
+    const FILE_NAME: &'static str = "tags.json";
+    const VERSION: u32 = 2;
+}
+
+/// Payload lengths and hashes for `--dedupe`.
+pub type HashCache = Cache<PayloadInfo>;
+
+/// Tags as read by `read_tags`, before albums are resolved.
+pub type TagCache = Cache<Result<TrackMetadata, UnsortedReason>>;
+
+/// Identifies one version of a file: an entry is only used while the file
+/// has the same size, modification time and inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamp {
+    size: u64,
+    /// Nanoseconds since the epoch.
+    mtime: u64,
+    inode: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Stamp> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&meta);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Some(Stamp {
+            size: meta.len(),
+            mtime: u64::try_from(mtime.as_nanos()).ok()?,
+            inode,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry<V> {
+    #[serde(flatten)]
+    stamp: Stamp,
+    value: V,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile<V> {
+    version: u32,
+    entries: BTreeMap<PathBuf, Entry<V>>,
+}
+
+impl<V> Default for CacheFile<V> {
+    fn default() -> Self {
+        CacheFile {
+            version: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+/// Values from earlier runs, keyed by path.
+#[derive(Debug)]
+pub struct Cache<V> {
+    path: Option<PathBuf>,
+    file: CacheFile<V>,
+    dirty: bool,
+}
+
+impl<V: CacheValue> Cache<V> {
+    /// The cache's file name in the [`cache_dir`].
+    pub const FILE_NAME: &'static str = V::FILE_NAME;
+
+    /// The cache's file in the [`cache_dir`].
+    pub fn default_path() -> Option<PathBuf> {
+        cache_dir().map(|d| d.join(V::FILE_NAME))
+    }
+
+    /// Load the cache at `path`. A missing, unreadable or outdated cache
+    /// starts out empty; it is only a shortcut.
+    pub fn load(path: &Path) -> Cache<V> {
+        let file = fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<CacheFile<V>>(&text).ok())
+            .filter(|file| file.version == V::VERSION)
+            .unwrap_or_default();
+        Cache {
+            path: Some(path.to_path_buf()),
+            file,
+            dirty: false,
+        }
+    }
+
+    /// A cache that is never written to disk.
+    pub fn in_memory() -> Cache<V> {
+        Cache {
+            path: None,
+            file: CacheFile::default(),
+            dirty: false,
+        }
+    }
+
+    /// The cached value for `path`, if the file did not change since.
+    pub fn get(&self, path: &Path) -> Option<&V> {
+        let entry = self.file.entries.get(path)?;
+        (Stamp::of(path)? == entry.stamp).then_some(&entry.value)
+    }
+
+    pub fn insert(&mut self, path: &Path, value: V) {
+        let Some(stamp) = Stamp::of(path) else {
+            return;
+        };
+        self.file
+            .entries
+            .insert(path.to_path_buf(), Entry { stamp, value });
+        self.dirty = true;
+    }
+
+    /// Write the cache back if anything changed, dropping entries for files
+    /// that no longer exist.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.dirty) else {
+            return Ok(());
+        };
+        self.file.entries.retain(|p, _| p.exists());
+        self.file.version = V::VERSION;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create cache directory: {}", dir.display()))?;
+        }
+
+        // Write next to the cache and rename, so concurrent runs never see
+        // half a file
+        let text = serde_json::to_string(&self.file)?;
+        let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&temp, text + "\n")
+            .with_context(|| format!("Failed to write cache: {}", temp.display()))?;
+        fs::rename(&temp, path)
+            .with_context(|| format!("Failed to write cache: {}", path.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn entries_survive_a_reload_until_the_file_changes() {
+        let tmp = temp_dir("hash_cache");
+        let audio = tmp.join("a.mp3");
+        let gone = tmp.join("gone.mp3");
+        fs::write(&audio, "audio").unwrap();
+        fs::write(&gone, "audio").unwrap();
+        let info = PayloadInfo {
+            len: 5,
+            hash: Some("abc".to_string()),
+        };
+
+        let path = tmp.join("cache/hashes.json");
+        let mut cache = HashCache::load(&path);
+        cache.insert(&audio, info.clone());
+        cache.insert(&gone, info.clone());
+        fs::remove_file(&gone).unwrap();
+        cache.save().unwrap();
+
+        let cache = HashCache::load(&path);
+        assert_eq!(cache.get(&audio), Some(&info));
+        assert_eq!(cache.file.entries.len(), 1);
+
+        fs::write(&audio, "other audio").unwrap();
+        assert_eq!(cache.get(&audio), None);
+
+        // A different kind of cache at the same path does not load
+        assert!(TagCache::load(&path).file.entries.is_empty());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::albums::album_key;
+use crate::sorting::{Conflict, PlannedMove};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// Non-audio files that belong with an album: cover art, cue sheets, rip
+/// logs, lyrics and release notes.
+pub const COMPANION_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "cue", "log", "lrc", "nfo", "txt",
+];
+
+/// True if `path` has one of the [`COMPANION_EXTENSIONS`].
+pub fn is_companion_file(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        COMPANION_EXTENSIONS
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(ext))
+    })
+}
+
+/// Moves for the companion files next to the planned audio files.
+///
+/// A companion named like one of the audio files (`Track.lrc` next to
+/// `Track.mp3`) follows that file and is renamed along with it. The other
+/// companions of a directory follow its audio only if all of it is tagged,
+/// belongs to one album and is filed into a single album folder; otherwise
+/// they stay where they are. The scanned directory `root` itself is often a
+/// catch-all like `~/Downloads`, so only companions named like a track are
+/// taken from it. Displaced library files and skipped files take no
+/// companions along.
+pub fn plan_companions(
+    root: &Path,
+    target: &Path,
+    moves: &[PlannedMove],
+) -> Result<Vec<PlannedMove>> {
+    let mut dirs: BTreeMap<&Path, Vec<&PlannedMove>> = BTreeMap::new();
+    for m in moves {
+        if m.companion || m.conflict == Some(Conflict::Displaced) {
+            continue;
+        }
+        if let Some(dir) = m.source.parent() {
+            dirs.entry(dir).or_default().push(m);
+        }
+    }
+
+    let mut companions = Vec::new();
+    for (dir, audio) in dirs {
+        let files = companion_files(dir)?;
+        if files.is_empty() {
+            continue;
+        }
+
+        let mut by_stem: HashMap<&OsStr, &PlannedMove> = HashMap::new();
+        for m in &audio {
+            if let Some(stem) = m.source.file_stem() {
+                by_stem.entry(stem).or_insert(m);
+            }
+        }
+        let album_dir = album_dir(target, &audio).filter(|_| dir != root);
+
+        for file in files {
+            let track = file.file_stem().and_then(|stem| by_stem.get(stem));
+            let (dest, mode) = match (track, &album_dir) {
+                (Some(track), _) if track.is_skipped() => continue,
+                (Some(track), _) => {
+                    let ext = file.extension().unwrap_or_default();
+                    (track.dest.with_extension(ext), track.mode)
+                }
+                (None, Some(album_dir)) => (album_dir.join(file_name(&file)), audio[0].mode),
+                (None, None) => continue,
+            };
+            if dest == file {
+                continue;
+            }
+
+            let folder_name = dest
+                .parent()
+                .and_then(|p| p.strip_prefix(target).ok())
+                .map(|p| {
+                    p.iter()
+                        .map(|c| c.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/")
+                })
+                .unwrap_or_default();
+            companions.push(PlannedMove {
+                file_name: file_name(&dest),
+                source: file,
+                dest,
+                folder_name,
+                mode,
+                companion: true,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(companions)
+}
+
+/// Companion files directly in `dir`, sorted by name. Hidden files are
+/// skipped.
+fn companion_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && is_companion_file(p))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// True if the move files its audio into the library proper, rather than
+/// leaving it or setting it aside as a duplicate.
+fn is_filed(m: &PlannedMove) -> bool {
+    let quarantined = m.duplicate_of.as_ref().is_some_and(|d| d.quarantined);
+    !quarantined && !m.is_skipped() && m.conflict != Some(Conflict::Duplicate)
+}
+
+/// The folder that all audio of one source directory is filed into, if
+/// there is one: every file is tagged with the same album, and the deepest
+/// folder their destinations share lies inside `target`.
+fn album_dir(target: &Path, audio: &[&PlannedMove]) -> Option<PathBuf> {
+    let mut albums = audio.iter().map(|m| m.meta.as_ref().map(album_key));
+    let first = albums.next()??;
+    if !albums.all(|key| key.as_ref() == Some(&first)) {
+        return None;
+    }
+
+    let mut dirs = audio
+        .iter()
+        .filter(|m| is_filed(m))
+        .map(|m| m.dest.parent());
+    let mut common = dirs.next()??.to_path_buf();
+    for dir in dirs {
+        let dir = dir?;
+        while !dir.starts_with(&common) {
+            common = common.parent()?.to_path_buf();
+        }
+    }
+    (common.starts_with(target) && common != target).then_some(common)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sorting::Layout;
+    use crate::tags::TrackMetadata;
+    use crate::template::DiscLayout;
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    fn track(album: &str, title: &str) -> TrackMetadata {
+        TrackMetadata {
+            artist: "Artist".to_string(),
+            album: album.to_string(),
+            title: Some(title.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn companions_follow_their_album_and_track() {
+        let tmp = temp_dir("companions");
+        for dir in ["album", "mixed"] {
+            fs::create_dir_all(tmp.join(dir)).unwrap();
+            for name in ["cover.jpg", "01.lrc", ".hidden.jpg", "notes.pdf"] {
+                fs::write(tmp.join(dir).join(name), "companion").unwrap();
+            }
+        }
+        let lib = tmp.join("lib");
+        let layout = Layout::default();
+        let template = DiscLayout::default().template();
+
+        let plan = |dir: &str, albums: &[&str]| -> Vec<PlannedMove> {
+            let moves: Vec<PlannedMove> = albums
+                .iter()
+                .enumerate()
+                .map(|(i, album)| {
+                    let source = tmp.join(dir).join(format!("{:02}.mp3", i + 1));
+                    let meta = track(album, &format!("Song {}", i + 1));
+                    layout.destination(&lib, &source, &meta, &template).unwrap()
+                })
+                .collect();
+            plan_companions(&tmp, &lib, &moves).unwrap()
+        };
+
+        let album = plan("album", &["One", "One"]);
+        let dests: Vec<PathBuf> = album.iter().map(|m| m.dest.clone()).collect();
+        assert_eq!(
+            dests,
+            vec![
+                lib.join("Artist - One/Song 1.lrc"),
+                lib.join("Artist - One/cover.jpg")
+            ]
+        );
+        assert!(album
+            .iter()
+            .all(|m| m.companion && m.folder_name == "Artist - One"));
+
+        // Two albums in one directory: only the lyrics know where to go
+        let mixed = plan("mixed", &["One", "Two"]);
+        assert_eq!(mixed.len(), 1);
+        assert_eq!(mixed[0].source, tmp.join("mixed/01.lrc"));
+
+        // Loose files in the scanned directory itself stay there
+        let source = tmp.join("album/01.mp3");
+        let meta = track("One", "Song 1");
+        let moves = vec![layout.destination(&lib, &source, &meta, &template).unwrap()];
+        let root = plan_companions(&tmp.join("album"), &lib, &moves).unwrap();
+        assert_eq!(root.len(), 1);
+        assert_eq!(root[0].source, tmp.join("album/01.lrc"));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
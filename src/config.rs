@@ -0,0 +1,527 @@
+use crate::cache::{HashCache, TagCache};
+use crate::dedupe::DedupeAction;
+use crate::executor::JUNK_FILES;
+use crate::infer::{default_patterns, Pattern};
+use crate::planner::Planner;
+use crate::sorting::{ConflictPolicy, Layout, SanitizeRules, TransferMode};
+use crate::template::{DiscLayout, Template};
+use crate::validate::CorruptAction;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Per-directory config, looked up in the scanned directory and its parents.
+pub const LOCAL_CONFIG_FILE: &str = ".tagmv.toml";
+
+/// Everything a config file (or one of its profiles) can set. Unset values
+/// fall through to the layer below; from lowest to highest precedence:
+/// built-in defaults, the user config, the nearest `.tagmv.toml`, the
+/// selected profile (user config, then `.tagmv.toml`), command-line flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub dest: Option<PathBuf>,
+    pub recursive: Option<bool>,
+    pub mode: Option<TransferMode>,
+    pub template: Option<Template>,
+    pub compilation_template: Option<Template>,
+    pub disc_layout: Option<DiscLayout>,
+    /// Extensions to scan for, replacing the built-in list.
+    pub extensions: Option<Vec<String>>,
+    pub unsorted_folder: Option<String>,
+    /// File unsorted files into a subfolder per reason.
+    pub unsorted_by_reason: Option<bool>,
+    pub duplicates_folder: Option<String>,
+    pub corrupt_folder: Option<String>,
+    /// Replaces the built-in rules as a whole; unset keys keep their default.
+    pub sanitize: Option<SanitizeRules>,
+    pub on_conflict: Option<ConflictPolicy>,
+    pub max_conflict_attempts: Option<u32>,
+    pub dedupe: Option<DedupeAction>,
+    /// What happens to unreadable and damaged files.
+    pub on_corrupt: Option<CorruptAction>,
+    /// Walk the audio stream of every file to find damaged ones.
+    pub validate: Option<bool>,
+    /// Take cover art, cue sheets, logs and lyrics along (default: true).
+    pub companions: Option<bool>,
+    /// Detect audio files by content and correct their extensions.
+    pub sniff: Option<bool>,
+    /// Read tags from the path of untagged files (default: true if
+    /// `infer-patterns` are set).
+    pub infer: Option<bool>,
+    /// Patterns for `infer`, replacing the built-in list.
+    pub infer_patterns: Option<Vec<Pattern>>,
+    /// Write the tags files are filed by back into them.
+    pub write_tags: Option<bool>,
+    /// Threads for reading tags (default: one per CPU).
+    pub jobs: Option<usize>,
+    /// Keep tags and payload hashes between runs (default: true).
+    pub cache: Option<bool>,
+    /// Remove source directories that executing left empty.
+    pub prune_empty: Option<bool>,
+    /// File names that do not count when deciding whether a directory is
+    /// empty, replacing the built-in list.
+    pub junk_files: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// `other` layered on top of `self`.
+    pub fn merge(self, other: Settings) -> Settings {
+        Settings {
+            dest: other.dest.or(self.dest),
+            recursive: other.recursive.or(self.recursive),
+            mode: other.mode.or(self.mode),
+            template: other.template.or(self.template),
+            compilation_template: other.compilation_template.or(self.compilation_template),
+            disc_layout: other.disc_layout.or(self.disc_layout),
+            extensions: other.extensions.or(self.extensions),
+            unsorted_folder: other.unsorted_folder.or(self.unsorted_folder),
+            unsorted_by_reason: other.unsorted_by_reason.or(self.unsorted_by_reason),
+            duplicates_folder: other.duplicates_folder.or(self.duplicates_folder),
+            corrupt_folder: other.corrupt_folder.or(self.corrupt_folder),
+            sanitize: other.sanitize.or(self.sanitize),
+            on_conflict: other.on_conflict.or(self.on_conflict),
+            max_conflict_attempts: other.max_conflict_attempts.or(self.max_conflict_attempts),
+            dedupe: other.dedupe.or(self.dedupe),
+            on_corrupt: other.on_corrupt.or(self.on_corrupt),
+            validate: other.validate.or(self.validate),
+            companions: other.companions.or(self.companions),
+            sniff: other.sniff.or(self.sniff),
+            infer: other.infer.or(self.infer),
+            infer_patterns: other.infer_patterns.or(self.infer_patterns),
+            write_tags: other.write_tags.or(self.write_tags),
+            jobs: other.jobs.or(self.jobs),
+            cache: other.cache.or(self.cache),
+            prune_empty: other.prune_empty.or(self.prune_empty),
+            junk_files: other.junk_files.or(self.junk_files),
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        let default = Layout::default();
+        Layout {
+            unsorted_folder: self
+                .unsorted_folder
+                .clone()
+                .unwrap_or(default.unsorted_folder),
+            duplicates_folder: self
+                .duplicates_folder
+                .clone()
+                .unwrap_or(default.duplicates_folder),
+            corrupt_folder: self
+                .corrupt_folder
+                .clone()
+                .unwrap_or(default.corrupt_folder),
+            sanitize: self.sanitize.clone().unwrap_or(default.sanitize),
+            on_conflict: self.on_conflict.unwrap_or(default.on_conflict),
+            max_conflict_attempts: self
+                .max_conflict_attempts
+                .unwrap_or(default.max_conflict_attempts),
+            unsorted_by_reason: self
+                .unsorted_by_reason
+                .unwrap_or(default.unsorted_by_reason),
+        }
+    }
+
+    /// A planner for `root` with these settings applied.
+    pub fn planner(&self, root: impl Into<PathBuf>) -> Planner {
+        let mut planner = Planner::new(root)
+            .recursive(self.recursive.unwrap_or(false))
+            .mode(self.mode.unwrap_or_default())
+            .disc_layout(self.disc_layout.unwrap_or_default())
+            .layout(self.layout())
+            .dedupe(self.dedupe)
+            .on_corrupt(self.on_corrupt.unwrap_or_default())
+            .validate(self.validate.unwrap_or(false))
+            .companions(self.companions.unwrap_or(true))
+            .sniff(self.sniff.unwrap_or(false))
+            .write_tags(self.write_tags.unwrap_or(false));
+        if let Some(dest) = &self.dest {
+            planner = planner.dest(dest);
+        }
+        if self.infer.unwrap_or(self.infer_patterns.is_some()) {
+            let patterns = self.infer_patterns.clone();
+            planner = planner.infer(patterns.unwrap_or_else(default_patterns));
+        }
+        if let Some(jobs) = self.jobs {
+            planner = planner.jobs(jobs);
+        }
+        if self.cache != Some(false) {
+            planner = planner
+                .tag_cache(TagCache::default_path())
+                .hash_cache(HashCache::default_path());
+        }
+        if let Some(template) = &self.template {
+            planner = planner.template(template.clone());
+        }
+        if let Some(template) = &self.compilation_template {
+            planner = planner.compilation_template(template.clone());
+        }
+        if let Some(extensions) = &self.extensions {
+            planner = planner.extensions(extensions.iter().map(|e| e.trim_start_matches('.')));
+        }
+        planner
+    }
+
+    /// Junk file names for pruning, as configured or the built-in ones.
+    pub fn junk_files(&self) -> Vec<String> {
+        match &self.junk_files {
+            Some(files) => files.clone(),
+            None => JUNK_FILES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    /// Drop what a config file that came with the files it applies to may
+    /// not decide: where files go and whether library files are replaced.
+    /// Returns the keys that were dropped.
+    fn restrict(&mut self) -> Vec<&'static str> {
+        let mut dropped = Vec::new();
+        if self.dest.take().is_some() {
+            dropped.push("dest");
+        }
+        if self.on_conflict == Some(ConflictPolicy::Overwrite) {
+            self.on_conflict = None;
+            dropped.push("on-conflict");
+        }
+        dropped
+    }
+
+    fn validate(&self) -> Result<()> {
+        let folders = [
+            ("unsorted-folder", &self.unsorted_folder),
+            ("duplicates-folder", &self.duplicates_folder),
+            ("corrupt-folder", &self.corrupt_folder),
+        ];
+        for (key, folder) in folders {
+            let Some(folder) = folder else { continue };
+            if folder.is_empty() || folder == "." || folder == ".." || folder.contains(['/', '\\'])
+            {
+                bail!("{} must be a single folder name, got \"{}\"", key, folder);
+            }
+        }
+        if let Some(extensions) = &self.extensions {
+            if extensions
+                .iter()
+                .any(|e| e.trim_start_matches('.').is_empty())
+            {
+                bail!("extensions must not contain empty entries");
+            }
+        }
+        if self.infer_patterns.as_ref().is_some_and(Vec::is_empty) {
+            bail!("infer-patterns must not be empty");
+        }
+        if self.jobs == Some(0) {
+            bail!("jobs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// One parsed config file: top-level settings plus `[profile.<name>]` tables.
+#[derive(Debug)]
+struct ConfigFile {
+    path: PathBuf,
+    settings: Settings,
+    profiles: BTreeMap<String, Settings>,
+    /// Keys left out by [`ConfigFile::restrict`].
+    ignored: BTreeSet<&'static str>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<ConfigFile> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+        ConfigFile::parse(path, &text)
+            .with_context(|| format!("Invalid config: {}", path.display()))
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<ConfigFile> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let profile_tables = match table.remove("profile") {
+            Some(toml::Value::Table(t)) => t,
+            Some(_) => bail!("`profile` must be a table of [profile.<name>] sections"),
+            None => toml::Table::new(),
+        };
+
+        // Relative destinations are relative to the file they are written in
+        let base = path.parent().unwrap_or(Path::new("."));
+        let settings = parse_settings(table, base)?;
+        let mut profiles = BTreeMap::new();
+        for (name, value) in profile_tables {
+            let toml::Value::Table(t) = value else {
+                bail!("profile.{} must be a table", name);
+            };
+            let settings =
+                parse_settings(t, base).with_context(|| format!("in [profile.{}]", name))?;
+            profiles.insert(name, settings);
+        }
+
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            settings,
+            profiles,
+            ignored: BTreeSet::new(),
+        })
+    }
+
+    /// Apply [`Settings::restrict`] to the settings and every profile.
+    fn restrict(&mut self) {
+        let profiles = self.profiles.values_mut();
+        for settings in std::iter::once(&mut self.settings).chain(profiles) {
+            self.ignored.extend(settings.restrict());
+        }
+    }
+}
+
+fn parse_settings(table: toml::Table, base: &Path) -> Result<Settings> {
+    let mut settings = Settings::deserialize(toml::Value::Table(table))?;
+    settings.validate()?;
+    if let Some(dest) = settings.dest.take() {
+        settings.dest = Some(resolve_path(&dest, base));
+    }
+    Ok(settings)
+}
+
+/// Expand a leading `~/` and make relative paths relative to `base`.
+fn resolve_path(path: &Path, base: &Path) -> PathBuf {
+    if let Ok(rest) = path.strip_prefix("~") {
+        if let Some(home) = std::env::var_os("HOME") {
+            return PathBuf::from(home).join(rest);
+        }
+    }
+    base.join(path)
+}
+
+/// The config files that apply to one directory, lowest precedence first.
+#[derive(Debug, Default)]
+pub struct Config {
+    files: Vec<ConfigFile>,
+}
+
+impl Config {
+    /// Load the user config and the `.tagmv.toml` nearest to `dir`, if any.
+    ///
+    /// A `.tagmv.toml` in `dir` itself may have come with the files, like
+    /// one shipped in a downloaded album, so its `dest` and an `overwrite`
+    /// conflict policy are ignored (see [`Config::ignored`]).
+    pub fn load(dir: &Path) -> Result<Config> {
+        let local = find_local_config(dir);
+        let paths = user_config_path()
+            .into_iter()
+            .filter(|p| p.is_file())
+            .chain(local.clone());
+        let mut config = Config::from_files(paths)?;
+        if local.is_some_and(|p| p.parent() == Some(dir)) {
+            if let Some(file) = config.files.last_mut() {
+                file.restrict();
+            }
+        }
+        Ok(config)
+    }
+
+    /// Load specific files, lowest precedence first.
+    pub fn from_files<I>(paths: I) -> Result<Config>
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let files = paths
+            .into_iter()
+            .map(|p| ConfigFile::load(&p))
+            .collect::<Result<_>>()?;
+        Ok(Config { files })
+    }
+
+    /// Files that were loaded, lowest precedence first.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|f| f.path.as_path())
+    }
+
+    /// Keys that were ignored in the file at `path`, because it lies in the
+    /// scanned directory.
+    pub fn ignored(&self, path: &Path) -> Vec<&'static str> {
+        self.files
+            .iter()
+            .filter(|f| f.path == path)
+            .flat_map(|f| f.ignored.iter().copied())
+            .collect()
+    }
+
+    /// Profile names defined in any of the files.
+    pub fn profiles(&self) -> BTreeSet<&str> {
+        self.files
+            .iter()
+            .flat_map(|f| f.profiles.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// Merged settings: the top-level settings of every file, then
+    /// `profile` from every file that defines it. The profile must exist in
+    /// at least one file.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings> {
+        let mut merged = Settings::default();
+        for file in &self.files {
+            merged = merged.merge(file.settings.clone());
+        }
+
+        let mut found = false;
+        for file in &self.files {
+            if let Some(p) = profile.and_then(|name| file.profiles.get(name)) {
+                merged = merged.merge(p.clone());
+                found = true;
+            }
+        }
+
+        if let Some(name) = profile.filter(|_| !found) {
+            let available: Vec<&str> = self.profiles().into_iter().collect();
+            if available.is_empty() {
+                bail!("Unknown profile \"{}\" (no profiles are configured)", name);
+            }
+            bail!(
+                "Unknown profile \"{}\" (available: {})",
+                name,
+                available.join(", ")
+            );
+        }
+        Ok(merged)
+    }
+}
+
+/// `$XDG_CONFIG_HOME/tagmv/config.toml`, falling back to `~/.config`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("tagmv/config.toml"))
+}
+
+/// The `.tagmv.toml` in `dir` or its nearest parent that has one.
+pub fn find_local_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|d| d.join(LOCAL_CONFIG_FILE))
+        .find(|p| p.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn parse(text: &str) -> Result<ConfigFile> {
+        ConfigFile::parse(Path::new("/cfg/config.toml"), text)
+    }
+
+    #[test]
+    fn parses_settings_and_profiles() {
+        let file = parse(
+            r#"
+            template = "{artist}/{album}/{title}.{ext}"
+            extensions = ["mp3", ".flac"]
+            unsorted-folder = "Unsorted"
+            on-conflict = "keep-lossless"
+            companions = false
+            junk-files = ["Thumbs.db"]
+
+            [sanitize]
+            separator = "_"
+            replace = { ":" = " -" }
+
+            [profile.usb-stick]
+            mode = "copy"
+            dest = "stick"
+            disc-layout = "folder"
+            max-conflict-attempts = 5
+            dedupe = "quarantine"
+            on-corrupt = "leave"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            file.settings.template.as_ref().unwrap().to_string(),
+            "{artist}/{album}/{title}.{ext}"
+        );
+        assert_eq!(
+            file.settings.on_conflict,
+            Some(ConflictPolicy::KeepLossless)
+        );
+        assert_eq!(file.settings.companions, Some(false));
+        assert_eq!(file.settings.junk_files(), vec!["Thumbs.db"]);
+        let sanitize = file.settings.sanitize.as_ref().unwrap();
+        assert_eq!(sanitize.apply("AC/DC: Live"), "AC_DC - Live");
+
+        let usb = &file.profiles["usb-stick"];
+        assert_eq!(usb.mode, Some(TransferMode::Copy));
+        assert_eq!(usb.dest.as_deref(), Some(Path::new("/cfg/stick")));
+        assert_eq!(usb.disc_layout, Some(DiscLayout::Folder));
+        assert_eq!(usb.dedupe, Some(DedupeAction::Quarantine));
+        assert_eq!(usb.on_corrupt, Some(CorruptAction::Leave));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        assert!(parse("tempalte = \"{artist}\"").is_err());
+        assert!(parse("[profile.x]\nmod = \"copy\"").is_err());
+        assert!(parse("template = \"{nope}\"").is_err());
+        assert!(parse("mode = \"teleport\"").is_err());
+        assert!(parse("unsorted-folder = \"a/b\"").is_err());
+        assert!(parse("duplicates-folder = \"..\"").is_err());
+        assert!(parse("corrupt-folder = \"\"").is_err());
+        assert!(parse("on-corrupt = \"delete\"").is_err());
+        assert!(parse("on-conflict = \"keep-newer\"").is_err());
+        assert!(parse("jobs = 0").is_err());
+        assert!(parse("infer-patterns = []").is_err());
+        assert!(parse("infer-patterns = [\"{artist} - {title\"]").is_err());
+    }
+
+    #[test]
+    fn later_files_and_profiles_take_precedence() {
+        let user =
+            parse("mode = \"copy\"\n[profile.lib]\nrecursive = true\nmode = \"hardlink\"").unwrap();
+        let local = parse("mode = \"symlink\"\nunsorted-folder = \"Inbox\"").unwrap();
+        let config = Config {
+            files: vec![user, local],
+        };
+
+        let plain = config.settings(None).unwrap();
+        assert_eq!(plain.mode, Some(TransferMode::Symlink));
+        assert_eq!(plain.recursive, None);
+
+        // A selected profile beats top-level settings of any file
+        let lib = config.settings(Some("lib")).unwrap();
+        assert_eq!(lib.mode, Some(TransferMode::Hardlink));
+        assert_eq!(lib.recursive, Some(true));
+        assert_eq!(lib.layout().unsorted_folder, "Inbox");
+
+        let err = config.settings(Some("usb")).unwrap_err().to_string();
+        assert!(err.contains("available: lib"));
+    }
+
+    #[test]
+    fn local_config_in_the_scanned_directory_is_restricted() {
+        let tmp = temp_dir("config_local");
+        fs::create_dir_all(tmp.join("album")).unwrap();
+        let local = tmp.join("album").join(LOCAL_CONFIG_FILE);
+        let text = "dest = \"/elsewhere\"\non-conflict = \"overwrite\"\nmode = \"copy\"\n\
+                    [profile.p]\ndest = \"/other\"";
+        fs::write(&local, text).unwrap();
+
+        let config = Config::load(&tmp.join("album")).unwrap();
+        assert_eq!(config.paths().last(), Some(local.as_path()));
+        assert_eq!(config.ignored(&local), vec!["dest", "on-conflict"]);
+        let settings = config.settings(Some("p")).unwrap();
+        assert_eq!(settings.mode, Some(TransferMode::Copy));
+        assert!(settings.dest.is_none() && settings.on_conflict.is_none());
+
+        // Found above the scanned directory, it is the user's own
+        fs::create_dir_all(tmp.join("album/cd1")).unwrap();
+        let config = Config::load(&tmp.join("album/cd1")).unwrap();
+        assert!(config.ignored(&local).is_empty());
+        let settings = config.settings(None).unwrap();
+        assert_eq!(settings.dest.as_deref(), Some(Path::new("/elsewhere")));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
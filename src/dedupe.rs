@@ -0,0 +1,506 @@
+use crate::cache::{HashCache, PayloadInfo};
+use crate::sorting::PlannedMove;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// What `--dedupe` does with a file whose audio is already in the library or
+/// earlier in the plan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupeAction {
+    /// Point the duplicate out, but transfer it as planned
+    #[default]
+    Report,
+    /// File the duplicate into the duplicates folder instead
+    Quarantine,
+}
+
+impl std::fmt::Display for DedupeAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DedupeAction::Report => "report",
+            DedupeAction::Quarantine => "quarantine",
+        })
+    }
+}
+
+/// For every move, the copy of the same audio that is kept if there is one:
+/// a file already in `library`, or the source of an earlier move. Only moves
+/// that need a transfer are compared; library files that are about to be
+/// moved count as moves, not as library files.
+///
+/// Files are first grouped by payload length, so only files that could be
+/// equal are hashed. Lengths and hashes are kept in `cache`.
+pub fn find_duplicates(
+    moves: &[PlannedMove],
+    library: &[PathBuf],
+    cache: &mut HashCache,
+) -> Vec<Option<PathBuf>> {
+    let pending: Vec<Option<&Path>> = moves
+        .iter()
+        .map(|m| m.needs_transfer().then_some(m.source.as_path()))
+        .collect();
+    let moving: HashSet<&Path> = pending.iter().flatten().copied().collect();
+    let existing: Vec<&Path> = library
+        .iter()
+        .map(PathBuf::as_path)
+        .filter(|p| !moving.contains(p))
+        .collect();
+
+    // Only lengths shared with at least one pending move are worth hashing
+    let len = |cache: &mut HashCache, path: &Path| payload_info(cache, path).map(|i| i.len);
+    let pending_lens: Vec<Option<u64>> = pending
+        .iter()
+        .map(|p| p.and_then(|p| len(cache, p)))
+        .collect();
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for l in pending_lens.iter().flatten() {
+        *counts.entry(*l).or_default() += 1;
+    }
+    let mut candidates: Vec<&Path> = Vec::new();
+    for path in &existing {
+        if let Some(n) = len(cache, path).and_then(|l| counts.get_mut(&l)) {
+            *n += 1;
+            candidates.push(path);
+        }
+    }
+
+    let mut kept: HashMap<String, PathBuf> = HashMap::new();
+    for path in candidates {
+        if let Some(hash) = payload_hash(cache, path) {
+            kept.entry(hash).or_insert_with(|| path.to_path_buf());
+        }
+    }
+
+    pending
+        .iter()
+        .zip(&pending_lens)
+        .map(|(source, l)| {
+            let source = (*source)?;
+            if counts.get(&(*l)?).copied().unwrap_or(0) < 2 {
+                return None;
+            }
+            let hash = payload_hash(cache, source)?;
+            match kept.get(&hash) {
+                Some(original) => Some(original.clone()),
+                None => {
+                    kept.insert(hash, source.to_path_buf());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn payload_info(cache: &mut HashCache, path: &Path) -> Option<PayloadInfo> {
+    if let Some(info) = cache.get(path) {
+        return Some(info.clone());
+    }
+    let ranges = payload_ranges(path).ok()?;
+    let info = PayloadInfo {
+        len: ranges.iter().map(|r| r.end - r.start).sum(),
+        hash: None,
+    };
+    cache.insert(path, info.clone());
+    Some(info)
+}
+
+fn payload_hash(cache: &mut HashCache, path: &Path) -> Option<String> {
+    if let Some(hash) = cache.get(path).and_then(|i| i.hash.clone()) {
+        return Some(hash);
+    }
+    let ranges = payload_ranges(path).ok()?;
+    let hash = hash_ranges(path, &ranges).ok()?;
+    cache.insert(
+        path,
+        PayloadInfo {
+            len: ranges.iter().map(|r| r.end - r.start).sum(),
+            hash: Some(hash.clone()),
+        },
+    );
+    Some(hash)
+}
+
+/// SHA-256 of the audio payload of `path`, as lowercase hex.
+pub fn payload_hash_of(path: &Path) -> Result<String> {
+    hash_ranges(path, &payload_ranges(path)?)
+}
+
+fn hash_ranges(path: &Path, ranges: &[Range<u64>]) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    for range in ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut left = range.end - range.start;
+        while left > 0 {
+            let n = buf.len().min(left as usize);
+            file.read_exact(&mut buf[..n])?;
+            hasher.update(&buf[..n]);
+            left -= n as u64;
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The byte ranges of `path` that hold audio: the file without ID3 and APE
+/// tags, FLAC metadata blocks, MP4 atoms other than `mdat`, Ogg header pages
+/// and RIFF/AIFF chunks other than the sample data. Retagging a file does
+/// not change its payload.
+pub fn payload_ranges(path: &Path) -> Result<Vec<Range<u64>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let start = skip_id3v2(&mut file, len)?;
+    let mut magic = [0u8; 12];
+    let n = read_at(&mut file, start, &mut magic)?;
+    let magic = &magic[..n];
+
+    if magic.starts_with(b"fLaC") {
+        flac_ranges(&mut file, start, len)
+    } else if magic.starts_with(b"OggS") {
+        ogg_ranges(&mut file, start, len)
+    } else if magic.len() >= 8 && &magic[4..8] == b"ftyp" {
+        mp4_ranges(&mut file, start, len)
+    } else if magic.len() >= 12 && &magic[..4] == b"RIFF" && &magic[8..12] == b"WAVE" {
+        chunk_ranges(&mut file, start + 12, len, b"data", u32::from_le_bytes)
+    } else if magic.len() >= 12
+        && &magic[..4] == b"FORM"
+        && matches!(&magic[8..12], b"AIFF" | b"AIFC")
+    {
+        chunk_ranges(&mut file, start + 12, len, b"SSND", u32::from_be_bytes)
+    } else {
+        let audio = start..strip_trailing_tags(&mut file, start, len)?;
+        Ok(vec![audio])
+    }
+}
+
+/// Read up to `buf.len()` bytes at `pos`; returns how many were read.
+pub(crate) fn read_at(file: &mut File, pos: u64, buf: &mut [u8]) -> Result<usize> {
+    file.seek(SeekFrom::Start(pos))?;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+pub(crate) fn read_exact_at<const N: usize>(file: &mut File, pos: u64) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    if read_at(file, pos, &mut buf)? < N {
+        bail!("Unexpected end of file at byte {}", pos);
+    }
+    Ok(buf)
+}
+
+/// Offset after any ID3v2 tags at the start of the file.
+pub(crate) fn skip_id3v2(file: &mut File, len: u64) -> Result<u64> {
+    let mut pos = 0;
+    while pos + 10 <= len {
+        let header: [u8; 10] = read_exact_at(file, pos)?;
+        if &header[..3] != b"ID3" {
+            break;
+        }
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |acc, b| (acc << 7) | u64::from(b & 0x7f));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        pos = (pos + 10 + size + footer).min(len);
+    }
+    Ok(pos)
+}
+
+/// End of the audio once an ID3v1 tag and an APEv2 tag (in that order from
+/// the end) are cut off.
+pub(crate) fn strip_trailing_tags(file: &mut File, start: u64, len: u64) -> Result<u64> {
+    let mut end = len;
+    if end >= start + 128 {
+        let tag: [u8; 3] = read_exact_at(file, end - 128)?;
+        if &tag == b"TAG" {
+            end -= 128;
+        }
+    }
+    if end >= start + 32 {
+        let footer: [u8; 32] = read_exact_at(file, end - 32)?;
+        if &footer[..8] == b"APETAGEX" {
+            let size = u64::from(u32::from_le_bytes(footer[12..16].try_into()?));
+            let flags = u32::from_le_bytes(footer[20..24].try_into()?);
+            let header = if flags & (1 << 31) != 0 { 32 } else { 0 };
+            end = end.saturating_sub(size + header).max(start);
+        }
+    }
+    Ok(end)
+}
+
+/// Everything after the last metadata block.
+fn flac_ranges(file: &mut File, start: u64, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut pos = start + 4;
+    loop {
+        let header: [u8; 4] = read_exact_at(file, pos)?;
+        let size = u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+        pos += 4 + size;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    let frames = pos.min(len)..strip_trailing_tags(file, pos.min(len), len)?;
+    Ok(vec![frames])
+}
+
+/// The packet data of every page after the header pages. Header pages (the
+/// identification, comment and setup packets) all have granule position 0
+/// and audio starts on a fresh page. Page headers are left out as well:
+/// their sequence numbers and checksums change when the comment packet
+/// grows.
+fn ogg_ranges(file: &mut File, start: u64, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    let mut in_headers = true;
+    let mut pos = start;
+
+    while pos + 27 <= len {
+        let header: [u8; 27] = read_exact_at(file, pos)?;
+        if &header[..4] != b"OggS" {
+            bail!("Lost Ogg page sync at byte {}", pos);
+        }
+        let granule = u64::from_le_bytes(header[6..14].try_into()?);
+        let segments = usize::from(header[26]);
+        let mut table = vec![0u8; segments];
+        if read_at(file, pos + 27, &mut table)? < segments {
+            bail!("Truncated Ogg page at byte {}", pos);
+        }
+        let data_start = pos + 27 + segments as u64;
+        let data_len: u64 = table.iter().map(|&s| u64::from(s)).sum();
+        let data_end = (data_start + data_len).min(len);
+
+        in_headers = in_headers && granule == 0;
+        if !in_headers {
+            match ranges.last_mut() {
+                Some(last) if last.end == data_start => last.end = data_end,
+                _ => ranges.push(data_start..data_end),
+            }
+        }
+        pos = data_start + data_len;
+    }
+    Ok(ranges)
+}
+
+/// The contents of the top-level `mdat` atoms.
+fn mp4_ranges(file: &mut File, start: u64, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    let mut pos = start;
+
+    while len.saturating_sub(pos) >= 8 {
+        let header: [u8; 8] = read_exact_at(file, pos)?;
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into()?) {
+            0 => (len - pos, 8),
+            1 => (u64::from_be_bytes(read_exact_at(file, pos + 8)?), 16),
+            n => (u64::from(n), 8),
+        };
+        let end = match pos.checked_add(size) {
+            Some(end) if size >= header_len => end,
+            _ => bail!("Invalid MP4 atom size at byte {}", pos),
+        };
+        if &header[4..8] == b"mdat" {
+            ranges.push((pos + header_len).min(len)..end.min(len));
+        }
+        pos = end;
+    }
+    Ok(ranges)
+}
+
+/// The contents of every `id` chunk of a RIFF (little-endian sizes) or
+/// AIFF (big-endian sizes) file. Chunks are padded to an even size.
+fn chunk_ranges(
+    file: &mut File,
+    mut pos: u64,
+    len: u64,
+    id: &[u8; 4],
+    size_of: fn([u8; 4]) -> u32,
+) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    while pos + 8 <= len {
+        let header: [u8; 8] = read_exact_at(file, pos)?;
+        let size = u64::from(size_of(header[4..8].try_into()?));
+        let data = pos + 8;
+        if &header[..4] == id {
+            ranges.push(data.min(len)..(data + size).min(len));
+        }
+        pos = data + size + (size & 1);
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    fn id3v2(text: &str) -> Vec<u8> {
+        let mut frame = b"TIT2".to_vec();
+        frame.extend_from_slice(&[0, 0, 0, text.len() as u8 + 1, 0, 0, 3]);
+        frame.extend_from_slice(text.as_bytes());
+        let mut tag = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        tag.push(frame.len() as u8);
+        tag.extend(frame);
+        tag
+    }
+
+    fn ogg_page(granule: u64, sequence: u32, data: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[1, 0, 0, 0]);
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(1);
+        page.push(data.len() as u8);
+        page.extend_from_slice(data);
+        page
+    }
+
+    fn mp4(atoms: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, data) in atoms {
+            out.extend_from_slice(&(data.len() as u32 + 8).to_be_bytes());
+            out.extend_from_slice(*name);
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    #[test]
+    fn payload_ignores_tags() {
+        let tmp = temp_dir("payload");
+        let write = |name: &str, bytes: Vec<u8>| {
+            let path = tmp.join(name);
+            fs::write(&path, bytes).unwrap();
+            payload_hash_of(&path).unwrap()
+        };
+
+        // MP3: ID3v2 in front, APEv2 and ID3v1 at the end
+        let frames = b"\xff\xfb\x90\x64audio".to_vec();
+        let mut ape = b"APE tag items".to_vec();
+        ape.extend_from_slice(b"APETAGEX");
+        ape.extend_from_slice(&2000u32.to_le_bytes());
+        ape.extend_from_slice(&45u32.to_le_bytes());
+        ape.extend_from_slice(&[0; 16]);
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        let plain = write("plain.mp3", frames.clone());
+        let tagged = write(
+            "tagged.mp3",
+            [id3v2("Title"), frames.clone(), ape, id3v1].concat(),
+        );
+        assert_eq!(plain, tagged);
+        assert_ne!(plain, write("other.mp3", b"\xff\xfb\x90\x64other".to_vec()));
+
+        // FLAC: metadata blocks before the frames
+        let flac = |comment: &[u8]| {
+            let mut out = b"fLaC\x00\x00\x00\x02si".to_vec();
+            out.extend_from_slice(&[0x84, 0, 0, comment.len() as u8]);
+            out.extend_from_slice(comment);
+            out.extend_from_slice(b"\xff\xf8frames");
+            out
+        };
+        assert_eq!(
+            write("a.flac", flac(b"TITLE=A")),
+            write("b.flac", flac(b"TITLE=Longer"))
+        );
+
+        // MP4: only mdat counts, wherever it is
+        assert_eq!(
+            write(
+                "a.m4a",
+                mp4(&[(b"ftyp", b"M4A "), (b"moov", b"x"), (b"mdat", b"aac")])
+            ),
+            write(
+                "b.m4a",
+                mp4(&[(b"ftyp", b"M4A "), (b"mdat", b"aac"), (b"moov", b"yy")])
+            )
+        );
+
+        // Ogg: header pages and page headers are left out
+        let ogg = |comment: &[u8], first_audio_page: u32| {
+            [
+                ogg_page(0, 0, b"\x01vorbis"),
+                ogg_page(0, 1, comment),
+                ogg_page(4096, first_audio_page, b"packets"),
+            ]
+            .concat()
+        };
+        assert_eq!(
+            write("a.ogg", ogg(b"\x03vorbis a", 2)),
+            write("b.ogg", ogg(b"\x03vorbis bb", 3))
+        );
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn oversized_mp4_atom_is_an_error() {
+        let tmp = temp_dir("payload_mp4_size");
+        let path = tmp.join("huge.m4a");
+        let mut data = mp4(&[(b"ftyp", b"M4A ")]);
+        data.extend_from_slice(b"\x00\x00\x00\x01mdat");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        data.extend_from_slice(b"aac");
+        fs::write(&path, data).unwrap();
+
+        let err = payload_ranges(&path).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid MP4 atom size at byte 12");
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn finds_duplicates_in_the_plan_and_the_library() {
+        let tmp = temp_dir("find_duplicates");
+        fs::create_dir_all(tmp.join("in")).unwrap();
+        fs::create_dir_all(tmp.join("lib")).unwrap();
+
+        let audio = |tag: &str, body: &[u8]| [id3v2(tag), body.to_vec()].concat();
+        fs::write(tmp.join("lib/kept.mp3"), audio("Kept", b"\xff\xfbsong")).unwrap();
+        fs::write(tmp.join("in/a.mp3"), audio("Retagged", b"\xff\xfbsong")).unwrap();
+        fs::write(tmp.join("in/b.mp3"), audio("New", b"\xff\xfbnew!")).unwrap();
+        fs::write(tmp.join("in/c.mp3"), audio("Copy of new", b"\xff\xfbnew!")).unwrap();
+        fs::write(tmp.join("in/d.mp3"), audio("Same length", b"\xff\xfbnope")).unwrap();
+
+        let moves: Vec<PlannedMove> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| PlannedMove {
+                source: tmp.join(format!("in/{}.mp3", name)),
+                dest: tmp.join(format!("lib/{}.mp3", name)),
+                ..Default::default()
+            })
+            .collect();
+        let library = vec![tmp.join("lib/kept.mp3")];
+
+        let mut cache = HashCache::in_memory();
+        let found = find_duplicates(&moves, &library, &mut cache);
+        assert_eq!(
+            found,
+            vec![
+                Some(tmp.join("lib/kept.mp3")),
+                None,
+                Some(tmp.join("in/b.mp3")),
+                None
+            ]
+        );
+        assert!(cache.get(&tmp.join("in/d.mp3")).unwrap().hash.is_some());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
@@ -0,0 +1,617 @@
+use crate::journal::{self, BackedUp, Journal};
+use crate::sorting::{execute_move, Conflict, PlannedMove, TransferMode};
+use crate::tags;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Files that do not keep a directory from being pruned: thumbnail caches
+/// and folder settings that file managers leave behind.
+pub const JUNK_FILES: &[&str] = &["Thumbs.db", "ehthumbs.db", "desktop.ini", ".DS_Store"];
+
+/// Outcome of executing one planned move.
+#[derive(Debug, Serialize)]
+pub struct ExecResult {
+    pub source: String,
+    pub dest: String,
+    pub status: ExecStatus,
+    /// `None` if the file was already in place.
+    pub applied_mode: Option<TransferMode>,
+    /// Set once the planned tag changes were written (`--write-tags`).
+    pub tags_written: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecStatus {
+    Ok,
+    Error,
+}
+
+impl ExecResult {
+    pub fn new(m: &PlannedMove, outcome: &Result<TransferMode>) -> ExecResult {
+        ExecResult {
+            source: m.source.to_string_lossy().into_owned(),
+            dest: m.dest.to_string_lossy().into_owned(),
+            status: if outcome.is_ok() {
+                ExecStatus::Ok
+            } else {
+                ExecStatus::Error
+            },
+            applied_mode: outcome.as_ref().ok().copied(),
+            tags_written: false,
+            error: outcome.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+    }
+
+    /// A file that was already in place, so only its tags are written.
+    fn in_place(m: &PlannedMove) -> ExecResult {
+        ExecResult {
+            source: m.source.to_string_lossy().into_owned(),
+            dest: m.dest.to_string_lossy().into_owned(),
+            status: ExecStatus::Ok,
+            applied_mode: None,
+            tags_written: false,
+            error: None,
+        }
+    }
+
+    fn record_tags(&mut self, outcome: &Result<()>) {
+        match outcome {
+            Ok(()) => self.tags_written = true,
+            Err(e) => {
+                self.status = ExecStatus::Error;
+                self.error = Some(format!("{:#}", e));
+            }
+        }
+    }
+}
+
+/// Reported to the progress callback while executing.
+#[derive(Debug)]
+pub enum Progress<'a> {
+    /// A transfer (and the tag write after it) finished, successfully or
+    /// not. `index` counts from 1 to `total`.
+    Transferred {
+        index: usize,
+        total: usize,
+        planned: &'a PlannedMove,
+        result: &'a ExecResult,
+    },
+    /// A transfer or tag write succeeded but could not be recorded in the
+    /// journal, so it cannot be undone with `tagmv undo`.
+    JournalFailed {
+        planned: &'a PlannedMove,
+        error: &'a anyhow::Error,
+    },
+}
+
+/// What an execution did.
+#[derive(Debug)]
+pub struct Execution {
+    /// One entry per attempted transfer or tag write (moves already in
+    /// place without tag changes, or skipped for a conflict, are left out).
+    pub results: Vec<ExecResult>,
+    /// The journal written for this run, if journaling is enabled.
+    pub journal: Option<PathBuf>,
+    /// Source directories removed by [`Executor::prune_empty`], deepest
+    /// first.
+    pub pruned: Vec<PathBuf>,
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(Progress<'_>) + 'a>;
+
+/// Executes planned moves, journaling them for `tagmv undo`.
+///
+/// ```no_run
+/// use tagmv::{Executor, Planner, Progress};
+///
+/// let plan = Planner::new("/home/me/Downloads").plan()?;
+/// let execution = Executor::new()
+///     .on_progress(|p| {
+///         if let Progress::Transferred { index, total, .. } = p {
+///             eprintln!("{}/{}", index, total);
+///         }
+///     })
+///     .execute(&plan.target, &plan.moves)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Executor<'a> {
+    journal: bool,
+    prune_root: Option<PathBuf>,
+    junk_files: Vec<String>,
+    on_progress: Option<ProgressFn<'a>>,
+}
+
+impl Default for Executor<'_> {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+impl<'a> Executor<'a> {
+    pub fn new() -> Executor<'a> {
+        Executor {
+            journal: true,
+            prune_root: None,
+            junk_files: JUNK_FILES.iter().map(|f| f.to_string()).collect(),
+            on_progress: None,
+        }
+    }
+
+    /// Record a journal under the target (on by default).
+    pub fn journal(mut self, journal: bool) -> Executor<'a> {
+        self.journal = journal;
+        self
+    }
+
+    /// Afterwards, remove the directories that files were moved out of if
+    /// nothing but junk files is left in them, and their parents up to (but
+    /// not including) `root`. Nothing is removed if any transfer or tag
+    /// write failed. Junk files are backed up in the journal first.
+    pub fn prune_empty(mut self, root: impl Into<PathBuf>) -> Executor<'a> {
+        self.prune_root = Some(root.into());
+        self
+    }
+
+    /// File names that [`Executor::prune_empty`] deletes along with a
+    /// directory (compared case-insensitively), replacing [`JUNK_FILES`].
+    pub fn junk_files<I, S>(mut self, files: I) -> Executor<'a>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.junk_files = files.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn on_progress(mut self, f: impl FnMut(Progress<'_>) + 'a) -> Executor<'a> {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Execute every move that needs a transfer, then write its planned tag
+    /// changes at the destination (backed up in the journal first). A file
+    /// that a transfer overwrites is backed up in the journal too. Failed
+    /// transfers and tag writes are reported in the results; only failing
+    /// to create the journal is an error.
+    pub fn execute(&mut self, target: &Path, moves: &[PlannedMove]) -> Result<Execution> {
+        let mut journal = if self.journal {
+            Some(Journal::create(target)?)
+        } else {
+            None
+        };
+
+        let pending: Vec<&PlannedMove> = moves.iter().filter(|m| m.is_pending()).collect();
+        let total = pending.len();
+        let mut results = Vec::with_capacity(total);
+        // Directories that files were moved out of
+        let mut vacated = BTreeSet::new();
+
+        for (i, m) in pending.into_iter().enumerate() {
+            if !m.needs_transfer() {
+                let mut result = ExecResult::in_place(m);
+                let written = if m.source != m.dest && shares_source(m.mode) {
+                    Err(not_through_link(m.mode))
+                } else {
+                    self.write_tags(m, journal.as_mut())
+                };
+                result.record_tags(&written);
+                self.report(Progress::Transferred {
+                    index: i + 1,
+                    total,
+                    planned: m,
+                    result: &result,
+                });
+                results.push(result);
+                continue;
+            }
+
+            let created = match journal {
+                Some(_) => m
+                    .dest
+                    .parent()
+                    .map(journal::missing_dirs)
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            };
+
+            let (outcome, replaced) = match back_up_replaced(m, journal.as_mut()) {
+                Ok(replaced) => (execute_move(m), replaced),
+                Err(e) => (Err(e), None),
+            };
+            if let (Err(_), Some(backup)) = (&outcome, &replaced) {
+                let _ = fs::remove_file(backup);
+            }
+            if let (Some(dir), Ok(TransferMode::Move)) = (m.source.parent(), &outcome) {
+                vacated.insert(dir.to_path_buf());
+            }
+            if let (Ok(applied), Some(journal)) = (&outcome, journal.as_mut()) {
+                if let Err(error) = journal
+                    .record_mkdirs(&created)
+                    .and_then(|_| journal.record_transfer(m, *applied, replaced.as_deref()))
+                {
+                    self.report(Progress::JournalFailed {
+                        planned: m,
+                        error: &error,
+                    });
+                }
+            }
+
+            let mut result = ExecResult::new(m, &outcome);
+            if let (Ok(applied), false) = (&outcome, m.tag_changes.is_empty()) {
+                let written = if shares_source(*applied) {
+                    Err(not_through_link(*applied))
+                } else {
+                    self.write_tags(m, journal.as_mut())
+                };
+                result.record_tags(&written);
+            }
+            self.report(Progress::Transferred {
+                index: i + 1,
+                total,
+                planned: m,
+                result: &result,
+            });
+            results.push(result);
+        }
+
+        let failed = results.iter().any(|r| r.status == ExecStatus::Error);
+        let pruned = match &self.prune_root {
+            Some(root) if !failed => self.prune(root, target, vacated, journal.as_mut()),
+            _ => Vec::new(),
+        };
+
+        Ok(Execution {
+            results,
+            journal: journal.map(|j| j.path().to_path_buf()),
+            pruned,
+        })
+    }
+
+    /// Write the planned tag changes into the file at `m.dest`. With a
+    /// journal, the file is backed up first and the write recorded, so it
+    /// can be undone.
+    fn write_tags(&mut self, m: &PlannedMove, journal: Option<&mut Journal>) -> Result<()> {
+        let Some(journal) = journal else {
+            return tags::write_tags(&m.dest, &m.tag_changes);
+        };
+        let backup = journal.backup(&m.dest)?;
+        if let Err(e) = tags::write_tags(&m.dest, &m.tag_changes) {
+            let _ = fs::remove_file(&backup);
+            return Err(e);
+        }
+        if let Err(error) = journal.record_tags(&m.dest, &backup) {
+            self.report(Progress::JournalFailed {
+                planned: m,
+                error: &error,
+            });
+        }
+        Ok(())
+    }
+
+    /// Remove the `vacated` directories that are empty but for junk, then
+    /// their parents, staying inside `root` and out of `target`.
+    fn prune(
+        &self,
+        root: &Path,
+        target: &Path,
+        vacated: BTreeSet<PathBuf>,
+        mut journal: Option<&mut Journal>,
+    ) -> Vec<PathBuf> {
+        let mut vacated: Vec<PathBuf> = vacated.into_iter().collect();
+        // Deepest first, so that a parent is looked at after its children
+        vacated.sort_by_key(|dir| Reverse(dir.components().count()));
+
+        let mut pruned = Vec::new();
+        for dir in &vacated {
+            let mut dir = dir.as_path();
+            while dir.starts_with(root) && dir != root && dir != target {
+                let Some(junk) = junk_only(dir, &self.junk_files) else {
+                    break;
+                };
+                let backups = match journal.as_mut() {
+                    Some(journal) => match junk.iter().map(|f| journal.backup(f)).collect() {
+                        Ok(backups) => backups,
+                        Err(_) => break,
+                    },
+                    None => Vec::new(),
+                };
+                if !(junk.iter().all(|f| fs::remove_file(f).is_ok()) && fs::remove_dir(dir).is_ok())
+                {
+                    for backup in &backups {
+                        let _ = fs::remove_file(backup);
+                    }
+                    break;
+                }
+                if let Some(journal) = journal.as_mut() {
+                    // Undo recreates missing directories when it moves files
+                    // back anyway; the entry matters for empty ones and junk
+                    let junk = junk
+                        .into_iter()
+                        .zip(backups)
+                        .map(|(path, backup)| BackedUp { path, backup })
+                        .collect();
+                    let _ = journal.record_rmdir(dir, junk);
+                }
+                pruned.push(dir.to_path_buf());
+                let Some(parent) = dir.parent() else { break };
+                dir = parent;
+            }
+        }
+        pruned
+    }
+
+    fn report(&mut self, progress: Progress<'_>) {
+        if let Some(f) = self.on_progress.as_mut() {
+            f(progress);
+        }
+    }
+}
+
+/// Back up the file that `m` is about to overwrite, so that undo can put it
+/// back. Without a journal there is nothing to restore it from. A replaced
+/// symlink needs no backup, its target is left alone.
+fn back_up_replaced(m: &PlannedMove, journal: Option<&mut Journal>) -> Result<Option<PathBuf>> {
+    let replaces_file = m.conflict == Some(Conflict::Overwrites)
+        && fs::symlink_metadata(&m.dest).is_ok_and(|meta| meta.is_file());
+    match journal {
+        Some(journal) if replaces_file => journal.backup(&m.dest).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// True if a destination made in `mode` shares its data with the source,
+/// so writing tags into it would rewrite the source too.
+fn shares_source(mode: TransferMode) -> bool {
+    matches!(mode, TransferMode::Hardlink | TransferMode::Symlink)
+}
+
+fn not_through_link(mode: TransferMode) -> anyhow::Error {
+    anyhow!(
+        "Tags not written: the destination is a {} to the source, which would change too",
+        mode
+    )
+}
+
+/// The files in `dir`, if it holds nothing but `junk` files.
+fn junk_only(dir: &Path, junk: &[String]) -> Option<Vec<PathBuf>> {
+    let mut junk_found = Vec::new();
+    for entry in fs::read_dir(dir).ok()? {
+        let entry = entry.ok()?;
+        let name = entry.file_name();
+        let is_junk = entry.file_type().is_ok_and(|t| t.is_file())
+            && junk
+                .iter()
+                .any(|j| j.eq_ignore_ascii_case(&name.to_string_lossy()));
+        if !is_junk {
+            return None;
+        }
+        junk_found.push(entry.path());
+    }
+    Some(junk_found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mpeg_frames, temp_dir};
+    use std::fs;
+
+    #[test]
+    fn executes_pending_moves_and_reports_progress() {
+        let tmp = temp_dir("executor");
+        fs::write(tmp.join("a.mp3"), "audio").unwrap();
+        fs::write(tmp.join("b.mp3"), "audio").unwrap();
+
+        let moves = vec![
+            PlannedMove {
+                source: tmp.join("a.mp3"),
+                dest: tmp.join("lib/a.mp3"),
+                ..Default::default()
+            },
+            PlannedMove {
+                source: tmp.join("b.mp3"),
+                dest: tmp.join("b.mp3"),
+                ..Default::default()
+            },
+            PlannedMove {
+                source: tmp.join("missing.mp3"),
+                dest: tmp.join("lib/missing.mp3"),
+                ..Default::default()
+            },
+        ];
+
+        let mut seen = Vec::new();
+        let execution = Executor::new()
+            .on_progress(|p| {
+                if let Progress::Transferred { index, total, .. } = p {
+                    seen.push((index, total));
+                }
+            })
+            .execute(&tmp, &moves)
+            .unwrap();
+
+        assert_eq!(seen, vec![(1, 2), (2, 2)]);
+        assert_eq!(execution.results[0].status, ExecStatus::Ok);
+        assert_eq!(execution.results[0].applied_mode, Some(TransferMode::Move));
+        assert_eq!(execution.results[1].status, ExecStatus::Error);
+        assert!(tmp.join("lib/a.mp3").exists());
+        assert!(execution.journal.unwrap().exists());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn writes_tags_that_undo_restores() {
+        let tmp = temp_dir("executor_tags");
+        let audio = mpeg_frames();
+        fs::write(tmp.join("a.mp3"), &audio).unwrap();
+        fs::write(tmp.join("b.mp3"), &audio).unwrap();
+
+        let change = |field, new: &str| tags::TagChange {
+            field,
+            old: None,
+            new: new.to_string(),
+        };
+        let tag_changes = vec![change("artist", "Artist"), change("album", "Album")];
+        let moves = vec![
+            PlannedMove {
+                source: tmp.join("a.mp3"),
+                dest: tmp.join("lib/a.mp3"),
+                tag_changes: tag_changes.clone(),
+                ..Default::default()
+            },
+            PlannedMove {
+                source: tmp.join("b.mp3"),
+                dest: tmp.join("b.mp3"),
+                tag_changes,
+                ..Default::default()
+            },
+        ];
+
+        let execution = Executor::new().execute(&tmp, &moves).unwrap();
+        assert!(execution.results.iter().all(|r| r.tags_written));
+        assert_eq!(execution.results[1].applied_mode, None);
+        for path in [tmp.join("lib/a.mp3"), tmp.join("b.mp3")] {
+            assert_eq!(tags::read_tags(&path).unwrap().album, "Album");
+        }
+
+        let steps = journal::undo(&execution.journal.unwrap(), false).unwrap();
+        assert!(steps.iter().all(|s| s.result.is_ok()));
+        for name in ["a.mp3", "b.mp3"] {
+            assert_eq!(fs::read(tmp.join(name)).unwrap(), audio);
+        }
+        assert!(!tmp.join("lib").exists());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn undo_restores_overwritten_files() {
+        let tmp = temp_dir("executor_overwrite");
+        fs::create_dir_all(tmp.join("lib")).unwrap();
+        fs::write(tmp.join("a.mp3"), "new").unwrap();
+        fs::write(tmp.join("lib/a.mp3"), "old").unwrap();
+
+        let moves = vec![PlannedMove {
+            source: tmp.join("a.mp3"),
+            dest: tmp.join("lib/a.mp3"),
+            conflict: Some(Conflict::Overwrites),
+            ..Default::default()
+        }];
+        let execution = Executor::new().execute(&tmp, &moves).unwrap();
+        assert_eq!(execution.results[0].status, ExecStatus::Ok);
+        assert_eq!(fs::read_to_string(tmp.join("lib/a.mp3")).unwrap(), "new");
+
+        let steps = journal::undo(&execution.journal.unwrap(), false).unwrap();
+        assert!(steps.iter().all(|s| s.result.is_ok()));
+        assert_eq!(fs::read_to_string(tmp.join("a.mp3")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(tmp.join("lib/a.mp3")).unwrap(), "old");
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn does_not_write_tags_through_links() {
+        let tmp = temp_dir("executor_link_tags");
+        let audio = mpeg_frames();
+        fs::write(tmp.join("a.mp3"), &audio).unwrap();
+        fs::create_dir_all(tmp.join("lib")).unwrap();
+        fs::hard_link(tmp.join("a.mp3"), tmp.join("lib/linked.mp3")).unwrap();
+
+        let tag_changes = vec![tags::TagChange {
+            field: "album",
+            old: None,
+            new: "Album".to_string(),
+        }];
+        let moves = vec![
+            PlannedMove {
+                source: tmp.join("a.mp3"),
+                dest: tmp.join("lib/a.mp3"),
+                mode: TransferMode::Hardlink,
+                tag_changes: tag_changes.clone(),
+                ..Default::default()
+            },
+            // Linked by an earlier run
+            PlannedMove {
+                source: tmp.join("a.mp3"),
+                dest: tmp.join("lib/linked.mp3"),
+                mode: TransferMode::Hardlink,
+                tag_changes,
+                ..Default::default()
+            },
+        ];
+
+        let execution = Executor::new().execute(&tmp, &moves).unwrap();
+        assert_eq!(execution.results.len(), 2);
+        for result in &execution.results {
+            assert!(!result.tags_written);
+            assert_eq!(result.status, ExecStatus::Error);
+        }
+        assert_eq!(
+            execution.results[0].applied_mode,
+            Some(TransferMode::Hardlink)
+        );
+        assert_eq!(fs::read(tmp.join("a.mp3")).unwrap(), audio);
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn prunes_directories_left_empty() {
+        let tmp = temp_dir("executor_prune");
+        let execute = |names: &[&str]| {
+            // Each run starts from the same tree
+            for dir in ["in", "lib"] {
+                let _ = fs::remove_dir_all(tmp.join(dir));
+            }
+            for dir in ["in/a/cd1", "in/b", "in/c"] {
+                fs::create_dir_all(tmp.join(dir)).unwrap();
+            }
+            fs::write(tmp.join("in/a/cd1/1.mp3"), "audio").unwrap();
+            fs::write(tmp.join("in/a/Thumbs.db"), "junk").unwrap();
+            fs::write(tmp.join("in/b/2.mp3"), "audio").unwrap();
+            fs::write(tmp.join("in/b/notes.pdf"), "keep").unwrap();
+
+            let moves: Vec<PlannedMove> = names
+                .iter()
+                .map(|name| PlannedMove {
+                    source: tmp.join("in").join(name),
+                    dest: tmp.join("lib").join(name),
+                    ..Default::default()
+                })
+                .collect();
+            Executor::new()
+                .prune_empty(tmp.join("in"))
+                .execute(&tmp.join("lib"), &moves)
+                .unwrap()
+        };
+
+        let execution = execute(&["a/cd1/1.mp3", "b/2.mp3"]);
+        assert_eq!(
+            execution.pruned,
+            vec![tmp.join("in/a/cd1"), tmp.join("in/a")]
+        );
+        assert!(tmp.join("in/b/notes.pdf").exists());
+        assert!(tmp.join("in/c").exists());
+
+        journal::undo(&execution.journal.unwrap(), false).unwrap();
+        assert!(tmp.join("in/a/cd1/1.mp3").exists());
+        assert_eq!(
+            fs::read_to_string(tmp.join("in/a/Thumbs.db")).unwrap(),
+            "junk"
+        );
+
+        // A failed transfer keeps everything
+        let execution = execute(&["a/cd1/1.mp3", "c/missing.mp3"]);
+        assert!(execution.pruned.is_empty());
+        assert!(tmp.join("in/a/cd1").exists());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::tags::TrackMetadata;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// Patterns tried, in order, when inference is on and none are configured.
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    "{artist} - {album}/{track} - {title}",
+    "{artist} - {album}/{track}. {title}",
+    "{artist} - {album}/{track} {title}",
+    "{artist}/{album}/{track} - {title}",
+    "{artist}/{album}/{track}. {title}",
+    "{artist}/{album}/{track} {title}",
+];
+
+/// Field names a pattern may capture.
+pub const FIELDS: &[&str] = &[
+    "artist",
+    "album",
+    "albumartist",
+    "title",
+    "track",
+    "disc",
+    "year",
+    "genre",
+];
+
+/// Fields that only match digits.
+const NUMBER_FIELDS: &[&str] = &["track", "disc", "year"];
+
+/// A parsed pattern for reading tags out of a file's path.
+///
+/// Syntax:
+/// - `{field}` captures text, e.g. `{artist}`; `{track}`, `{disc}` and
+///   `{year}` only capture digits
+/// - `/` separates path components; `\` escapes the next character
+///
+/// The pattern is matched against the last components of the path (relative
+/// to the scanned directory, so the directories above it never match), the
+/// file name without its extension last: `{artist} - {album}/{track} {title}`
+/// reads `Artist - Album/01 Title.mp3`. Text fields take as little as they
+/// can, so in `{artist} - {title}` a second ` - ` ends up in the title. A
+/// pattern may capture any of the fields; what it leaves out can come from
+/// the file's own tags (see [`merge`]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern {
+    source: String,
+    components: Vec<Vec<Part>>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field(&'static str),
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Pattern> {
+        let mut components = vec![Vec::new()];
+        let mut literal = String::new();
+        let mut chars = source.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(escaped) => literal.push(escaped),
+                    None => bail!("Pattern ends with an unfinished escape"),
+                },
+                '/' => {
+                    flush_literal(&mut literal, components.last_mut().unwrap());
+                    components.push(Vec::new());
+                }
+                '{' => {
+                    let parts = components.last_mut().unwrap();
+                    flush_literal(&mut literal, parts);
+                    let rest = chars.as_str();
+                    let Some((name, after)) = rest.split_once('}') else {
+                        bail!("Unclosed '{{' in pattern: {}", source);
+                    };
+                    chars = after.chars();
+                    let Some(field) = FIELDS.iter().find(|f| **f == name) else {
+                        bail!("Unknown pattern field: {{{}}}", name);
+                    };
+                    if matches!(parts.last(), Some(Part::Field(_))) {
+                        bail!("Pattern fields must be separated by text: {}", source);
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => bail!("Unmatched '}}' in pattern: {}", source),
+                _ => literal.push(c),
+            }
+        }
+        flush_literal(&mut literal, components.last_mut().unwrap());
+
+        if components.iter().any(Vec::is_empty) {
+            bail!("Pattern has an empty path component: {}", source);
+        }
+
+        Ok(Pattern {
+            source: source.to_string(),
+            components,
+        })
+    }
+
+    /// Tags read out of `path`, if the pattern matches it. Artist and album
+    /// are empty if the pattern does not capture them.
+    pub fn infer(&self, path: &Path) -> Option<TrackMetadata> {
+        let stem = path.file_stem()?.to_str()?;
+        let mut names: Vec<&str> = path
+            .parent()?
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        names.push(stem);
+        let names = names.get(names.len().checked_sub(self.components.len())?..)?;
+
+        let mut captures = Vec::new();
+        for (parts, name) in self.components.iter().zip(names) {
+            if !match_parts(parts, name, &mut captures) {
+                return None;
+            }
+        }
+
+        let text = |name: &str| {
+            captures
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.to_string())
+        };
+        let number = |name: &str| text(name).and_then(|n| n.parse().ok());
+        let album_artist = text("albumartist");
+        Some(TrackMetadata {
+            artist: text("artist")
+                .or_else(|| album_artist.clone())
+                .unwrap_or_default(),
+            album: text("album").unwrap_or_default(),
+            title: text("title"),
+            track_number: number("track"),
+            album_artist,
+            disc_number: number("disc"),
+            year: number("year"),
+            genre: text("genre"),
+            ..Default::default()
+        })
+    }
+}
+
+/// Tags from the first of `patterns` that matches `path`, with the pattern.
+pub fn infer<'a>(path: &Path, patterns: &'a [Pattern]) -> Option<(TrackMetadata, &'a Pattern)> {
+    patterns
+        .iter()
+        .find_map(|pattern| Some((pattern.infer(path)?, pattern)))
+}
+
+/// `tags` with the fields it lacks taken from `inferred`.
+pub fn merge(mut tags: TrackMetadata, inferred: TrackMetadata) -> TrackMetadata {
+    if tags.artist.is_empty() {
+        tags.artist = inferred.artist;
+    }
+    if tags.album.is_empty() {
+        tags.album = inferred.album;
+    }
+    tags.title = tags.title.or(inferred.title);
+    tags.track_number = tags.track_number.or(inferred.track_number);
+    tags.album_artist = tags.album_artist.or(inferred.album_artist);
+    tags.disc_number = tags.disc_number.or(inferred.disc_number);
+    tags.year = tags.year.or(inferred.year);
+    tags.genre = tags.genre.or(inferred.genre);
+    tags
+}
+
+/// The [`DEFAULT_PATTERNS`], parsed.
+pub fn default_patterns() -> Vec<Pattern> {
+    DEFAULT_PATTERNS
+        .iter()
+        .map(|p| Pattern::parse(p).expect("default pattern is valid"))
+        .collect()
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Pattern::parse(s)
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Pattern::parse(&s)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn flush_literal(literal: &mut String, parts: &mut Vec<Part>) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(std::mem::take(literal)));
+    }
+}
+
+/// Match one path component against `parts`, backtracking over how much
+/// text each field takes. A field captured twice must capture the same text.
+fn match_parts<'a>(
+    parts: &[Part],
+    text: &'a str,
+    captures: &mut Vec<(&'static str, &'a str)>,
+) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return text.is_empty();
+    };
+    let field = match part {
+        Part::Literal(literal) => {
+            return text
+                .strip_prefix(literal.as_str())
+                .is_some_and(|text| match_parts(rest, text, captures));
+        }
+        Part::Field(field) => *field,
+    };
+
+    let ends = text.char_indices().map(|(i, _)| i).skip(1);
+    for end in ends.chain([text.len()]).filter(|&end| end > 0) {
+        let value = &text[..end];
+        if NUMBER_FIELDS.contains(&field) && !value.bytes().all(|b| b.is_ascii_digit()) {
+            break;
+        }
+        let value = value.trim();
+        let seen = captures.iter().find(|(name, _)| *name == field);
+        if value.is_empty() || seen.is_some_and(|(_, seen)| *seen != value) {
+            continue;
+        }
+        captures.push((field, value));
+        if match_parts(rest, &text[end..], captures) {
+            return true;
+        }
+        captures.pop();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_tags_from_folder_and_file_names() {
+        let patterns = default_patterns();
+        let (meta, pattern) = infer(
+            Path::new("/dl/The Band - Greatest - Hits/07 - Song - Live.mp3"),
+            &patterns,
+        )
+        .unwrap();
+        assert_eq!(pattern.to_string(), DEFAULT_PATTERNS[0]);
+        assert_eq!(meta.artist, "The Band");
+        assert_eq!(meta.album, "Greatest - Hits");
+        assert_eq!(meta.title.as_deref(), Some("Song - Live"));
+        assert_eq!(meta.track_number, Some(7));
+
+        let (meta, _) = infer(Path::new("/dl/Artist/Album/3 Song.flac"), &patterns).unwrap();
+        assert_eq!(
+            (meta.artist.as_str(), meta.album.as_str()),
+            ("Artist", "Album")
+        );
+        assert_eq!(meta.track_number, Some(3));
+
+        // No track number, and a path too short for the pattern
+        assert!(infer(Path::new("/dl/Artist - Album/Song.mp3"), &patterns).is_none());
+        assert!(infer(Path::new("01 Song.mp3"), &patterns).is_none());
+    }
+
+    #[test]
+    fn inferred_tags_only_fill_gaps() {
+        let tags = TrackMetadata {
+            title: Some("Real Title".to_string()),
+            track_number: Some(5),
+            ..Default::default()
+        };
+        let (inferred, _) = infer(
+            Path::new("Artist - Album/01 - Guess.mp3"),
+            &default_patterns(),
+        )
+        .unwrap();
+        let meta = merge(tags, inferred);
+        assert_eq!(
+            (meta.artist.as_str(), meta.album.as_str()),
+            ("Artist", "Album")
+        );
+        assert_eq!(meta.title.as_deref(), Some("Real Title"));
+        assert_eq!(meta.track_number, Some(5));
+
+        // A pattern without the album fills in only the artist
+        let tags = TrackMetadata {
+            album: "Tagged Album".to_string(),
+            ..Default::default()
+        };
+        let pattern = Pattern::parse("{track}. {artist} - {title}").unwrap();
+        let inferred = pattern
+            .infer(Path::new("dl/03. Someone - Song.mp3"))
+            .unwrap();
+        assert!(inferred.album.is_empty());
+        let meta = merge(tags, inferred);
+        assert_eq!(
+            (meta.artist.as_str(), meta.album.as_str()),
+            ("Someone", "Tagged Album")
+        );
+        assert_eq!(meta.track_number, Some(3));
+    }
+
+    #[test]
+    fn custom_patterns() {
+        let pattern = Pattern::parse("{album} ({year})/{track}. {artist} - {title}").unwrap();
+        let meta = pattern
+            .infer(Path::new("Mix (2001)/02. Someone - Thing.ogg"))
+            .unwrap();
+        assert_eq!(meta.album, "Mix");
+        assert_eq!(meta.year, Some(2001));
+        assert_eq!(meta.artist, "Someone");
+        assert_eq!(meta.title.as_deref(), Some("Thing"));
+
+        let repeated = Pattern::parse("{artist}/{album}/{artist} - {title}").unwrap();
+        assert!(repeated.infer(Path::new("A/B/A - Song.mp3")).is_some());
+        assert!(repeated.infer(Path::new("A/B/C - Song.mp3")).is_none());
+
+        assert!(Pattern::parse("{artist} - {album}\\/x").is_ok());
+        assert!(Pattern::parse("{artist}{album}").is_err());
+        assert!(Pattern::parse("{artist}//{album}").is_err());
+        assert!(Pattern::parse("{artist} - {title}").is_ok());
+        assert!(Pattern::parse("{artist} - {album")
+            .unwrap_err()
+            .to_string()
+            .contains("Unclosed '{'"));
+        assert!(Pattern::parse("{artist} - {nonsense}")
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown pattern field"));
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::{bail, Context, Result};
 use std::fs;
 use std::path::PathBuf;
+use tagmv::config::Config;
 
 const MENU_LABEL: &str = "Sort Music by Tags";
 
@@ -48,6 +49,58 @@ fn warn_if_build_dir(exe_str: &str) {
     }
 }
 
+/// One context menu entry: the default one, or one that runs with a config
+/// profile. Profile entries get their own label and file names so that they
+/// can be installed next to the default entry.
+struct MenuEntry {
+    profile: Option<String>,
+}
+
+impl MenuEntry {
+    fn new(profile: Option<&str>) -> Result<MenuEntry> {
+        if let Some(name) = profile {
+            // The name ends up in shell scripts, desktop files and registry
+            // keys; keep it to characters that need no quoting anywhere
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                bail!(
+                    "Invalid profile name for a menu entry: \"{}\" (use letters, digits, - and _)",
+                    name
+                );
+            }
+        }
+        Ok(MenuEntry {
+            profile: profile.map(str::to_string),
+        })
+    }
+
+    fn label(&self) -> String {
+        match &self.profile {
+            Some(name) => format!("{} ({})", MENU_LABEL, name),
+            None => MENU_LABEL.to_string(),
+        }
+    }
+
+    /// File name / registry key stem.
+    fn id(&self) -> String {
+        match &self.profile {
+            Some(name) => format!("tagmv-{}", name),
+            None => "tagmv".to_string(),
+        }
+    }
+
+    /// Arguments passed to tagmv before the selected folder.
+    fn args(&self) -> String {
+        match &self.profile {
+            Some(name) => format!("--profile {} --execute", name),
+            None => "--execute".to_string(),
+        }
+    }
+}
+
 /// Escape a string for safe embedding in XML text content.
 fn xml_escape(s: &str) -> String {
     let mut out = String::with_capacity(s.len());
@@ -83,25 +136,33 @@ fn shell_escape(s: &str) -> String {
 // Public entry points
 // ---------------------------------------------------------------------------
 
-pub fn install_quick_action() -> Result<()> {
+pub fn install_quick_action(profile: Option<&str>) -> Result<()> {
+    let entry = MenuEntry::new(profile)?;
+    if let Some(name) = profile {
+        // A menu entry for a missing profile would fail on every click, and
+        // file managers may never show why. Profiles from the user config
+        // and a `.tagmv.toml` in the home directory apply everywhere
+        Config::load(&home_dir()?)?.settings(Some(name))?;
+    }
     if cfg!(target_os = "macos") {
-        install_macos()
+        install_macos(&entry)
     } else if cfg!(target_os = "linux") {
-        install_linux()
+        install_linux(&entry)
     } else if cfg!(target_os = "windows") {
-        install_windows()
+        install_windows(&entry)
     } else {
         bail!("Unsupported platform for context menu installation")
     }
 }
 
-pub fn uninstall_quick_action() -> Result<()> {
+pub fn uninstall_quick_action(profile: Option<&str>) -> Result<()> {
+    let entry = MenuEntry::new(profile)?;
     if cfg!(target_os = "macos") {
-        uninstall_macos()
+        uninstall_macos(&entry)
     } else if cfg!(target_os = "linux") {
-        uninstall_linux()
+        uninstall_linux(&entry)
     } else if cfg!(target_os = "windows") {
-        uninstall_windows()
+        uninstall_windows(&entry)
     } else {
         bail!("Unsupported platform for context menu removal")
     }
@@ -111,17 +172,17 @@ pub fn uninstall_quick_action() -> Result<()> {
 // macOS -- Automator Quick Action
 // ===========================================================================
 
-fn macos_workflow_dir() -> Result<PathBuf> {
+fn macos_workflow_dir(entry: &MenuEntry) -> Result<PathBuf> {
     Ok(home_dir()?
         .join("Library/Services")
-        .join(format!("{}.workflow", MENU_LABEL)))
+        .join(format!("{}.workflow", entry.label())))
 }
 
-fn install_macos() -> Result<()> {
+fn install_macos(entry: &MenuEntry) -> Result<()> {
     let (exe, exe_str) = exe_path()?;
     warn_if_build_dir(&exe_str);
 
-    let wf_dir = macos_workflow_dir()?;
+    let wf_dir = macos_workflow_dir(entry)?;
     let contents_dir = wf_dir.join("Contents");
 
     if wf_dir.exists() {
@@ -133,26 +194,32 @@ fn install_macos() -> Result<()> {
     fs::create_dir_all(&contents_dir)
         .with_context(|| format!("Failed to create {}", contents_dir.display()))?;
 
-    fs::write(contents_dir.join("document.wflow"), macos_document_wflow(&exe_str))
-        .context("Failed to write document.wflow")?;
-    fs::write(contents_dir.join("Info.plist"), macos_info_plist())
+    fs::write(
+        contents_dir.join("document.wflow"),
+        macos_document_wflow(&exe_str, entry),
+    )
+    .context("Failed to write document.wflow")?;
+    fs::write(contents_dir.join("Info.plist"), macos_info_plist(entry))
         .context("Failed to write Info.plist")?;
 
-    println!("Installed macOS Quick Action: \"{}\"", MENU_LABEL);
+    println!("Installed macOS Quick Action: \"{}\"", entry.label());
     println!("  Location: {}", wf_dir.display());
     println!("  Binary:   {}", exe.display());
     println!();
     println!("Next steps:");
     println!("  1. Open System Settings -> Privacy & Security -> Extensions -> Finder");
-    println!("  2. Enable \"{}\"", MENU_LABEL);
+    println!("  2. Enable \"{}\"", entry.label());
     println!("  3. If it doesn't appear, run: killall Finder");
     println!();
-    println!("Usage: Right-click a folder in Finder -> Quick Actions -> \"{}\"", MENU_LABEL);
+    println!(
+        "Usage: Right-click a folder in Finder -> Quick Actions -> \"{}\"",
+        entry.label()
+    );
     Ok(())
 }
 
-fn uninstall_macos() -> Result<()> {
-    let wf_dir = macos_workflow_dir()?;
+fn uninstall_macos(entry: &MenuEntry) -> Result<()> {
+    let wf_dir = macos_workflow_dir(entry)?;
     if wf_dir.exists() {
         fs::remove_dir_all(&wf_dir)?;
         println!("Removed: {}", wf_dir.display());
@@ -162,11 +229,12 @@ fn uninstall_macos() -> Result<()> {
     Ok(())
 }
 
-fn macos_document_wflow(binary_path: &str) -> String {
+fn macos_document_wflow(binary_path: &str, entry: &MenuEntry) -> String {
     let shell_safe = shell_escape(binary_path);
     let xml_safe_script = xml_escape(&format!(
-        "for f in \"$@\"; do\n  if [ -d \"$f\" ]; then\n    {} --execute \"$f\"\n  fi\ndone",
-        shell_safe
+        "for f in \"$@\"; do\n  if [ -d \"$f\" ]; then\n    {} {} \"$f\"\n  fi\ndone",
+        shell_safe,
+        entry.args()
     ));
 
     format!(
@@ -402,8 +470,9 @@ fn macos_document_wflow(binary_path: &str) -> String {
     )
 }
 
-fn macos_info_plist() -> &'static str {
-    r#"<?xml version="1.0" encoding="UTF-8"?>
+fn macos_info_plist(entry: &MenuEntry) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
 <!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
 <plist version="1.0">
 <dict>
@@ -419,7 +488,7 @@ fn macos_info_plist() -> &'static str {
 			<key>NSMenuItem</key>
 			<dict>
 				<key>default</key>
-				<string>Sort Music by Tags</string>
+				<string>{label}</string>
 			</dict>
 			<key>NSMessage</key>
 			<string>runWorkflowAsService</string>
@@ -435,38 +504,64 @@ fn macos_info_plist() -> &'static str {
 		</dict>
 	</array>
 </dict>
-</plist>"#
+</plist>"#,
+        label = xml_escape(&entry.label())
+    )
 }
 
 // ===========================================================================
 // Linux -- Nautilus script + Nemo action + Dolphin service menu
 // ===========================================================================
 
-fn linux_paths() -> Result<(PathBuf, PathBuf, PathBuf)> {
+fn linux_paths(entry: &MenuEntry) -> Result<(PathBuf, PathBuf, PathBuf)> {
     let data = home_dir()?.join(".local/share");
     Ok((
-        data.join("nautilus/scripts").join(MENU_LABEL),
-        data.join("nemo/actions/tagmv.nemo_action"),
-        data.join("kio/servicemenus/tagmv.desktop"),
+        data.join("nautilus/scripts").join(entry.label()),
+        data.join("nemo/actions")
+            .join(format!("{}.nemo_action", entry.id())),
+        data.join("kio/servicemenus")
+            .join(format!("{}.desktop", entry.id())),
     ))
 }
 
-fn install_linux() -> Result<()> {
+fn nautilus_script(exe_str: &str, entry: &MenuEntry) -> String {
+    format!(
+        "#!/bin/bash\nIFS=$'\\n'\nfor f in $NAUTILUS_SCRIPT_SELECTED_FILE_PATHS; do\n  [ -d \"$f\" ] && {} {} \"$f\"\ndone\n",
+        shell_escape(exe_str),
+        entry.args()
+    )
+}
+
+fn nemo_action(exe_str: &str, entry: &MenuEntry) -> String {
+    format!(
+        "[Nemo Action]\nName={}\nComment=Organize music files by audio tags\nExec={} {} %F\nIcon-Name=audio-x-generic\nSelection=Any\nExtensions=dir;\n",
+        entry.label(),
+        exe_str,
+        entry.args()
+    )
+}
+
+fn dolphin_desktop(exe_str: &str, entry: &MenuEntry) -> String {
+    format!(
+        "[Desktop Entry]\nType=Service\nMimeType=inode/directory;\nActions={id}\n\n[Desktop Action {id}]\nName={}\nExec={} {} %f\nIcon=audio-x-generic\n",
+        entry.label(),
+        exe_str,
+        entry.args(),
+        id = entry.id()
+    )
+}
+
+fn install_linux(entry: &MenuEntry) -> Result<()> {
     let (exe, exe_str) = exe_path()?;
     warn_if_build_dir(&exe_str);
 
-    let escaped = shell_escape(&exe_str);
-    let (nautilus_path, nemo_path, dolphin_path) = linux_paths()?;
+    let (nautilus_path, nemo_path, dolphin_path) = linux_paths(entry)?;
 
     // --- Nautilus (GNOME Files) ---
     if let Some(parent) = nautilus_path.parent() {
         fs::create_dir_all(parent)?;
     }
-    let nautilus_script = format!(
-        "#!/bin/bash\nIFS=$'\\n'\nfor f in $NAUTILUS_SCRIPT_SELECTED_FILE_PATHS; do\n  [ -d \"$f\" ] && {} --execute \"$f\"\ndone\n",
-        escaped
-    );
-    fs::write(&nautilus_path, &nautilus_script)?;
+    fs::write(&nautilus_path, nautilus_script(&exe_str, entry))?;
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
@@ -478,34 +573,29 @@ fn install_linux() -> Result<()> {
     if let Some(parent) = nemo_path.parent() {
         fs::create_dir_all(parent)?;
     }
-    let nemo_action = format!(
-        "[Nemo Action]\nName={}\nComment=Organize music files by audio tags\nExec={} --execute %F\nIcon-Name=audio-x-generic\nSelection=Any\nExtensions=dir;\n",
-        MENU_LABEL, exe_str
-    );
-    fs::write(&nemo_path, &nemo_action)?;
+    fs::write(&nemo_path, nemo_action(&exe_str, entry))?;
     println!("  Nemo:     {}", nemo_path.display());
 
     // --- Dolphin (KDE) ---
     if let Some(parent) = dolphin_path.parent() {
         fs::create_dir_all(parent)?;
     }
-    let dolphin_desktop = format!(
-        "[Desktop Entry]\nType=Service\nMimeType=inode/directory;\nActions=tagmv\n\n[Desktop Action tagmv]\nName={}\nExec={} --execute %f\nIcon=audio-x-generic\n",
-        MENU_LABEL, exe_str
-    );
-    fs::write(&dolphin_path, &dolphin_desktop)?;
+    fs::write(&dolphin_path, dolphin_desktop(&exe_str, entry))?;
     println!("  Dolphin:  {}", dolphin_path.display());
 
     println!();
     println!("Installed context menu for Nautilus, Nemo, and Dolphin.");
     println!("  Binary: {}", exe.display());
     println!();
-    println!("Usage: Right-click a folder -> Scripts/Actions -> \"{}\"", MENU_LABEL);
+    println!(
+        "Usage: Right-click a folder -> Scripts/Actions -> \"{}\"",
+        entry.label()
+    );
     Ok(())
 }
 
-fn uninstall_linux() -> Result<()> {
-    let (nautilus_path, nemo_path, dolphin_path) = linux_paths()?;
+fn uninstall_linux(entry: &MenuEntry) -> Result<()> {
+    let (nautilus_path, nemo_path, dolphin_path) = linux_paths(entry)?;
     let mut removed = 0;
     for path in [&nautilus_path, &nemo_path, &dolphin_path] {
         if path.exists() {
@@ -524,62 +614,51 @@ fn uninstall_linux() -> Result<()> {
 // Windows -- Explorer context menu via registry
 // ===========================================================================
 
-fn install_windows() -> Result<()> {
+/// Context menu keys: right-click on a folder, and on the folder background
+/// (inside a folder).
+fn windows_keys(entry: &MenuEntry) -> [String; 2] {
+    [
+        format!(r"HKCU\Software\Classes\Directory\shell\{}", entry.id()),
+        format!(
+            r"HKCU\Software\Classes\Directory\Background\shell\{}",
+            entry.id()
+        ),
+    ]
+}
+
+fn windows_command(exe_str: &str, entry: &MenuEntry) -> String {
+    format!("\"{}\" {} \"%V\"", exe_str, entry.args())
+}
+
+fn install_windows(entry: &MenuEntry) -> Result<()> {
     let (exe, exe_str) = exe_path()?;
     warn_if_build_dir(&exe_str);
 
-    let command_value = format!("\"{}\" --execute \"%V\"", exe_str);
-
-    // Right-click on a folder
-    run_reg(&[
-        "add",
-        r"HKCU\Software\Classes\Directory\shell\tagmv",
-        "/ve",
-        "/d",
-        MENU_LABEL,
-        "/f",
-    ])?;
-    run_reg(&[
-        "add",
-        r"HKCU\Software\Classes\Directory\shell\tagmv\command",
-        "/ve",
-        "/d",
-        &command_value,
-        "/f",
-    ])?;
-
-    // Right-click on folder background (inside a folder)
-    run_reg(&[
-        "add",
-        r"HKCU\Software\Classes\Directory\Background\shell\tagmv",
-        "/ve",
-        "/d",
-        MENU_LABEL,
-        "/f",
-    ])?;
-    run_reg(&[
-        "add",
-        r"HKCU\Software\Classes\Directory\Background\shell\tagmv\command",
-        "/ve",
-        "/d",
-        &command_value,
-        "/f",
-    ])?;
-
-    println!("Installed Windows Explorer context menu: \"{}\"", MENU_LABEL);
+    let label = entry.label();
+    let command_value = windows_command(&exe_str, entry);
+
+    for key in windows_keys(entry) {
+        run_reg(&["add", &key, "/ve", "/d", &label, "/f"])?;
+        run_reg(&[
+            "add",
+            &format!(r"{}\command", key),
+            "/ve",
+            "/d",
+            &command_value,
+            "/f",
+        ])?;
+    }
+
+    println!("Installed Windows Explorer context menu: \"{}\"", label);
     println!("  Binary: {}", exe.display());
     println!();
-    println!("Usage: Right-click a folder in Explorer -> \"{}\"", MENU_LABEL);
+    println!("Usage: Right-click a folder in Explorer -> \"{}\"", label);
     Ok(())
 }
 
-fn uninstall_windows() -> Result<()> {
-    let keys = [
-        r"HKCU\Software\Classes\Directory\shell\tagmv",
-        r"HKCU\Software\Classes\Directory\Background\shell\tagmv",
-    ];
+fn uninstall_windows(entry: &MenuEntry) -> Result<()> {
     let mut removed = 0;
-    for key in &keys {
+    for key in &windows_keys(entry) {
         // /f = force (no prompt), failure is ok if key doesn't exist
         if run_reg(&["delete", key, "/f"]).is_ok() {
             println!("Removed: {}", key);
@@ -651,13 +730,13 @@ mod tests {
         }
     }
 
+    fn default_entry() -> MenuEntry {
+        MenuEntry::new(None).unwrap()
+    }
+
     #[test]
     fn linux_nautilus_script_content() {
-        let escaped = shell_escape("/usr/local/bin/tagmv");
-        let script = format!(
-            "#!/bin/bash\nIFS=$'\\n'\nfor f in $NAUTILUS_SCRIPT_SELECTED_FILE_PATHS; do\n  [ -d \"$f\" ] && {} --execute \"$f\"\ndone\n",
-            escaped
-        );
+        let script = nautilus_script("/usr/local/bin/tagmv", &default_entry());
         assert!(script.starts_with("#!/bin/bash"));
         assert!(script.contains("'/usr/local/bin/tagmv'"));
         assert!(script.contains("NAUTILUS_SCRIPT_SELECTED_FILE_PATHS"));
@@ -665,10 +744,7 @@ mod tests {
 
     #[test]
     fn linux_nemo_action_content() {
-        let action = format!(
-            "[Nemo Action]\nName={}\nExec={} --execute %F\n",
-            MENU_LABEL, "/usr/local/bin/tagmv"
-        );
+        let action = nemo_action("/usr/local/bin/tagmv", &default_entry());
         assert!(action.contains("[Nemo Action]"));
         assert!(action.contains("Sort Music by Tags"));
         assert!(action.contains("--execute %F"));
@@ -676,10 +752,7 @@ mod tests {
 
     #[test]
     fn linux_dolphin_desktop_content() {
-        let desktop = format!(
-            "[Desktop Entry]\nType=Service\nMimeType=inode/directory;\nActions=tagmv\n\n[Desktop Action tagmv]\nName={}\nExec={} --execute %f\n",
-            MENU_LABEL, "/usr/local/bin/tagmv"
-        );
+        let desktop = dolphin_desktop("/usr/local/bin/tagmv", &default_entry());
         assert!(desktop.contains("Type=Service"));
         assert!(desktop.contains("inode/directory"));
         assert!(desktop.contains("[Desktop Action tagmv]"));
@@ -688,7 +761,27 @@ mod tests {
     #[test]
     fn windows_command_value_format() {
         let exe = r"C:\Users\chris\bin\tagmv.exe";
-        let cmd = format!("\"{}\" --execute \"%V\"", exe);
+        let cmd = windows_command(exe, &default_entry());
         assert_eq!(cmd, r#""C:\Users\chris\bin\tagmv.exe" --execute "%V""#);
     }
+
+    #[test]
+    fn profile_entries_are_separate() {
+        let entry = MenuEntry::new(Some("usb-stick")).unwrap();
+        assert_eq!(entry.label(), "Sort Music by Tags (usb-stick)");
+
+        let script = nautilus_script("/usr/local/bin/tagmv", &entry);
+        assert!(script.contains("'/usr/local/bin/tagmv' --profile usb-stick --execute \"$f\""));
+        let desktop = dolphin_desktop("/usr/local/bin/tagmv", &entry);
+        assert!(desktop.contains("[Desktop Action tagmv-usb-stick]"));
+        assert!(windows_keys(&entry)[0].ends_with(r"\shell\tagmv-usb-stick"));
+    }
+
+    #[test]
+    fn profile_names_are_validated() {
+        assert!(MenuEntry::new(Some("library_2")).is_ok());
+        assert!(MenuEntry::new(Some("")).is_err());
+        assert!(MenuEntry::new(Some("a b")).is_err());
+        assert!(MenuEntry::new(Some("x;rm")).is_err());
+    }
 }
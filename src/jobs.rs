@@ -0,0 +1,70 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Worker threads used when no `--jobs` are given: one per CPU.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// `f` applied to every item on up to `jobs` threads. The results are in
+/// the order of `items`, however the work was scheduled.
+pub fn map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = jobs.min(items.len());
+    if jobs <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Workers take the next index until all items are taken, so a few slow
+    // files do not hold up a whole share of the work
+    let next = AtomicUsize::new(0);
+    let work = || {
+        let mut done = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(item) = items.get(i) else { break };
+            done.push((i, f(item)));
+        }
+        done
+    };
+
+    let mut results: Vec<Option<R>> = Vec::new();
+    results.resize_with(items.len(), || None);
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs).map(|_| scope.spawn(work)).collect();
+        for worker in workers {
+            let done = worker
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+            for (i, result) in done {
+                results[i] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_order_of_the_items() {
+        let items: Vec<u64> = (0..500).collect();
+        let slow_start = |n: &u64| {
+            if *n < 4 {
+                thread::sleep(std::time::Duration::from_millis(20));
+            }
+            n * 2
+        };
+        let expected: Vec<u64> = items.iter().map(|n| n * 2).collect();
+        assert_eq!(map(&items, 4, slow_start), expected);
+        assert_eq!(map(&items, 1, slow_start), expected);
+        assert_eq!(map(&items[..0], 4, slow_start), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,568 @@
+use crate::sorting::{execute_move, PlannedMove, TransferMode};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Journals live under the target directory so they travel with the library.
+const JOURNAL_DIR: &str = ".tagmv/journal";
+/// Copies of files taken before their tags were written, one directory per
+/// journal.
+const BACKUP_DIR: &str = ".tagmv/backup";
+const JOURNAL_EXT: &str = "jsonl";
+const UNDONE_EXT: &str = "undone";
+
+/// One line of a journal file. Entries are appended in execution order and
+/// undone in reverse.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JournalEntry {
+    /// A directory created to hold a destination.
+    Mkdir { path: PathBuf },
+    /// A source directory removed once it was empty (`--prune-empty`), with
+    /// backups of the junk files deleted along with it.
+    Rmdir {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        junk: Vec<BackedUp>,
+    },
+    /// A completed transfer, with the destination's size and mtime right
+    /// after the transfer so later edits can be detected. `replaced` holds a
+    /// copy of the file it overwrote (`--on-conflict overwrite`), which undo
+    /// puts back.
+    Transfer {
+        source: PathBuf,
+        dest: PathBuf,
+        mode: TransferMode,
+        timestamp: u64,
+        size: u64,
+        mtime: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        replaced: Option<PathBuf>,
+    },
+    /// Tags written into `path` (`--write-tags`). `backup` holds the file as
+    /// it was before; size and mtime are taken right after the write.
+    Tags {
+        path: PathBuf,
+        backup: PathBuf,
+        size: u64,
+        mtime: u64,
+    },
+}
+
+/// A file that was deleted, and its [`Journal::backup`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackedUp {
+    pub path: PathBuf,
+    pub backup: PathBuf,
+}
+
+/// An open journal for the current run.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    backup_dir: PathBuf,
+    backups: usize,
+}
+
+impl Journal {
+    /// Create a new journal file under `target`.
+    pub fn create(target: &Path) -> Result<Journal> {
+        let dir = target.join(JOURNAL_DIR);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create journal directory: {}", dir.display()))?;
+
+        let stem = format!("{}-{}", now(), std::process::id());
+        let path = dir.join(format!("{}.{}", stem, JOURNAL_EXT));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create journal: {}", path.display()))?;
+
+        Ok(Journal {
+            path,
+            file,
+            backup_dir: target.join(BACKUP_DIR).join(stem),
+            backups: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record_mkdirs(&mut self, dirs: &[PathBuf]) -> Result<()> {
+        for dir in dirs {
+            if dir.is_dir() {
+                self.append(&JournalEntry::Mkdir { path: dir.clone() })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a removed directory and the junk files deleted with it.
+    pub fn record_rmdir(&mut self, dir: &Path, junk: Vec<BackedUp>) -> Result<()> {
+        self.append(&JournalEntry::Rmdir {
+            path: dir.to_path_buf(),
+            junk,
+        })
+    }
+
+    /// Record a transfer. `replaced` is the [`Journal::backup`] of the file
+    /// the transfer overwrote, if any.
+    pub fn record_transfer(
+        &mut self,
+        planned: &PlannedMove,
+        applied: TransferMode,
+        replaced: Option<&Path>,
+    ) -> Result<()> {
+        let (size, mtime) = file_stamp(&planned.dest)?;
+        self.append(&JournalEntry::Transfer {
+            source: planned.source.clone(),
+            dest: planned.dest.clone(),
+            mode: applied,
+            timestamp: now(),
+            size,
+            mtime,
+            replaced: replaced.map(Path::to_path_buf),
+        })
+    }
+
+    /// Copy `path` aside so that it can be restored, keeping its
+    /// modification time. Returns the copy, for [`Journal::record_tags`] or
+    /// [`Journal::record_transfer`].
+    pub fn backup(&mut self, path: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(&self.backup_dir).with_context(|| {
+            format!(
+                "Failed to create backup directory: {}",
+                self.backup_dir.display()
+            )
+        })?;
+        self.backups += 1;
+        let mut name = self.backups.to_string();
+        if let Some(ext) = path.extension() {
+            name = format!("{}.{}", name, ext.to_string_lossy());
+        }
+        let backup = self.backup_dir.join(name);
+        copy_with_mtime(path, &backup)
+            .with_context(|| format!("Failed to back up {}", path.display()))?;
+        Ok(backup)
+    }
+
+    pub fn record_tags(&mut self, path: &Path, backup: &Path) -> Result<()> {
+        let (size, mtime) = file_stamp(path)?;
+        self.append(&JournalEntry::Tags {
+            path: path.to_path_buf(),
+            backup: backup.to_path_buf(),
+            size,
+            mtime,
+        })
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let line = serde_json::to_string(entry)?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .with_context(|| format!("Failed to write journal: {}", self.path.display()))
+    }
+}
+
+/// Directories that `create_dir_all(path)` would create, outermost first.
+pub fn missing_dirs(path: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = path
+        .ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    dirs.reverse();
+    dirs
+}
+
+/// Journals under `target` that have not been undone yet, oldest first.
+pub fn list_journals(target: &Path) -> Result<Vec<PathBuf>> {
+    let dir = target.join(JOURNAL_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut journals: Vec<PathBuf> = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read journal directory: {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some(JOURNAL_EXT))
+        .collect();
+
+    journals.sort_by_key(|p| journal_sort_key(p));
+    Ok(journals)
+}
+
+fn journal_sort_key(path: &Path) -> (u64, String) {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_string();
+    let secs = name
+        .split('-')
+        .next()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    (secs, name)
+}
+
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open journal: {}", path.display()))?;
+
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("{}:{}: invalid journal entry", path.display(), i + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// A single step of an undo run.
+pub struct UndoStep {
+    pub description: String,
+    pub result: Result<()>,
+}
+
+/// Reverse every entry of a journal, newest first. Transfers whose
+/// destination changed since they were recorded are refused unless `force`
+/// is set. On full success the journal is marked as undone; otherwise only
+/// the failed entries are kept in it, so that undo can be run again.
+pub fn undo(journal_path: &Path, force: bool) -> Result<Vec<UndoStep>> {
+    let entries = read_journal(journal_path)?;
+    let mut steps = Vec::new();
+
+    for entry in entries.iter().rev() {
+        let step = match entry {
+            JournalEntry::Transfer {
+                source,
+                dest,
+                mode,
+                size,
+                mtime,
+                replaced,
+                ..
+            } => UndoStep {
+                description: match replaced {
+                    Some(_) => format!(
+                        "{} -> {}, restoring the file it replaced",
+                        dest.display(),
+                        source.display()
+                    ),
+                    None => format!("{} -> {}", dest.display(), source.display()),
+                },
+                result: undo_transfer(
+                    source,
+                    dest,
+                    *mode,
+                    replaced.as_deref(),
+                    (*size, *mtime),
+                    force,
+                ),
+            },
+            JournalEntry::Mkdir { path } => UndoStep {
+                description: format!("rmdir {}", path.display()),
+                result: undo_mkdir(path),
+            },
+            JournalEntry::Tags {
+                path,
+                backup,
+                size,
+                mtime,
+            } => UndoStep {
+                description: format!("restore tags of {}", path.display()),
+                result: undo_tags(path, backup, (*size, *mtime), force),
+            },
+            JournalEntry::Rmdir { path, junk } => UndoStep {
+                description: format!("mkdir {}", path.display()),
+                result: undo_rmdir(path, junk),
+            },
+        };
+        steps.push(step);
+    }
+
+    let failed: Vec<&JournalEntry> = entries
+        .iter()
+        .zip(steps.iter().rev())
+        .filter(|(_, step)| step.result.is_err())
+        .map(|(entry, _)| entry)
+        .collect();
+    if !failed.is_empty() {
+        if failed.len() < entries.len() {
+            rewrite_journal(journal_path, &failed)?;
+        }
+    } else {
+        let done = journal_path.with_extension(UNDONE_EXT);
+        fs::rename(journal_path, &done)
+            .with_context(|| format!("Failed to mark journal as undone: {}", done.display()))?;
+        if let (Some(root), Some(stem)) = (journal_root(journal_path), journal_path.file_stem()) {
+            let _ = fs::remove_dir(root.join(BACKUP_DIR).join(stem));
+        }
+    }
+
+    Ok(steps)
+}
+
+/// Replace the entries of a journal (written aside first, so a crash keeps
+/// the old journal).
+fn rewrite_journal(path: &Path, entries: &[&JournalEntry]) -> Result<()> {
+    let mut text = String::new();
+    for entry in entries {
+        text += &serde_json::to_string(entry)?;
+        text.push('\n');
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text)
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("Failed to update journal: {}", path.display()))
+}
+
+/// Reverse a transfer, then put the file it replaced back at `dest`.
+fn undo_transfer(
+    source: &Path,
+    dest: &Path,
+    mode: TransferMode,
+    replaced: Option<&Path>,
+    recorded: (u64, u64),
+    force: bool,
+) -> Result<()> {
+    if dest.symlink_metadata().is_err() {
+        bail!("Destination no longer exists");
+    }
+    if !force && file_stamp(dest)? != recorded {
+        bail!("Destination changed since it was recorded (use --force to undo anyway)");
+    }
+
+    match mode {
+        TransferMode::Move => {
+            if source.exists() {
+                bail!("Original location is occupied: {}", source.display());
+            }
+            let back = PlannedMove {
+                source: dest.to_path_buf(),
+                dest: source.to_path_buf(),
+                folder_name: String::new(),
+                file_name: String::new(),
+                ..Default::default()
+            };
+            execute_move(&back)?;
+        }
+        TransferMode::Copy
+        | TransferMode::Hardlink
+        | TransferMode::Symlink
+        | TransferMode::Reflink => {
+            // The destination is only a copy or link; refuse to delete it if
+            // the original is gone, since it may now be the only copy
+            if !source.exists() {
+                bail!("Original is missing, keeping {}", dest.display());
+            }
+            fs::remove_file(dest)
+                .with_context(|| format!("Failed to remove {}", dest.display()))?;
+        }
+    }
+    if let Some(backup) = replaced {
+        fs::rename(backup, dest)
+            .with_context(|| format!("Failed to restore the replaced {}", dest.display()))?;
+    }
+    Ok(())
+}
+
+/// Put the backup back over `path`. The file gets its old modification
+/// time too, so that undoing the transfer before it finds it unchanged.
+fn undo_tags(path: &Path, backup: &Path, recorded: (u64, u64), force: bool) -> Result<()> {
+    if !path.exists() {
+        bail!("File no longer exists");
+    }
+    if !force && file_stamp(path)? != recorded {
+        bail!("File changed since its tags were written (use --force to undo anyway)");
+    }
+    copy_with_mtime(backup, path)
+        .with_context(|| format!("Failed to restore {}", path.display()))?;
+    fs::remove_file(backup)
+        .with_context(|| format!("Failed to remove backup: {}", backup.display()))
+}
+
+/// Copy `from` over `to` (writing into the existing file, so hard links stay
+/// linked) and give it the modification time of `from`.
+fn copy_with_mtime(from: &Path, to: &Path) -> Result<()> {
+    let modified = fs::metadata(from)?.modified()?;
+    fs::copy(from, to)?;
+    File::options()
+        .write(true)
+        .open(to)?
+        .set_modified(modified)?;
+    Ok(())
+}
+
+/// The target directory a journal at `<target>/.tagmv/journal/` belongs to.
+fn journal_root(journal_path: &Path) -> Option<&Path> {
+    let mut root = journal_path.parent()?;
+    for _ in Path::new(JOURNAL_DIR).components() {
+        root = root.parent()?;
+    }
+    Some(root)
+}
+
+/// Recreate a pruned directory and put its junk files back. Files that are
+/// back already (from an earlier, partial undo) are left alone.
+fn undo_rmdir(path: &Path, junk: &[BackedUp]) -> Result<()> {
+    fs::create_dir_all(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    for file in junk.iter().filter(|f| !f.path.exists()) {
+        fs::rename(&file.backup, &file.path)
+            .with_context(|| format!("Failed to restore {}", file.path.display()))?;
+    }
+    Ok(())
+}
+
+fn undo_mkdir(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    fs::remove_dir(path).with_context(|| format!("Directory not empty: {}", path.display()))
+}
+
+/// Size and modification time (seconds) of `path`, not following symlinks.
+pub fn file_stamp(path: &Path) -> Result<(u64, u64)> {
+    let meta = fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    Ok((meta.len(), mtime))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn run_with_journal(tmp: &Path, mode: TransferMode) -> PathBuf {
+        let source = tmp.join("in/song.mp3");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "audio").unwrap();
+
+        let planned = PlannedMove {
+            source,
+            dest: tmp.join("lib/Artist - Album/01 - Song.mp3"),
+            folder_name: "Artist - Album".to_string(),
+            file_name: "01 - Song.mp3".to_string(),
+            mode,
+            ..Default::default()
+        };
+
+        let mut journal = Journal::create(tmp).unwrap();
+        let created = missing_dirs(planned.dest.parent().unwrap());
+        let applied = execute_move(&planned).unwrap();
+        journal.record_mkdirs(&created).unwrap();
+        journal.record_transfer(&planned, applied, None).unwrap();
+        journal.path().to_path_buf()
+    }
+
+    #[test]
+    fn missing_dirs_outermost_first() {
+        let tmp = temp_dir("missing_dirs");
+
+        let dirs = missing_dirs(&tmp.join("a/b"));
+        assert_eq!(dirs, vec![tmp.join("a"), tmp.join("a/b")]);
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn undo_move_restores_source_and_removes_dirs() {
+        let tmp = temp_dir("undo_move");
+
+        let journal = run_with_journal(&tmp, TransferMode::Move);
+        assert!(!tmp.join("in/song.mp3").exists());
+        assert_eq!(list_journals(&tmp).unwrap(), vec![journal.clone()]);
+
+        let steps = undo(&journal, false).unwrap();
+        assert!(steps.iter().all(|s| s.result.is_ok()));
+        assert_eq!(
+            fs::read_to_string(tmp.join("in/song.mp3")).unwrap(),
+            "audio"
+        );
+        assert!(!tmp.join("lib").exists());
+        assert!(list_journals(&tmp).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn undo_copy_removes_only_the_copy() {
+        let tmp = temp_dir("undo_copy");
+
+        let journal = run_with_journal(&tmp, TransferMode::Copy);
+        let steps = undo(&journal, false).unwrap();
+        assert!(steps.iter().all(|s| s.result.is_ok()));
+        assert!(tmp.join("in/song.mp3").exists());
+        assert!(!tmp.join("lib").exists());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn undo_refuses_changed_destination() {
+        let tmp = temp_dir("undo_changed");
+
+        let journal = run_with_journal(&tmp, TransferMode::Move);
+        let dest = tmp.join("lib/Artist - Album/01 - Song.mp3");
+        fs::write(&dest, "re-encoded audio").unwrap();
+
+        let steps = undo(&journal, false).unwrap();
+        assert!(steps[0].result.is_err());
+        assert!(dest.exists());
+        // Journal stays active so the user can retry with --force
+        assert_eq!(list_journals(&tmp).unwrap(), vec![journal]);
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn undo_resumes_after_a_failed_step() {
+        let tmp = temp_dir("undo_resume");
+
+        let journal = run_with_journal(&tmp, TransferMode::Move);
+        // Something the user added keeps the album folder from going away
+        let added = tmp.join("lib/Artist - Album/cover.jpg");
+        fs::write(&added, "image").unwrap();
+
+        let steps = undo(&journal, false).unwrap();
+        assert!(steps[0].result.is_ok());
+        assert!(steps[1..].iter().all(|s| s.result.is_err()));
+        assert!(tmp.join("in/song.mp3").exists());
+        // Only the directories are left to undo
+        let left = read_journal(&journal).unwrap();
+        assert_eq!(left.len(), 2);
+        assert!(left.iter().all(|e| matches!(e, JournalEntry::Mkdir { .. })));
+
+        fs::remove_file(&added).unwrap();
+        let steps = undo(&journal, false).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert!(steps.iter().all(|s| s.result.is_ok()));
+        assert!(!tmp.join("lib").exists());
+        assert!(list_journals(&tmp).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
@@ -0,0 +1,32 @@
+//! Organize music files into folders by their audio tags.
+//!
+//! The `tagmv` binary is a thin wrapper around this crate: a [`Planner`]
+//! scans a directory and resolves every file's destination into a [`Plan`],
+//! and an [`Executor`] carries the plan out, journaling each transfer so it
+//! can be undone.
+
+pub mod albums;
+pub mod cache;
+pub mod companions;
+pub mod config;
+pub mod dedupe;
+pub mod executor;
+pub mod infer;
+pub mod jobs;
+pub mod journal;
+pub mod output;
+pub mod plan;
+pub mod planner;
+pub mod scan;
+pub mod sorting;
+pub mod tags;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+pub mod validate;
+
+pub use executor::{ExecResult, ExecStatus, Execution, Executor, Progress};
+pub use planner::{Plan, Planner};
+pub use sorting::{ConflictPolicy, PlannedMove, TransferMode};
+pub use tags::TrackMetadata;
+pub use template::{DiscLayout, Template};
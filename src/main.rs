@@ -1,260 +1,1123 @@
 mod install;
-mod sorting;
-mod tags;
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
 use colored::Colorize;
-use sorting::{
-    compute_destination, compute_unsorted_destination, execute_move, resolve_conflicts,
-    PlannedMove,
-};
 use std::collections::BTreeMap;
-use std::ffi::OsStr;
-use std::path::{Path, PathBuf};
-use tags::read_tags;
-use walkdir::WalkDir;
-
-const AUDIO_EXTENSIONS: &[&str] = &["mp3", "m4a", "flac", "ogg", "wma", "aac", "wav"];
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use tagmv::cache;
+use tagmv::config::{Config, Settings};
+use tagmv::dedupe::DedupeAction;
+use tagmv::infer::Pattern;
+use tagmv::output::{self, OutputFormat, Summary};
+use tagmv::plan::PlanFile;
+use tagmv::sorting::{AskFn, Conflict, ConflictCase, Layout};
+use tagmv::tags::{read_quality, AudioQuality};
+use tagmv::validate::CorruptAction;
+use tagmv::{
+    journal, ConflictPolicy, DiscLayout, ExecResult, Executor, Plan, PlannedMove, Planner,
+    Progress, Template, TransferMode,
+};
 
 #[derive(Parser)]
 #[command(name = "tagmv", version, about = "Organize music files by audio tags")]
 struct Cli {
-    /// Directory to sort (defaults to current directory)
-    path: Option<PathBuf>,
+    #[command(flatten)]
+    plan: PlanArgs,
 
     /// Actually move files (default is dry-run preview)
     #[arg(long)]
     execute: bool,
 
+    /// After executing, remove source directories that are left empty (or
+    /// only hold junk like Thumbs.db and .DS_Store)
+    #[arg(long)]
+    prune_empty: bool,
+
+    /// Review the plan folder by folder (accept, skip, change the
+    /// destination or the artist and album), then execute what was accepted
+    #[arg(long)]
+    interactive: bool,
+
+    /// Output format for the plan and execution results
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+/// Options that decide where files go, shared by the default command and
+/// `tagmv plan`.
+#[derive(Args)]
+struct PlanArgs {
+    /// Directory to sort (defaults to current directory)
+    path: Option<PathBuf>,
+
     /// Scan subdirectories
     #[arg(short, long)]
     recursive: bool,
 
-    #[command(subcommand)]
-    command: Option<Commands>,
+    /// Library root to file sorted music into (defaults to the scanned directory)
+    #[arg(long, value_name = "DIR")]
+    dest: Option<PathBuf>,
+
+    /// How files are transferred to their destination [default: move]
+    #[arg(long, value_enum)]
+    mode: Option<TransferMode>,
+
+    /// Destination layout, e.g. "{artist}/{album}/[{track:02} ]{title}.{ext}"
+    /// (default depends on --disc-layout)
+    #[arg(long)]
+    template: Option<Template>,
+
+    /// Layout for compilations (compilation flag, "Various Artists", or many artists per album)
+    #[arg(long)]
+    compilation_template: Option<Template>,
+
+    /// How the default templates lay out albums with more than one disc [default: prefix]
+    #[arg(long, value_enum)]
+    disc_layout: Option<DiscLayout>,
+
+    /// What to do when a destination is already taken [default: rename]
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_conflict: Option<ConflictPolicy>,
+
+    /// Find files whose audio (ignoring tags) is already in the library or
+    /// earlier in the plan: report them, or quarantine them in the duplicates folder
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        num_args = 0..=1,
+        default_missing_value = "report"
+    )]
+    dedupe: Option<DedupeAction>,
+
+    /// Sort unsorted files into subfolders by why they could not be sorted,
+    /// e.g. _Unsorted/no-tags
+    #[arg(long)]
+    unsorted_by_reason: bool,
+
+    /// What to do with files that cannot be read (or that --validate finds
+    /// damaged) [default: quarantine, into _Corrupt]
+    #[arg(long, value_enum, value_name = "ACTION")]
+    on_corrupt: Option<CorruptAction>,
+
+    /// Walk the audio stream of every file to find truncated downloads and
+    /// other damage that reading tags does not notice
+    #[arg(long)]
+    validate: bool,
+
+    /// Leave cover art, cue sheets, logs and lyrics where they are
+    #[arg(long)]
+    no_companions: bool,
+
+    /// Detect audio files by content: also pick up files with a wrong or
+    /// missing extension, and give them the right one
+    #[arg(long)]
+    sniff: bool,
+
+    /// Read the tags of untagged files from their folder and file names
+    #[arg(long)]
+    infer: bool,
+
+    /// Pattern for --infer, e.g. "{artist} - {album}/{track} {title}"
+    /// (repeatable, tried in order; implies --infer)
+    #[arg(long, value_name = "PATTERN")]
+    infer_pattern: Vec<Pattern>,
+
+    /// Write the tags files are filed by into them: inferred tags, and album
+    /// artists and disc totals resolved for the whole album (not with
+    /// --mode hardlink or symlink)
+    #[arg(long)]
+    write_tags: bool,
+
+    /// Read every file again instead of using (and updating) the tag and
+    /// hash caches
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Number of threads listing folders and reading tags [default: one per CPU]
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+
+    /// Config profile to use (a [profile.<name>] section of the config files)
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Install file manager context menu integration
-    Install,
+    Install {
+        /// Add a separate menu entry that runs with this config profile
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+    },
     /// Remove file manager context menu integration
-    Uninstall,
+    Uninstall {
+        /// Remove the menu entry installed for this config profile
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+    },
+    /// Save the dry-run plan to a file for review, to execute with `apply`
+    Plan {
+        #[command(flatten)]
+        plan: PlanArgs,
+
+        /// File to write the plan to
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Execute a plan saved with `tagmv plan`, skipping stale entries
+    Apply {
+        /// Plan file written by `tagmv plan`
+        file: PathBuf,
+    },
+    /// Revert previous --execute runs using their journal
+    Undo {
+        /// Library root the runs wrote into (defaults to current directory)
+        path: Option<PathBuf>,
+
+        /// Number of most recent runs to undo
+        #[arg(long, default_value_t = 1, conflicts_with = "journal")]
+        last: usize,
+
+        /// Undo a specific journal file
+        #[arg(long, value_name = "FILE")]
+        journal: Option<PathBuf>,
+
+        /// Undo even if a destination changed since it was recorded
+        #[arg(long)]
+        force: bool,
+    },
+    /// Manage the tag and hash caches
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
 }
 
-fn is_audio_file(path: &Path) -> bool {
-    path.extension()
-        .and_then(OsStr::to_str)
-        .map(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
-        .unwrap_or(false)
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Delete the caches, so every file is read again on the next run
+    Clear,
 }
 
-fn is_hidden(name: &str) -> bool {
-    name.starts_with('.')
+impl PlanArgs {
+    /// Settings from the config files (and `--profile`), overridden by the
+    /// flags given on the command line, with the config files they came
+    /// from.
+    fn settings(&self) -> Result<(PathBuf, Config, Settings)> {
+        let dir = match &self.path {
+            Some(p) => p.clone(),
+            None => std::env::current_dir()
+                .context("Could not determine current directory. Please specify a path.")?,
+        };
+        let dir = std::fs::canonicalize(&dir)
+            .with_context(|| format!("Cannot resolve path: {}", dir.display()))?;
+
+        let flags = Settings {
+            dest: self.dest.clone(),
+            recursive: self.recursive.then_some(true),
+            mode: self.mode,
+            template: self.template.clone(),
+            compilation_template: self.compilation_template.clone(),
+            disc_layout: self.disc_layout,
+            on_conflict: self.on_conflict,
+            dedupe: self.dedupe,
+            companions: self.no_companions.then_some(false),
+            unsorted_by_reason: self.unsorted_by_reason.then_some(true),
+            on_corrupt: self.on_corrupt,
+            validate: self.validate.then_some(true),
+            sniff: self.sniff.then_some(true),
+            infer: self.infer.then_some(true),
+            write_tags: self.write_tags.then_some(true),
+            infer_patterns: Some(self.infer_pattern.clone()).filter(|p| !p.is_empty()),
+            jobs: self.jobs.map(|n| n as usize),
+            cache: self.no_cache.then_some(false),
+            ..Default::default()
+        };
+        let config = Config::load(&dir)?;
+        let settings = config.settings(self.profile.as_deref())?.merge(flags);
+        Ok((dir, config, settings))
+    }
 }
 
-fn scan_files(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
-    let mut files = Vec::new();
-
-    if recursive {
-        for entry in WalkDir::new(dir)
-            .into_iter()
-            .filter_entry(|e| {
-                let name = e.file_name().to_string_lossy();
-                if e.file_type().is_dir() {
-                    if e.depth() == 0 {
-                        return true;
-                    }
-                    return !is_hidden(&name) && name != "_Unsorted";
-                }
-                true
-            })
-            .filter_map(|e| e.ok())
-        {
-            let path = entry.path().to_path_buf();
-            if path.is_file()
-                && is_audio_file(&path)
-                && !is_hidden(&entry.file_name().to_string_lossy())
-            {
-                files.push(path);
-            }
-        }
-    } else {
-        let entries = std::fs::read_dir(dir)
-            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
-        for entry in entries.filter_map(|e| e.ok()) {
-            let path = entry.path();
-            if path.is_file() && is_audio_file(&path) {
-                let name = entry.file_name().to_string_lossy().to_string();
-                if !is_hidden(&name) {
-                    files.push(path);
-                }
-            }
-        }
+fn summary_line(summary: &Summary) -> String {
+    let mut line = format!(
+        "Summary: {} files -> {} folders, {} unsorted",
+        summary.files, summary.folders, summary.unsorted
+    );
+    if summary.corrupt > 0 {
+        line += &format!(", {} corrupt", summary.corrupt);
+    }
+    if summary.in_place > 0 {
+        line += &format!(", {} already in place", summary.in_place);
+    }
+    if summary.skipped_for_conflict > 0 {
+        line += &format!(
+            ", {} skipped (destination taken)",
+            summary.skipped_for_conflict
+        );
+    }
+    if summary.undecided > 0 {
+        line += &format!(
+            ", {} conflicts to decide (asked with --execute)",
+            summary.undecided
+        );
     }
+    if summary.exact_duplicates > 0 {
+        line += &format!(", {} exact duplicates", summary.exact_duplicates);
+    }
+    if summary.duplicates > 0 {
+        line += &format!(", {} to duplicates", summary.duplicates);
+    }
+    if summary.extensions_corrected > 0 {
+        line += &format!(", {} extensions corrected", summary.extensions_corrected);
+    }
+    if summary.inferred > 0 {
+        line += &format!(", {} tagged from their path", summary.inferred);
+    }
+    if summary.tags_to_write > 0 {
+        line += &format!(", {} with new tags", summary.tags_to_write);
+    }
+    if summary.companions > 0 {
+        line += &format!(", {} companion files", summary.companions);
+    }
+    line
+}
 
-    files.sort();
-    Ok(files)
+fn describe_quality(q: &AudioQuality) -> String {
+    let bitrate = match q.bitrate {
+        Some(kbps) => format!("{} kbps", kbps),
+        None => "unknown bitrate".to_string(),
+    };
+    format!(
+        "{}, {}, {:.1} MB",
+        if q.lossless { "lossless" } else { "lossy" },
+        bitrate,
+        q.size as f64 / 1_000_000.0
+    )
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// `--on-conflict ask`: prompt on the terminal. Without one, conflicts are
+/// skipped.
+fn ask_conflict(case: &ConflictCase<'_>) -> ConflictPolicy {
+    if !std::io::stdin().is_terminal() {
+        return ConflictPolicy::Skip;
+    }
 
-    match cli.command {
-        Some(Commands::Install) => return install::install_quick_action(),
-        Some(Commands::Uninstall) => return install::uninstall_quick_action(),
-        None => {}
+    eprintln!("\n{} {}", "Conflict:".yellow().bold(), case.dest.display());
+    eprintln!(
+        "  existing  {}  {}",
+        case.existing.display(),
+        describe_quality(&read_quality(case.existing)).dimmed()
+    );
+    eprintln!(
+        "  incoming  {}  {}",
+        case.incoming.display(),
+        describe_quality(&read_quality(case.incoming)).dimmed()
+    );
+
+    loop {
+        let Some(answer) = prompt("  [s]kip, [o]verwrite, [r]ename, keep [b]etter? ") else {
+            return ConflictPolicy::Skip;
+        };
+        match answer.to_ascii_lowercase().as_str() {
+            "s" | "skip" => return ConflictPolicy::Skip,
+            "o" | "overwrite" => return ConflictPolicy::Overwrite,
+            "r" | "rename" => return ConflictPolicy::Rename,
+            "b" | "better" => return ConflictPolicy::KeepLossless,
+            _ => {}
+        }
     }
+}
 
-    let dir = match cli.path {
-        Some(p) => p,
-        None => std::env::current_dir()
-            .context("Could not determine current directory. Please specify a path.")?,
-    };
+/// Ask on stderr and read one trimmed line from stdin; `None` at the end of
+/// input.
+fn prompt(question: &str) -> Option<String> {
+    eprint!("{}", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    match std::io::stdin().lock().read_line(&mut answer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(answer.trim().to_string()),
+    }
+}
 
-    let dir = std::fs::canonicalize(&dir)
-        .with_context(|| format!("Cannot resolve path: {}", dir.display()))?;
+/// Moves grouped by destination folder, for display.
+fn by_folder<'a>(
+    moves: impl IntoIterator<Item = &'a PlannedMove>,
+) -> BTreeMap<&'a str, Vec<&'a PlannedMove>> {
+    let mut folders: BTreeMap<&str, Vec<&PlannedMove>> = BTreeMap::new();
+    for m in moves {
+        folders.entry(&m.folder_name).or_default().push(m);
+    }
+    folders
+}
 
-    if !dir.is_dir() {
-        anyhow::bail!("Not a directory: {}", dir.display());
+/// Human-readable dry-run listing, grouped by destination folder.
+fn print_plan(moves: &[PlannedMove], summary: &Summary) {
+    for (folder, folder_moves) in by_folder(moves) {
+        print_folder(folder, &folder_moves);
+        println!();
     }
 
-    let mode = if cli.execute {
-        "EXECUTING"
+    println!("{}", summary_line(summary));
+}
+
+/// A destination folder and the files going into it.
+fn print_folder(folder: &str, folder_moves: &[&PlannedMove]) {
+    if folder_moves
+        .iter()
+        .all(|m| m.meta.is_none() && !m.companion)
+    {
+        println!("  {}", folder.red().bold());
+    } else if let Some(rule) = folder_moves.iter().find_map(|m| m.compilation) {
+        println!(
+            "  {}  {}",
+            format!("{}/", folder).yellow().bold(),
+            format!("(compilation: {})", rule).cyan()
+        );
     } else {
-        "DRY RUN (use --execute to move files)"
-    };
+        println!("  {}", format!("{}/", folder).yellow().bold());
+    }
 
-    let version = env!("CARGO_PKG_VERSION");
-    println!("tagmv v{} -- {}\n", version, mode.bold());
-    println!("Scanning: {}", dir.display().to_string().dimmed());
+    for m in folder_moves {
+        if m.is_in_place() && m.damage.is_some() {
+            println!("    {}  {}", m.file_name.dimmed(), "(left in place)".red());
+        } else if m.is_in_place() {
+            println!(
+                "    {}  {}",
+                m.file_name.dimmed(),
+                "(already in place)".dimmed()
+            );
+            print_tag_changes(m);
+        } else if m.conflict == Some(Conflict::Skipped) {
+            println!(
+                "    {}  {}",
+                m.file_name.dimmed(),
+                "(skipped, destination taken)".yellow()
+            );
+        } else if m.conflict == Some(Conflict::Undecided) {
+            println!(
+                "    {}  {}",
+                m.file_name.dimmed(),
+                "(destination taken, asked with --execute)".yellow()
+            );
+        } else {
+            let source_name = m.source.file_name().and_then(|n| n.to_str()).unwrap_or("?");
 
-    let files = scan_files(&dir, cli.recursive)?;
-    println!("Found {} audio files\n", files.len().to_string().bold());
+            let note = match (m.conflict, &m.duplicate_of) {
+                (Some(Conflict::Overwrites), _) => " (overwrites existing)".red(),
+                (Some(Conflict::Preferred), _) => " (better copy)".cyan(),
+                (Some(Conflict::Duplicate), _) => " (duplicate)".magenta(),
+                (Some(Conflict::Displaced), _) => " (displaced by a better copy)".magenta(),
+                _ if m.companion => " (companion)".dimmed(),
+                (_, Some(duplicate)) => {
+                    format!(" (same audio as {})", duplicate.path.display()).magenta()
+                }
+                _ => match m.unsorted {
+                    Some(reason) => format!(" ({})", reason).red(),
+                    None => "".normal(),
+                },
+            };
+            let corrected = match &m.corrected_extension {
+                Some(ext) => {
+                    let old = m.source.extension().map(|e| e.to_string_lossy());
+                    match old {
+                        Some(old) => format!(" (extension .{} -> .{})", old, ext),
+                        None => format!(" (extension .{} added)", ext),
+                    }
+                }
+                None => String::new(),
+            };
 
-    if files.is_empty() {
-        return Ok(());
+            let inferred = match &m.inferred_from {
+                Some(pattern) => format!(" (inferred from {})", pattern),
+                None => String::new(),
+            };
+
+            println!(
+                "    {}  {} {}{}{}{}",
+                m.file_name.green(),
+                "<-".dimmed(),
+                source_name.dimmed(),
+                note,
+                corrected.cyan(),
+                inferred.yellow()
+            );
+            print_tag_changes(m);
+        }
+        if let Some(damage) = &m.damage {
+            println!("        {}", damage.to_string().red());
+        }
     }
+}
 
-    let mut moves: Vec<PlannedMove> = Vec::new();
+/// One line per tag that `--write-tags` changes, under the file's line.
+fn print_tag_changes(m: &PlannedMove) {
+    for change in &m.tag_changes {
+        let old = match &change.old {
+            Some(old) => format!("\"{}\"", old),
+            None => "(none)".to_string(),
+        };
+        println!(
+            "        {} {} {} {}",
+            format!("{}:", change.field).dimmed(),
+            old.red(),
+            "->".dimmed(),
+            format!("\"{}\"", change.new).green()
+        );
+    }
+}
 
-    for file in &files {
-        match read_tags(file) {
-            Some(meta) => {
-                let planned = compute_destination(&dir, file, &meta);
-                moves.push(planned);
+/// `--interactive`: step through the plan folder by folder and return the
+/// accepted moves, in plan order. Folders where nothing would happen are
+/// not asked about.
+fn review(
+    moves: Vec<PlannedMove>,
+    target: &Path,
+    planner: &Planner,
+    layout: &Layout,
+) -> Vec<PlannedMove> {
+    // Each slot is a move, after the library files it displaces (which go
+    // wherever the move goes)
+    let mut slots: Vec<Vec<PlannedMove>> = Vec::new();
+    let mut displaced = Vec::new();
+    for m in moves {
+        let last = m.conflict != Some(Conflict::Displaced);
+        displaced.push(m);
+        if last {
+            slots.push(std::mem::take(&mut displaced));
+        }
+    }
+    if !displaced.is_empty() {
+        slots.push(displaced);
+    }
+
+    let mut folders: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, slot) in slots.iter().enumerate() {
+        let folder = &slot[slot.len() - 1].folder_name;
+        folders.entry(folder.clone()).or_default().push(i);
+    }
+
+    let mut accepted = vec![false; slots.len()];
+    let mut accept_rest = false;
+    let count = folders.len();
+    let mut taken = 0;
+    'folders: for (n, indices) in folders.values().enumerate() {
+        let idle = indices
+            .iter()
+            .flat_map(|&i| &slots[i])
+            .all(|m| !m.needs_transfer() && m.tag_changes.is_empty());
+        if accept_rest || idle {
+            for &i in indices {
+                accepted[i] = accept_rest;
             }
-            None => {
-                let planned = compute_unsorted_destination(&dir, file);
-                moves.push(planned);
+            taken += usize::from(accept_rest && !idle);
+            continue;
+        }
+
+        loop {
+            println!();
+            for (folder, folder_moves) in by_folder(indices.iter().flat_map(|&i| &slots[i])) {
+                print_folder(folder, &folder_moves);
+            }
+            let question = format!(
+                "[{}/{}] [a]ccept, [s]kip, [d]estination, [t]ags, accept a[l]l, [q]uit? ",
+                n + 1,
+                count
+            );
+            let Some(answer) = prompt(&question) else {
+                break 'folders;
+            };
+            match answer.to_ascii_lowercase().as_str() {
+                "a" | "accept" => {}
+                "s" | "skip" => break,
+                "l" | "all" => accept_rest = true,
+                "q" | "quit" => break 'folders,
+                "d" | "destination" => {
+                    let first = slots[indices[0]].last().expect("slots are never empty");
+                    let question = format!("  Folder [{}]: ", first.folder_name);
+                    let Some(input) = prompt(&question).filter(|f| !f.is_empty()) else {
+                        continue;
+                    };
+                    match parse_folder(&input, layout) {
+                        Ok(folder) => refile(&mut slots, indices, target, layout, |m| {
+                            into_folder(target, m, &folder)
+                        }),
+                        Err(e) => eprintln!("  {} {}", "ERROR".red().bold(), e),
+                    }
+                    continue;
+                }
+                "t" | "tags" => {
+                    let shown = indices
+                        .iter()
+                        .flat_map(|&i| &slots[i])
+                        .find_map(|m| m.meta.as_ref())
+                        .map(|meta| {
+                            (
+                                meta.album_artist.as_ref().unwrap_or(&meta.artist),
+                                &meta.album,
+                            )
+                        });
+                    let (old_artist, old_album) = match shown {
+                        Some((artist, album)) => (artist.clone(), album.clone()),
+                        None => Default::default(),
+                    };
+                    let Some(artist) = prompt(&format!("  Artist [{}]: ", old_artist)) else {
+                        continue;
+                    };
+                    let Some(album) = prompt(&format!("  Album [{}]: ", old_album)) else {
+                        continue;
+                    };
+                    let artist = Some(artist)
+                        .filter(|a| !a.is_empty())
+                        .unwrap_or(old_artist.clone());
+                    let album = Some(album).filter(|a| !a.is_empty()).unwrap_or(old_album);
+                    refile(&mut slots, indices, target, layout, |m| {
+                        if m.companion {
+                            return m;
+                        }
+                        let mut meta = m.meta.clone().unwrap_or_default();
+                        if meta.album_artist.is_some() {
+                            meta.album_artist = Some(artist.clone());
+                        }
+                        if meta.artist.is_empty() || meta.artist == old_artist {
+                            meta.artist = artist.clone();
+                        }
+                        meta.album = album.clone();
+                        match planner.relayout(target, &m, &meta) {
+                            Some(mut relaid) => {
+                                relaid.meta = Some(meta);
+                                relaid
+                            }
+                            None => {
+                                eprintln!(
+                                    "  {} {}: the template needs tags it does not have",
+                                    "WARNING".yellow().bold(),
+                                    m.source.display()
+                                );
+                                m
+                            }
+                        }
+                    });
+                    continue;
+                }
+                _ => continue,
             }
+            for &i in indices {
+                accepted[i] = true;
+            }
+            taken += 1;
+            break;
         }
     }
 
-    resolve_conflicts(&mut moves);
+    println!("\nAccepted {} of {} folders", taken, count);
+    slots
+        .into_iter()
+        .zip(accepted)
+        .filter(|(_, accepted)| *accepted)
+        .flat_map(|(slot, _)| slot)
+        .collect()
+}
 
-    // Group by folder for display
-    let mut folders: BTreeMap<String, Vec<&PlannedMove>> = BTreeMap::new();
-    for m in &moves {
-        folders.entry(m.folder_name.clone()).or_default().push(m);
+/// A folder typed in during review, as sanitized components under the
+/// library root.
+fn parse_folder(input: &str, layout: &Layout) -> Result<Vec<String>> {
+    let path = Path::new(input);
+    if path.is_absolute()
+        || path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("{} is not a folder inside the library", input);
     }
+    let folder: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(layout.sanitize.apply(&c.to_string_lossy())),
+            _ => None,
+        })
+        .collect();
+    if folder.is_empty() {
+        bail!("{} is the library root, not a folder inside it", input);
+    }
+    Ok(folder)
+}
 
-    let mut move_count = 0u32;
-    let mut unsorted_count = 0u32;
-    let mut skipped_count = 0u32;
+/// `m` filed into `folder` under the library root, keeping its file name.
+fn into_folder(target: &Path, mut m: PlannedMove, folder: &[String]) -> PlannedMove {
+    let dir = folder
+        .iter()
+        .fold(target.to_path_buf(), |dir, c| dir.join(c));
+    m.dest = dir.join(&m.file_name);
+    m.folder_name = folder.join("/");
+    m
+}
 
-    for (folder, folder_moves) in &folders {
-        if folder == "_Unsorted" {
-            println!("  {}", folder.red().bold());
-        } else {
-            println!("  {}", format!("{}/", folder).yellow().bold());
+/// Replace the moves of the slots at `indices` by `f` of them and settle
+/// their conflicts again. The library files they displaced are looked at
+/// anew, and companions follow the audio out of a folder it left.
+fn refile(
+    slots: &mut [Vec<PlannedMove>],
+    indices: &[usize],
+    target: &Path,
+    layout: &Layout,
+    f: impl Fn(PlannedMove) -> PlannedMove,
+) {
+    let mut sources = Vec::new();
+    let mut moves = Vec::new();
+    let mut stayed = Vec::new();
+    for &i in indices {
+        let m = slots[i].pop().expect("slots are never empty");
+        slots[i].clear();
+        sources.push(m.source.clone());
+        let folder = m.folder_name.clone();
+        let m = f(PlannedMove {
+            conflict: None,
+            ..m
+        });
+        stayed.push(m.companion && m.folder_name == folder);
+        moves.push(m);
+    }
+    if let Some(audio) = moves.iter().find(|m| !m.companion) {
+        let folder: Vec<String> = audio.folder_name.split('/').map(str::to_string).collect();
+        for (m, _) in moves.iter_mut().zip(stayed).filter(|(_, stayed)| *stayed) {
+            *m = into_folder(target, std::mem::take(m), &folder);
         }
+    }
 
-        for m in folder_moves {
-            if m.source == m.dest {
-                skipped_count += 1;
-                println!(
-                    "    {}  {}",
-                    m.file_name.dimmed(),
-                    "(already in place)".dimmed()
-                );
-            } else {
-                let source_name = m
-                    .source
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("?");
+    // Moves of other folders that already claim one of the new destinations
+    // are settled again too, ahead of these so that they keep it
+    let claims: Vec<usize> = (0..slots.len())
+        .filter(|i| !indices.contains(i))
+        .filter(|&i| {
+            slots[i].last().is_some_and(|other| {
+                other.needs_transfer()
+                    && !other.is_skipped()
+                    && moves.iter().any(|m| m.dest == other.dest)
+            })
+        })
+        .collect();
+    let mut owners = Vec::new();
+    let mut all = Vec::new();
+    for &i in &claims {
+        let m = slots[i].pop().expect("slots are never empty");
+        slots[i].clear();
+        owners.push((m.source.clone(), i));
+        all.push(PlannedMove {
+            conflict: None,
+            ..m
+        });
+    }
+    owners.extend(sources.into_iter().zip(indices.iter().copied()));
+    all.extend(moves);
 
-                println!(
-                    "    {}  {} {}",
-                    m.file_name.green(),
-                    "<-".dimmed(),
-                    source_name.dimmed()
-                );
+    let ask: AskFn<'_> = &ask_conflict;
+    layout.resolve_conflicts(target, &mut all, Some(ask));
 
-                if folder == "_Unsorted" {
-                    unsorted_count += 1;
-                } else {
-                    move_count += 1;
-                }
+    let mut displaced = Vec::new();
+    let mut last = None;
+    for m in all {
+        let owner = owners.iter().find(|(source, _)| *source == m.source);
+        match owner {
+            Some(&(_, i)) if m.conflict != Some(Conflict::Displaced) => {
+                displaced.push(m);
+                slots[i] = std::mem::take(&mut displaced);
+                last = Some(i);
             }
+            _ => displaced.push(m),
         }
+    }
+    if let Some(i) = last {
+        slots[i].append(&mut displaced);
+    }
+}
 
-        println!();
+/// Transfer counts keyed by the mode actually applied and whether it was a
+/// fallback from the planned mode.
+type TransferCounts = BTreeMap<(TransferMode, bool), u32>;
+
+fn print_execution_summary(done: &TransferCounts, errors: u32, mode: TransferMode) {
+    let mut parts: Vec<String> = done
+        .iter()
+        .map(|((applied, fallback), n)| {
+            let fallback = if *fallback { " (fallback)" } else { "" };
+            format!("{} {} files{}", applied.verb(), n, fallback)
+        })
+        .collect();
+    if parts.is_empty() {
+        parts.push(format!("{} 0 files", mode.verb()));
     }
+    let done_text = parts.join(", ");
+    let mut chars = done_text.chars();
+    let done_text: String = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => done_text,
+    };
 
-    let folder_count = folders.keys().filter(|k| *k != "_Unsorted").count();
-    let total = move_count + unsorted_count + skipped_count;
     println!(
-        "Summary: {} files -> {} folders, {} unsorted{}",
-        total,
-        folder_count,
-        unsorted_count,
-        if skipped_count > 0 {
-            format!(", {} already in place", skipped_count)
+        "{} successfully{}",
+        done_text,
+        if errors > 0 {
+            format!(", {} errors", errors)
         } else {
             String::new()
         }
     );
+}
 
-    if cli.execute {
-        println!();
-        let mut success = 0u32;
-        let mut errors = 0u32;
+/// Plan according to `args`, returning the plan and the settings it was made
+/// with. With `header`, the scan is reported for humans. Conflicts under
+/// `--on-conflict ask` are only asked about with `ask`; otherwise they are
+/// left undecided.
+fn build_plan(args: &PlanArgs, header: Option<&str>, ask: bool) -> Result<(Plan, Settings)> {
+    let (dir, config, settings) = args.settings()?;
+    let mut planner = settings.planner(dir);
+    if ask {
+        planner = planner.on_ask(ask_conflict);
+    }
+    let plan = planner.plan()?;
 
-        for m in &moves {
-            if m.source == m.dest {
-                continue;
+    if let Some(run_mode) = header {
+        let version = env!("CARGO_PKG_VERSION");
+        println!("tagmv v{} -- {}\n", version, run_mode.bold());
+        println!("Scanning: {}", plan.root.display().to_string().dimmed());
+        if plan.target != plan.root {
+            println!("Target:   {}", plan.target.display().to_string().dimmed());
+        }
+        for path in config.paths() {
+            println!("Config:   {}", path.display().to_string().dimmed());
+            let ignored = config.ignored(path);
+            if !ignored.is_empty() {
+                println!(
+                    "          {} {} (the file is in the scanned directory)",
+                    "ignored:".yellow(),
+                    ignored.join(", ")
+                );
             }
+        }
+        if let Some(profile) = &args.profile {
+            println!("Profile:  {}", profile.dimmed());
+        }
+        let mode = settings.mode.unwrap_or_default();
+        if mode != TransferMode::Move {
+            println!("Mode:     {}", mode.to_string().dimmed());
+        }
+        let on_conflict = settings.on_conflict.unwrap_or_default();
+        if on_conflict != ConflictPolicy::Rename {
+            println!("Conflict: {}", on_conflict.to_string().dimmed());
+        }
+        if let Some(dedupe) = settings.dedupe {
+            println!("Dedupe:   {}", dedupe.to_string().dimmed());
+        }
+        println!(
+            "Found {} audio files\n",
+            plan.summary().files.to_string().bold()
+        );
+    }
+    Ok((plan, settings))
+}
 
-            match execute_move(m) {
-                Ok(()) => {
-                    success += 1;
+/// Execute every move that is not already in place, journaling into
+/// `target`. Errors are reported on stderr and returned as results. With
+/// `prune`, emptied directories under its root are removed afterwards.
+fn execute_moves(
+    moves: &[PlannedMove],
+    target: &Path,
+    text: bool,
+    prune: Option<(&Path, Vec<String>)>,
+) -> Result<Vec<ExecResult>> {
+    let mut done = TransferCounts::new();
+    let mut tagged = 0u32;
+    let mut errors = 0u32;
+
+    let pruning = prune.is_some();
+    let mut executor = Executor::new();
+    if let Some((root, junk_files)) = prune {
+        executor = executor.prune_empty(root).junk_files(junk_files);
+    }
+    let execution = executor
+        .on_progress(|progress| match progress {
+            Progress::Transferred {
+                planned, result, ..
+            } => {
+                if let Some(applied) = result.applied_mode {
+                    *done.entry((applied, applied != planned.mode)).or_default() += 1;
                 }
-                Err(e) => {
+                if result.tags_written {
+                    tagged += 1;
+                }
+                if let Some(error) = &result.error {
                     eprintln!(
                         "  {} {} -> {}: {}",
                         "ERROR".red().bold(),
-                        m.source.display(),
-                        m.dest.display(),
-                        e
+                        planned.source.display(),
+                        planned.dest.display(),
+                        error
                     );
                     errors += 1;
                 }
             }
+            Progress::JournalFailed { error, .. } => {
+                eprintln!("  {} {}", "WARNING".yellow().bold(), error);
+            }
+        })
+        .execute(target, moves)?;
+
+    let journal = execution.journal.unwrap_or_default();
+    if text {
+        let mode = moves.first().map(|m| m.mode).unwrap_or_default();
+        println!();
+        print_execution_summary(&done, errors, mode);
+        if tagged > 0 {
+            println!("Wrote tags into {} files", tagged);
+        }
+        if !execution.pruned.is_empty() {
+            println!("Removed {} empty directories", execution.pruned.len());
+        } else if pruning && errors > 0 {
+            println!("Removed no empty directories, since not everything succeeded");
+        }
+        println!(
+            "Journal: {} (revert with `tagmv undo`)",
+            journal.display().to_string().dimmed()
+        );
+    } else {
+        if tagged > 0 {
+            eprintln!("Wrote tags into {} files", tagged);
         }
+        if !execution.pruned.is_empty() {
+            eprintln!("Removed {} empty directories", execution.pruned.len());
+        } else if pruning && errors > 0 {
+            eprintln!("Removed no empty directories, since not everything succeeded");
+        }
+        eprintln!("Journal: {}", journal.display());
+    }
+    Ok(execution.results)
+}
+
+fn run_plan(args: &PlanArgs, output: &Path) -> Result<()> {
+    let (plan, _) = build_plan(args, Some("PLAN (nothing is moved)"), false)?;
+    if plan.moves.is_empty() {
+        return Ok(());
+    }
 
+    print_plan(&plan.moves, &plan.summary());
+
+    if plan.moves.iter().any(|m| !m.tag_changes.is_empty()) {
         println!(
-            "Moved {} files successfully{}",
-            success,
-            if errors > 0 {
-                format!(", {} errors", errors)
-            } else {
-                String::new()
-            }
+            "\n{} plan files do not keep tag changes; `tagmv apply` only moves files",
+            "Note:".yellow().bold()
         );
     }
 
+    let plan = PlanFile::new(&plan.target, &plan.moves)?;
+    plan.save(output)?;
+    println!(
+        "\nWrote {} entries to {} (execute with `tagmv apply {}`)",
+        plan.entries.len(),
+        output.display().to_string().dimmed(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn run_apply(file: &Path) -> Result<()> {
+    let plan = PlanFile::load(file)?;
+    println!("Applying {}\n", file.display().to_string().dimmed());
+
+    let mut moves = Vec::new();
+    let mut stale = 0u32;
+    for (entry, checked) in plan.entries.iter().zip(plan.validate()) {
+        match checked {
+            Ok(planned) => {
+                println!(
+                    "    {}  {} {}",
+                    planned.dest.display().to_string().green(),
+                    "<-".dimmed(),
+                    planned.source.display().to_string().dimmed()
+                );
+                moves.push(planned);
+            }
+            Err(e) => {
+                stale += 1;
+                eprintln!(
+                    "  {} {} -> {}: {}",
+                    "STALE".yellow().bold(),
+                    entry.source.display(),
+                    entry.dest.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    if stale > 0 {
+        println!("\nRefused {} stale entries", stale);
+    }
+    if moves.is_empty() {
+        println!("Nothing to apply");
+        return Ok(());
+    }
+
+    execute_moves(&moves, &plan.target, true, None)?;
+    Ok(())
+}
+
+fn run_undo(
+    path: Option<PathBuf>,
+    last: usize,
+    journal: Option<PathBuf>,
+    force: bool,
+) -> Result<()> {
+    let journals = match journal {
+        Some(j) => vec![j],
+        None => {
+            let target = match path {
+                Some(p) => p,
+                None => std::env::current_dir()
+                    .context("Could not determine current directory. Please specify a path.")?,
+            };
+            let all = journal::list_journals(&target)?;
+            if all.is_empty() {
+                println!("Nothing to undo (no journals in {})", target.display());
+                return Ok(());
+            }
+            all.into_iter().rev().take(last).collect()
+        }
+    };
+
+    let mut reverted = 0u32;
+    let mut errors = 0u32;
+
+    for j in &journals {
+        println!("Undoing {}", j.display().to_string().dimmed());
+        for step in journal::undo(j, force)? {
+            match step.result {
+                Ok(()) => {
+                    reverted += 1;
+                    println!("    {}", step.description.green());
+                }
+                Err(e) => {
+                    errors += 1;
+                    eprintln!("  {} {}: {}", "ERROR".red().bold(), step.description, e);
+                }
+            }
+        }
+        println!();
+    }
+
+    println!(
+        "Reverted {} steps from {} journals{}",
+        reverted,
+        journals.len(),
+        if errors > 0 {
+            format!(", {} errors (journal kept)", errors)
+        } else {
+            String::new()
+        }
+    );
+    Ok(())
+}
+
+fn run_cache_clear() -> Result<()> {
+    let removed = cache::clear()?;
+    if removed.is_empty() {
+        println!("Nothing to clear");
+    }
+    for path in removed {
+        println!("Removed {}", path.display().to_string().dimmed());
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Commands::Install { profile }) => {
+            return install::install_quick_action(profile.as_deref())
+        }
+        Some(Commands::Uninstall { profile }) => {
+            return install::uninstall_quick_action(profile.as_deref())
+        }
+        Some(Commands::Plan { plan, output }) => return run_plan(&plan, &output),
+        Some(Commands::Apply { file }) => return run_apply(&file),
+        Some(Commands::Undo {
+            path,
+            last,
+            journal,
+            force,
+        }) => return run_undo(path, last, journal, force),
+        Some(Commands::Cache {
+            command: CacheCommand::Clear,
+        }) => return run_cache_clear(),
+        None => {}
+    }
+
+    let text = cli.format == OutputFormat::Text;
+    if cli.interactive && (!text || !std::io::stdin().is_terminal()) {
+        bail!("--interactive needs a terminal and text output");
+    }
+    let run_mode = if cli.interactive {
+        "INTERACTIVE (review each folder, then execute)"
+    } else if cli.execute {
+        "EXECUTING"
+    } else {
+        "DRY RUN (use --execute to move files)"
+    };
+
+    let ask = cli.execute || cli.interactive;
+    let (Plan { root, target, moves }, settings) =
+        build_plan(&cli.plan, text.then_some(run_mode), ask)?;
+    if text && moves.is_empty() {
+        return Ok(());
+    }
+    let prune_empty = cli.prune_empty || settings.prune_empty == Some(true);
+    let prune = prune_empty.then(|| (root.as_path(), settings.junk_files()));
+
+    if cli.interactive {
+        println!("{}", summary_line(&Summary::of(&moves)));
+        let planner = settings.planner(&root);
+        let moves = review(moves, &target, &planner, &settings.layout());
+        let summary = Summary::of(&moves);
+        println!("{}", summary_line(&summary));
+        if summary.to_transfer > 0 || summary.tags_to_write > 0 {
+            execute_moves(&moves, &target, true, prune)?;
+        }
+        return Ok(());
+    }
+
+    let summary = Summary::of(&moves);
+    if text {
+        print_plan(&moves, &summary);
+    }
+
+    // Nothing to execute (and no journal to write) if everything is in place
+    let mut results: Option<Vec<ExecResult>> = None;
+    if cli.execute && (summary.to_transfer > 0 || summary.tags_to_write > 0) {
+        results = Some(execute_moves(&moves, &target, text, prune)?);
+    }
+
+    if !text {
+        let mut stdout = std::io::stdout().lock();
+        output::write_plan(
+            &mut stdout,
+            cli.format,
+            &moves,
+            &summary,
+            results.as_deref(),
+        )?;
+        if cli.format == OutputFormat::Csv {
+            eprintln!("{}", summary_line(&summary));
+        }
+    }
+
     Ok(())
 }
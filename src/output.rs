@@ -0,0 +1,513 @@
+use crate::executor::ExecResult;
+use crate::sorting::{Conflict, MoveReason, PlannedMove, TransferMode};
+use crate::tags::{TagChange, TrackMetadata, UnsortedReason};
+use crate::validate::Damage;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+
+/// Bumped whenever a field is removed or changes meaning.
+const FORMAT_VERSION: u32 = 1;
+
+const CSV_HEADER: &[&str] = &[
+    "source",
+    "dest",
+    "folder",
+    "file",
+    "reason",
+    "mode",
+    "compilation",
+    "artist",
+    "album",
+    "album_artist",
+    "title",
+    "track",
+    "disc",
+    "year",
+    "unsorted_reason",
+    "damage",
+    "inferred_from",
+    "duplicate_of",
+    "status",
+    "applied_mode",
+    "error",
+];
+
+/// How the plan (and execution results) are written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Colored, grouped listing for humans
+    #[default]
+    Text,
+    /// One JSON document
+    Json,
+    /// One JSON object per line, tagged with "type"
+    Ndjson,
+    /// One row per file (summary goes to stderr)
+    Csv,
+}
+
+/// Counts shown in the summary line.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    /// Scanned files (displaced library files are not counted).
+    pub files: usize,
+    pub folders: usize,
+    pub to_transfer: usize,
+    pub unsorted: usize,
+    /// Unreadable or damaged files, wherever they go.
+    pub corrupt: usize,
+    pub in_place: usize,
+    pub renamed_for_conflict: usize,
+    pub skipped_for_conflict: usize,
+    /// Conflicts left to `--on-conflict ask` (dry runs do not ask).
+    pub undecided: usize,
+    /// Files moved to the duplicates folder, displaced ones included.
+    pub duplicates: usize,
+    /// Files with the same audio as a file that is kept (`--dedupe`).
+    pub exact_duplicates: usize,
+    /// Files whose extension did not match their content (`--sniff`).
+    pub extensions_corrected: usize,
+    /// Files filed by tags read from their path (`--infer`).
+    pub inferred: usize,
+    /// Files whose tags are rewritten (`--write-tags`).
+    pub tags_to_write: usize,
+    /// Cover art, cue sheets and the like that go along with the audio
+    /// (not counted in `files`).
+    pub companions: usize,
+}
+
+impl Summary {
+    pub fn of(moves: &[PlannedMove]) -> Summary {
+        let mut summary = Summary::default();
+        let mut folders = BTreeSet::new();
+
+        for m in moves {
+            if m.needs_transfer() {
+                summary.to_transfer += 1;
+            }
+            if m.companion {
+                if m.needs_transfer() {
+                    summary.companions += 1;
+                }
+                continue;
+            }
+            match m.conflict {
+                Some(Conflict::Displaced) => {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                Some(Conflict::Duplicate) => summary.duplicates += 1,
+                Some(Conflict::Renamed) => summary.renamed_for_conflict += 1,
+                Some(Conflict::Skipped) => {
+                    summary.skipped_for_conflict += 1;
+                    summary.files += 1;
+                    continue;
+                }
+                Some(Conflict::Undecided) => {
+                    summary.undecided += 1;
+                    summary.files += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            summary.files += 1;
+            if m.corrected_extension.is_some() {
+                summary.extensions_corrected += 1;
+            }
+            if m.inferred_from.is_some() {
+                summary.inferred += 1;
+            }
+            if !m.tag_changes.is_empty() {
+                summary.tags_to_write += 1;
+            }
+            if m.damage.is_some() {
+                summary.corrupt += 1;
+            }
+
+            if let Some(duplicate) = &m.duplicate_of {
+                summary.exact_duplicates += 1;
+                if duplicate.quarantined {
+                    summary.duplicates += 1;
+                    continue;
+                }
+            }
+            if m.meta.is_some() && m.conflict != Some(Conflict::Duplicate) {
+                folders.insert(m.folder_name.as_str());
+            }
+            if m.is_in_place() {
+                summary.in_place += 1;
+            } else if m.meta.is_none() && m.reason() != MoveReason::Corrupt {
+                summary.unsorted += 1;
+            }
+        }
+
+        summary.folders = folders.len();
+        summary
+    }
+}
+
+#[derive(Serialize)]
+struct MoveRecord<'a> {
+    source: String,
+    dest: String,
+    folder: &'a str,
+    file: &'a str,
+    reason: MoveReason,
+    mode: TransferMode,
+    compilation: Option<crate::albums::CompilationRule>,
+    duplicate_of: Option<String>,
+    tags: Option<&'a TrackMetadata>,
+    /// Why the file is unsorted, if it is.
+    unsorted_reason: Option<UnsortedReason>,
+    /// What is wrong with an unreadable or damaged file.
+    damage: Option<&'a Damage>,
+    /// The pattern `tags` were read from the path with, if any.
+    inferred_from: Option<&'a str>,
+    /// Tags `--write-tags` changes in the file.
+    tag_changes: &'a [TagChange],
+}
+
+impl<'a> MoveRecord<'a> {
+    fn new(m: &'a PlannedMove) -> MoveRecord<'a> {
+        MoveRecord {
+            source: lossy(&m.source),
+            dest: lossy(&m.dest),
+            folder: &m.folder_name,
+            file: &m.file_name,
+            reason: m.reason(),
+            mode: m.mode,
+            compilation: m.compilation,
+            duplicate_of: m.duplicate_of.as_ref().map(|d| lossy(&d.path)),
+            tags: m.meta.as_ref(),
+            unsorted_reason: m.unsorted,
+            damage: m.damage.as_ref(),
+            inferred_from: m.inferred_from.as_deref(),
+            tag_changes: &m.tag_changes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u32,
+    moves: Vec<MoveRecord<'a>>,
+    summary: &'a Summary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<&'a [ExecResult]>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Line<'a> {
+    Move(MoveRecord<'a>),
+    Summary(&'a Summary),
+    Result(&'a ExecResult),
+}
+
+/// Write the plan in a machine-readable format. `results` is set after
+/// `--execute` and holds one entry per attempted move, in the order of
+/// `moves` (see [`PlannedMove::is_pending`]).
+pub fn write_plan<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    moves: &[PlannedMove],
+    summary: &Summary,
+    results: Option<&[ExecResult]>,
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => {
+            let doc = Document {
+                version: FORMAT_VERSION,
+                moves: moves.iter().map(MoveRecord::new).collect(),
+                summary,
+                results,
+            };
+            serde_json::to_writer_pretty(&mut *out, &doc)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for m in moves {
+                serde_json::to_writer(&mut *out, &Line::Move(MoveRecord::new(m)))?;
+                writeln!(out)?;
+            }
+            serde_json::to_writer(&mut *out, &Line::Summary(summary))?;
+            writeln!(out)?;
+            for r in results.unwrap_or_default() {
+                serde_json::to_writer(&mut *out, &Line::Result(r))?;
+                writeln!(out)?;
+            }
+        }
+        OutputFormat::Csv => write_csv(out, moves, results)?,
+    }
+    Ok(())
+}
+
+fn write_csv<W: Write>(
+    out: &mut W,
+    moves: &[PlannedMove],
+    results: Option<&[ExecResult]>,
+) -> Result<()> {
+    writeln!(out, "{}", CSV_HEADER.join(","))?;
+
+    let mut results = results.unwrap_or_default().iter();
+    for m in moves {
+        let source = lossy(&m.source);
+        let result = if m.is_pending() {
+            results.next()
+        } else {
+            None
+        };
+        let meta = m.meta.as_ref();
+        let number = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+
+        let row = [
+            source.clone(),
+            lossy(&m.dest),
+            m.folder_name.clone(),
+            m.file_name.clone(),
+            enum_name(&m.reason()),
+            m.mode.to_string(),
+            m.compilation.map(|c| enum_name(&c)).unwrap_or_default(),
+            meta.map(|t| t.artist.clone()).unwrap_or_default(),
+            meta.map(|t| t.album.clone()).unwrap_or_default(),
+            meta.and_then(|t| t.album_artist.clone()).unwrap_or_default(),
+            meta.and_then(|t| t.title.clone()).unwrap_or_default(),
+            number(meta.and_then(|t| t.track_number)),
+            number(meta.and_then(|t| t.disc_number)),
+            number(meta.and_then(|t| t.year)),
+            m.unsorted.map(|r| enum_name(&r)).unwrap_or_default(),
+            m.damage.as_ref().map(Damage::to_string).unwrap_or_default(),
+            m.inferred_from.clone().unwrap_or_default(),
+            m.duplicate_of
+                .as_ref()
+                .map(|d| lossy(&d.path))
+                .unwrap_or_default(),
+            result.map(|r| enum_name(&r.status)).unwrap_or_default(),
+            result
+                .and_then(|r| r.applied_mode)
+                .map(|m| m.to_string())
+                .unwrap_or_default(),
+            result.and_then(|r| r.error.clone()).unwrap_or_default(),
+        ];
+        let fields: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// The serialized name of a unit enum variant, e.g. "renamed-for-conflict".
+fn enum_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+fn lossy(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sorting::UNSORTED_FOLDER;
+    use crate::test_util::{mpeg_frames, temp_dir};
+    use crate::{Executor, Planner};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn sample_moves() -> Vec<PlannedMove> {
+        vec![
+            PlannedMove {
+                source: PathBuf::from("/in/a.mp3"),
+                dest: PathBuf::from("/lib/A - B/01 - Song, Live.mp3"),
+                folder_name: "A - B".to_string(),
+                file_name: "01 - Song, Live.mp3".to_string(),
+                meta: Some(TrackMetadata {
+                    artist: "A".to_string(),
+                    album: "B".to_string(),
+                    title: Some("Song, Live".to_string()),
+                    track_number: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            PlannedMove {
+                source: PathBuf::from("/in/x.mp3"),
+                dest: PathBuf::from("/lib/_Unsorted/x.mp3"),
+                folder_name: UNSORTED_FOLDER.to_string(),
+                file_name: "x.mp3".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn summary_counts() {
+        let summary = Summary::of(&sample_moves());
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.folders, 1);
+        assert_eq!(summary.unsorted, 1);
+        assert_eq!(summary.to_transfer, 2);
+    }
+
+    #[test]
+    fn json_document_shape() {
+        let moves = sample_moves();
+        let mut out = Vec::new();
+        write_plan(&mut out, OutputFormat::Json, &moves, &Summary::of(&moves), None).unwrap();
+
+        let doc: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(doc["version"], 1);
+        assert_eq!(doc["moves"][0]["reason"], "tagged");
+        assert_eq!(doc["moves"][0]["tags"]["artist"], "A");
+        assert_eq!(doc["moves"][1]["reason"], "unsorted");
+        assert!(doc["moves"][1]["tags"].is_null());
+        assert_eq!(doc["summary"]["unsorted"], 1);
+        assert!(doc.get("results").is_none());
+    }
+
+    #[test]
+    fn corrupt_files_carry_their_damage() {
+        let moves = vec![PlannedMove {
+            source: PathBuf::from("/in/cut.mp3"),
+            dest: PathBuf::from("/lib/_Corrupt/cut.mp3"),
+            folder_name: "_Corrupt".to_string(),
+            file_name: "cut.mp3".to_string(),
+            damage: Some(Damage {
+                offset: Some(1251),
+                error: "last frame is cut off".to_string(),
+            }),
+            ..Default::default()
+        }];
+        let summary = Summary::of(&moves);
+        assert_eq!((summary.corrupt, summary.unsorted), (1, 0));
+
+        let mut out = Vec::new();
+        write_plan(&mut out, OutputFormat::Json, &moves, &summary, None).unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(doc["moves"][0]["reason"], "corrupt");
+        assert_eq!(doc["moves"][0]["damage"]["offset"], 1251);
+
+        let mut out = Vec::new();
+        write_plan(&mut out, OutputFormat::Csv, &moves, &summary, None).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(",last frame is cut off (at byte 1251),"));
+    }
+
+    #[test]
+    fn ndjson_lines_are_typed() {
+        let moves = sample_moves();
+        let results = vec![ExecResult::new(
+            &moves[0],
+            &Err(anyhow::anyhow!("disk full")),
+        )];
+        let mut out = Vec::new();
+        write_plan(
+            &mut out,
+            OutputFormat::Ndjson,
+            &moves,
+            &Summary::of(&moves),
+            Some(&results),
+        )
+        .unwrap();
+
+        let types: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let v: serde_json::Value = serde_json::from_str(l).unwrap();
+                v["type"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(types, vec!["move", "move", "summary", "result"]);
+    }
+
+    #[test]
+    fn csv_quotes_and_results() {
+        let moves = sample_moves();
+        let results = vec![
+            ExecResult::new(&moves[0], &Err(anyhow::anyhow!("disk full"))),
+            ExecResult::new(&moves[1], &Ok(TransferMode::Copy)),
+        ];
+        let mut out = Vec::new();
+        write_plan(
+            &mut out,
+            OutputFormat::Csv,
+            &moves,
+            &Summary::of(&moves),
+            Some(&results),
+        )
+        .unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER.join(","));
+        assert!(lines[1].contains("\"/lib/A - B/01 - Song, Live.mp3\""));
+        assert!(lines[1].ends_with(",error,,disk full"));
+        assert!(lines[2].contains(",unsorted,move,"));
+        assert!(lines[2].ends_with(",ok,copy,"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn csv_after_executing_links_reports_what_was_planned() {
+        let tmp = temp_dir("output_executed_links");
+        fs::create_dir_all(tmp.join("in")).unwrap();
+        fs::write(tmp.join("in/a.mp3"), mpeg_frames()).unwrap();
+        fs::write(tmp.join("in/b.mp3"), mpeg_frames()).unwrap();
+
+        let plan = Planner::new(tmp.join("in"))
+            .dest(tmp.join("lib"))
+            .mode(TransferMode::Hardlink)
+            .tag_cache(None)
+            .hash_cache(None)
+            .plan()
+            .unwrap();
+        let summary = Summary::of(&plan.moves);
+        assert_eq!((summary.to_transfer, summary.in_place), (2, 0));
+        let results = Executor::new()
+            .execute(&plan.target, &plan.moves)
+            .unwrap()
+            .results;
+
+        let mut out = Vec::new();
+        write_plan(
+            &mut out,
+            OutputFormat::Csv,
+            &plan.moves,
+            &summary,
+            Some(&results),
+        )
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let rows: Vec<&str> = text.lines().skip(1).collect();
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            assert!(row.contains(",unsorted,hardlink,"), "{}", row);
+            assert!(row.ends_with(",ok,hardlink,"), "{}", row);
+        }
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn csv_field_escaping() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -0,0 +1,230 @@
+use crate::journal::file_stamp;
+use crate::sorting::{Conflict, PlannedMove, TransferMode};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever a field is removed or changes meaning.
+const PLAN_VERSION: u32 = 1;
+
+/// A saved plan: the transfers of a dry run, to be reviewed (and possibly
+/// hand-edited) and then executed with `tagmv apply`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub version: u32,
+    /// Library root the plan was made for; the journal is written there.
+    pub target: PathBuf,
+    pub entries: Vec<PlanEntry>,
+}
+
+/// One planned transfer. `size` and `mtime` describe the source at planning
+/// time so that `apply` can detect files that changed in the meantime.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    pub mode: TransferMode,
+    pub size: u64,
+    pub mtime: u64,
+    /// Replace whatever is at `dest` (`--on-conflict overwrite`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub overwrite: bool,
+}
+
+impl PlanFile {
+    /// Build a plan from resolved moves. Moves that are already in place or
+    /// skipped for a conflict are left out, since there is nothing to apply
+    /// for them.
+    pub fn new(target: &Path, moves: &[PlannedMove]) -> Result<PlanFile> {
+        let mut entries = Vec::new();
+        for m in moves.iter().filter(|m| m.needs_transfer()) {
+            let (size, mtime) = file_stamp(&m.source)?;
+            entries.push(PlanEntry {
+                source: m.source.clone(),
+                dest: m.dest.clone(),
+                mode: m.mode,
+                size,
+                mtime,
+                overwrite: m.conflict == Some(Conflict::Overwrites),
+            });
+        }
+
+        Ok(PlanFile {
+            version: PLAN_VERSION,
+            target: target.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<PlanFile> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan: {}", path.display()))?;
+        let plan: PlanFile = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid plan file: {}", path.display()))?;
+        if plan.version != PLAN_VERSION {
+            bail!(
+                "Unsupported plan version {} in {} (expected {})",
+                plan.version,
+                path.display(),
+                PLAN_VERSION
+            );
+        }
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        fs::write(path, text + "\n")
+            .with_context(|| format!("Failed to write plan: {}", path.display()))
+    }
+
+    /// Check every entry against the filesystem. Returns one result per
+    /// entry, in order: the move to execute, or why the entry is stale.
+    pub fn validate(&self) -> Vec<Result<PlannedMove>> {
+        let mut claimed = HashSet::new();
+        // Sources of earlier entries, which are gone by the time later
+        // entries run (a file moved out of the way for a better copy)
+        let mut vacated = HashSet::new();
+        self.entries
+            .iter()
+            .map(|entry| {
+                // Two entries writing to the same place (e.g. after a hand
+                // edit): only the first one wins
+                if !claimed.insert(entry.dest.clone()) {
+                    bail!("Destination is used by an earlier entry");
+                }
+                let checked = entry.validate(vacated.contains(&entry.dest));
+                if checked.is_ok() && entry.mode == TransferMode::Move {
+                    vacated.insert(entry.source.clone());
+                }
+                checked
+            })
+            .collect()
+    }
+}
+
+impl PlanEntry {
+    fn validate(&self, vacated: bool) -> Result<PlannedMove> {
+        if !self.dest.is_absolute() {
+            bail!("Destination must be an absolute path");
+        }
+        let stamp = file_stamp(&self.source).context("Source no longer exists")?;
+        if stamp != (self.size, self.mtime) {
+            bail!("Source changed since the plan was made");
+        }
+        if !self.overwrite && !vacated && self.dest.symlink_metadata().is_ok() {
+            bail!("Destination already exists");
+        }
+
+        Ok(PlannedMove {
+            source: self.source.clone(),
+            dest: self.dest.clone(),
+            folder_name: self
+                .dest
+                .parent()
+                .and_then(|p| p.file_name())
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            file_name: self
+                .dest
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            mode: self.mode,
+            conflict: self.overwrite.then_some(Conflict::Overwrites),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn planned(tmp: &Path, name: &str) -> PlannedMove {
+        let source = tmp.join("in").join(name);
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "audio").unwrap();
+        PlannedMove {
+            source,
+            dest: tmp.join("lib/A - B").join(name),
+            folder_name: "A - B".to_string(),
+            file_name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip_and_validate() {
+        let tmp = temp_dir("plan_round_trip");
+
+        let moves = vec![planned(&tmp, "a.mp3"), planned(&tmp, "b.mp3")];
+        let path = tmp.join("plan.json");
+        PlanFile::new(&tmp, &moves).unwrap().save(&path).unwrap();
+
+        let plan = PlanFile::load(&path).unwrap();
+        assert_eq!(plan.entries.len(), 2);
+        let checked = plan.validate();
+        assert!(checked.iter().all(|r| r.is_ok()));
+        assert_eq!(checked[0].as_ref().unwrap().folder_name, "A - B");
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn stale_entries_are_refused() {
+        let tmp = temp_dir("plan_stale");
+
+        let moves = vec![
+            planned(&tmp, "changed.mp3"),
+            planned(&tmp, "gone.mp3"),
+            planned(&tmp, "taken.mp3"),
+            planned(&tmp, "ok.mp3"),
+        ];
+        let mut plan = PlanFile::new(&tmp, &moves).unwrap();
+        plan.entries[3].dest = plan.entries[2].dest.clone();
+
+        fs::write(&moves[0].source, "re-encoded audio").unwrap();
+        fs::remove_file(&moves[1].source).unwrap();
+        fs::create_dir_all(moves[2].dest.parent().unwrap()).unwrap();
+        fs::write(&moves[2].dest, "other").unwrap();
+
+        let checked = plan.validate();
+        assert!(checked.iter().all(|r| r.is_err()));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn taken_destinations_that_are_planned_to_be_freed() {
+        let tmp = temp_dir("plan_conflicts");
+
+        let mut moves = vec![
+            planned(&tmp, "old.flac"),
+            planned(&tmp, "new.flac"),
+            planned(&tmp, "replacement.mp3"),
+        ];
+        // `old.flac` sits where `new.flac` goes and is moved away first
+        fs::create_dir_all(moves[1].dest.parent().unwrap()).unwrap();
+        fs::rename(&moves[0].source, &moves[1].dest).unwrap();
+        moves[0].source = moves[1].dest.clone();
+        moves[0].dest = tmp.join("lib/_Duplicates/old.flac");
+        // `replacement.mp3` overwrites an existing file
+        fs::write(&moves[2].dest, "older").unwrap();
+        moves[2].conflict = Some(Conflict::Overwrites);
+
+        let path = tmp.join("plan.json");
+        PlanFile::new(&tmp, &moves).unwrap().save(&path).unwrap();
+        let checked = PlanFile::load(&path).unwrap().validate();
+        assert!(checked.iter().all(|r| r.is_ok()));
+        assert_eq!(
+            checked[2].as_ref().unwrap().conflict,
+            Some(Conflict::Overwrites)
+        );
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
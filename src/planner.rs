@@ -0,0 +1,864 @@
+use crate::albums;
+use crate::cache::{HashCache, TagCache};
+use crate::companions::plan_companions;
+use crate::dedupe::{find_duplicates, DedupeAction};
+use crate::infer::{infer, merge, Pattern};
+use crate::jobs;
+use crate::output::Summary;
+use crate::scan::{scan_files, sniff_files, AUDIO_EXTENSIONS};
+use crate::sorting::{
+    Conflict, ConflictCase, ConflictPolicy, DuplicateOf, Layout, PlannedMove, TransferMode,
+};
+use crate::tags::{
+    corrected_extension, read_partial_tags, read_tags, sortable, tag_changes, TrackMetadata,
+    UnsortedReason,
+};
+use crate::template::{DiscLayout, Template};
+use crate::validate::{validate, CorruptAction, Damage};
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Builder for a [`Plan`]: which files to scan and how to lay them out.
+///
+/// ```no_run
+/// use tagmv::{Planner, TransferMode};
+///
+/// let plan = Planner::new("/home/me/Downloads")
+///     .recursive(true)
+///     .dest("/srv/music")
+///     .mode(TransferMode::Hardlink)
+///     .plan()?;
+/// println!("{} files to transfer", plan.summary().to_transfer);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Planner {
+    root: PathBuf,
+    recursive: bool,
+    dest: Option<PathBuf>,
+    mode: TransferMode,
+    template: Option<Template>,
+    compilation_template: Option<Template>,
+    disc_layout: DiscLayout,
+    extensions: Vec<String>,
+    layout: Layout,
+    ask: Option<Ask>,
+    dedupe: Option<DedupeAction>,
+    hash_cache: Option<PathBuf>,
+    tag_cache: Option<PathBuf>,
+    companions: bool,
+    sniff: bool,
+    infer: Vec<Pattern>,
+    write_tags: bool,
+    on_corrupt: CorruptAction,
+    validate: bool,
+    jobs: usize,
+}
+
+/// What [`read_tags`] found in one file.
+type TagsRead = Result<TrackMetadata, UnsortedReason>;
+
+/// The [`Planner::on_ask`] callback.
+#[derive(Clone)]
+struct Ask(Arc<dyn Fn(&ConflictCase<'_>) -> ConflictPolicy + Send + Sync>);
+
+impl fmt::Debug for Ask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Ask(..)")
+    }
+}
+
+/// The resolved destinations for one scan, with conflicts already resolved.
+#[derive(Debug)]
+pub struct Plan {
+    /// The scanned directory (canonicalized).
+    pub root: PathBuf,
+    /// Library root the files are filed into.
+    pub target: PathBuf,
+    /// One entry per scanned file, in path order. A file already in the
+    /// library that a better copy displaces gets an entry of its own, right
+    /// before the move that displaces it. Companion files come last.
+    pub moves: Vec<PlannedMove>,
+}
+
+impl Plan {
+    pub fn summary(&self) -> Summary {
+        Summary::of(&self.moves)
+    }
+}
+
+impl Planner {
+    /// Plan for the audio files in `root`, filed into `root` itself.
+    pub fn new(root: impl Into<PathBuf>) -> Planner {
+        Planner {
+            root: root.into(),
+            recursive: false,
+            dest: None,
+            mode: TransferMode::default(),
+            template: None,
+            compilation_template: None,
+            disc_layout: DiscLayout::default(),
+            extensions: AUDIO_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            layout: Layout::default(),
+            ask: None,
+            dedupe: None,
+            hash_cache: None,
+            tag_cache: None,
+            companions: true,
+            sniff: false,
+            infer: Vec::new(),
+            write_tags: false,
+            on_corrupt: CorruptAction::default(),
+            validate: false,
+            jobs: jobs::default_jobs(),
+        }
+    }
+
+    /// Scan subdirectories too.
+    pub fn recursive(mut self, recursive: bool) -> Planner {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Library root to file into; it does not need to exist yet.
+    pub fn dest(mut self, dest: impl Into<PathBuf>) -> Planner {
+        self.dest = Some(dest.into());
+        self
+    }
+
+    pub fn mode(mut self, mode: TransferMode) -> Planner {
+        self.mode = mode;
+        self
+    }
+
+    /// Layout for regular albums (defaults to the disc layout's template).
+    pub fn template(mut self, template: Template) -> Planner {
+        self.template = Some(template);
+        self
+    }
+
+    /// Layout for compilations (defaults to the disc layout's template).
+    pub fn compilation_template(mut self, template: Template) -> Planner {
+        self.compilation_template = Some(template);
+        self
+    }
+
+    /// Multi-disc layout used by the default templates.
+    pub fn disc_layout(mut self, layout: DiscLayout) -> Planner {
+        self.disc_layout = layout;
+        self
+    }
+
+    /// File extensions to scan for, without the dot.
+    pub fn extensions<I, S>(mut self, extensions: I) -> Planner
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Unsorted folder, sanitization and conflict rules.
+    pub fn layout(mut self, layout: Layout) -> Planner {
+        self.layout = layout;
+        self
+    }
+
+    /// Decides each conflict when the layout's policy is
+    /// [`ConflictPolicy::Ask`]. Without it, those conflicts are left
+    /// undecided and their files stay where they are.
+    pub fn on_ask(
+        mut self,
+        f: impl Fn(&ConflictCase<'_>) -> ConflictPolicy + Send + Sync + 'static,
+    ) -> Planner {
+        self.ask = Some(Ask(Arc::new(f)));
+        self
+    }
+
+    /// Look for files whose audio is already in the library or earlier in
+    /// the plan, ignoring tags.
+    pub fn dedupe(mut self, action: Option<DedupeAction>) -> Planner {
+        self.dedupe = action;
+        self
+    }
+
+    /// Where dedupe keeps payload hashes between runs, e.g.
+    /// [`HashCache::default_path`]; `None` (the default) hashes from scratch
+    /// every time.
+    pub fn hash_cache(mut self, path: Option<PathBuf>) -> Planner {
+        self.hash_cache = path;
+        self
+    }
+
+    /// Where tags are kept between runs, so unchanged files are not read
+    /// again, e.g. [`TagCache::default_path`]; `None` (the default) reads
+    /// every file.
+    pub fn tag_cache(mut self, path: Option<PathBuf>) -> Planner {
+        self.tag_cache = path;
+        self
+    }
+
+    /// Take cover art, cue sheets, logs and lyrics along with the audio
+    /// files (on by default; see [`plan_companions`]).
+    pub fn companions(mut self, companions: bool) -> Planner {
+        self.companions = companions;
+        self
+    }
+
+    /// Detect audio files by their content: files with a wrong or missing
+    /// extension are scanned too, and get the extension of their format.
+    pub fn sniff(mut self, sniff: bool) -> Planner {
+        self.sniff = sniff;
+        self
+    }
+
+    /// Patterns that read tags out of the path of files without usable
+    /// tags, tried in order (see [`Pattern`]); empty by default, leaving
+    /// those files unsorted.
+    pub fn infer(mut self, patterns: Vec<Pattern>) -> Planner {
+        self.infer = patterns;
+        self
+    }
+
+    /// Plan to write the tags each file was filed by into the file, where
+    /// they differ from what it has: inferred tags, and album artists and
+    /// disc totals resolved for the whole album. Planning fails if the
+    /// mode is hardlink or symlink, since that would rewrite the sources.
+    pub fn write_tags(mut self, write_tags: bool) -> Planner {
+        self.write_tags = write_tags;
+        self
+    }
+
+    /// What happens to files that cannot be read (and, with
+    /// [`Planner::validate`], damaged ones); they are quarantined in the
+    /// corrupt folder by default.
+    pub fn on_corrupt(mut self, action: CorruptAction) -> Planner {
+        self.on_corrupt = action;
+        self
+    }
+
+    /// Walk the audio stream of every file (see [`validate`]), so files
+    /// that are truncated or damaged but whose tags read fine are handled
+    /// like unreadable ones. Reads every file in full.
+    pub fn validate(mut self, validate: bool) -> Planner {
+        self.validate = validate;
+        self
+    }
+
+    /// Threads used to list directories, read tags and sniff formats;
+    /// defaults to one per CPU. The plan is the same for any number.
+    pub fn jobs(mut self, jobs: usize) -> Planner {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Scan, read tags and compute every destination. Nothing is touched on
+    /// disk.
+    pub fn plan(&self) -> Result<Plan> {
+        let root = std::fs::canonicalize(&self.root)
+            .with_context(|| format!("Cannot resolve path: {}", self.root.display()))?;
+        if !root.is_dir() {
+            bail!("Not a directory: {}", root.display());
+        }
+        if self.write_tags && matches!(self.mode, TransferMode::Hardlink | TransferMode::Symlink) {
+            bail!(
+                "Cannot write tags with --mode {}: the destinations share their data with the sources, which would change too",
+                self.mode
+            );
+        }
+
+        let target = match &self.dest {
+            Some(d) => resolve_target(d)?,
+            None => root.clone(),
+        };
+
+        let skip_dirs = [
+            self.layout.unsorted_folder.as_str(),
+            self.layout.duplicates_folder.as_str(),
+            self.layout.corrupt_folder.as_str(),
+        ];
+        let files = if self.sniff {
+            sniff_files(
+                &root,
+                self.recursive,
+                &self.extensions,
+                &skip_dirs,
+                self.jobs,
+            )?
+        } else {
+            scan_files(
+                &root,
+                self.recursive,
+                &self.extensions,
+                &skip_dirs,
+                self.jobs,
+            )?
+        };
+
+        let (mut metas, corrections): (Vec<_>, Vec<_>) =
+            self.read_files(&files).into_iter().unzip();
+        let damage = self.find_damage(&files, &mut metas);
+
+        // Fall back to the path for files without usable tags (but not for
+        // unreadable ones), before albums are resolved so inferred tracks
+        // are grouped like tagged ones. Files that still lack an artist or
+        // album stay unsorted
+        let inferred: Vec<Option<&Pattern>> = files
+            .iter()
+            .zip(&mut metas)
+            .map(|(file, meta)| {
+                if matches!(meta, Ok(_) | Err(UnsortedReason::Corrupt)) {
+                    return None;
+                }
+                let relative = file.strip_prefix(&root).unwrap_or(file);
+                let (found, pattern) = infer(relative, &self.infer)?;
+                *meta = sortable(match read_partial_tags(file) {
+                    Ok(tags) => merge(tags, found),
+                    Err(_) => found,
+                });
+                meta.is_ok().then_some(pattern)
+            })
+            .collect();
+        let mut tracks: Vec<(&PathBuf, Result<_, _>)> = files.iter().zip(metas).collect();
+
+        // Keep albums together before their destinations are computed
+        albums::resolve_album_artists(
+            tracks
+                .iter_mut()
+                .filter_map(|(file, meta)| Some((file.as_path(), meta.as_mut().ok()?))),
+        );
+        albums::resolve_disc_totals(
+            tracks
+                .iter_mut()
+                .filter_map(|(file, meta)| Some((file.as_path(), meta.as_mut().ok()?))),
+        );
+
+        let (template, compilation_template) = self.templates();
+
+        let tagged: Vec<(&Path, &_)> = tracks
+            .iter()
+            .filter_map(|(file, meta)| Some((file.as_path(), meta.as_ref().ok()?)))
+            .collect();
+        let mut compilations = albums::detect_compilations(&tagged).into_iter();
+
+        let mut moves: Vec<PlannedMove> = Vec::new();
+
+        let files = tracks.iter().zip(corrections).zip(inferred).zip(damage);
+        for ((((file, meta), corrected), inferred), damage) in files {
+            let mut planned = match meta {
+                Ok(meta) => {
+                    let rule = compilations.next().flatten();
+                    let template = match rule {
+                        Some(_) => &compilation_template,
+                        None => &template,
+                    };
+                    self.layout
+                        .destination(&target, file, meta, template)
+                        .map(|mut planned| {
+                            planned.compilation = rule;
+                            planned
+                        })
+                        .ok_or(UnsortedReason::MissingFields)
+                }
+                Err(reason) => Err(*reason),
+            }
+            .unwrap_or_else(|reason| match (damage, self.on_corrupt) {
+                (Some(damage), CorruptAction::Quarantine) => {
+                    self.layout.corrupt_destination(&target, file, damage)
+                }
+                (Some(damage), CorruptAction::Leave) => {
+                    let mut planned = self.layout.corrupt_destination(&target, file, damage);
+                    planned.dest = file.to_path_buf();
+                    planned
+                }
+                (damage, _) => {
+                    let mut planned = self.layout.unsorted_destination(&target, file, reason);
+                    planned.damage = damage;
+                    planned
+                }
+            });
+            planned.mode = self.mode;
+            if planned.meta.is_some() {
+                planned.inferred_from = inferred.map(Pattern::to_string);
+            }
+            let left = planned.damage.is_some() && self.on_corrupt == CorruptAction::Leave;
+            if let Some(ext) = corrected.filter(|_| !left) {
+                planned.correct_extension(ext);
+            }
+            moves.push(planned);
+        }
+
+        if let Some(action) = self.dedupe {
+            self.mark_duplicates(&target, &mut moves, action)?;
+        }
+
+        let ask = self.ask.as_ref().map(|a| &*a.0 as _);
+        self.layout.resolve_conflicts(&target, &mut moves, ask);
+
+        if self.write_tags {
+            let changes = jobs::map(&moves, self.jobs, |m| match &m.meta {
+                Some(meta) if !m.is_skipped() && m.conflict != Some(Conflict::Displaced) => {
+                    tag_changes(&m.source, meta)
+                }
+                _ => Vec::new(),
+            });
+            for (m, changes) in moves.iter_mut().zip(changes) {
+                m.tag_changes = changes;
+            }
+        }
+
+        // Companions go where their audio ended up, so they are planned
+        // after its conflicts are settled. They never collide with audio
+        // files, are not worth a prompt and never replace what the library
+        // already has: a taken destination is skipped under overwrite and
+        // ask.
+        if self.companions {
+            let mut companions = plan_companions(&root, &target, &moves)?;
+            let mut layout = self.layout.clone();
+            if matches!(
+                layout.on_conflict,
+                ConflictPolicy::Overwrite | ConflictPolicy::Ask
+            ) {
+                layout.on_conflict = ConflictPolicy::Skip;
+            }
+            layout.resolve_conflicts(&target, &mut companions, None);
+            moves.extend(companions);
+        }
+
+        Ok(Plan {
+            root,
+            target,
+            moves,
+        })
+    }
+
+    /// `m` laid out again with `meta` in place of its tags, for callers that
+    /// correct the tags of planned files. The destination is not checked for
+    /// conflicts. `None` if the template needs a field `meta` does not have.
+    pub fn relayout(
+        &self,
+        target: &Path,
+        m: &PlannedMove,
+        meta: &TrackMetadata,
+    ) -> Option<PlannedMove> {
+        let (template, compilation_template) = self.templates();
+        let template = match m.compilation {
+            Some(_) => &compilation_template,
+            None => &template,
+        };
+        let mut planned = self.layout.destination(target, &m.source, meta, template)?;
+        planned.mode = m.mode;
+        planned.compilation = m.compilation;
+        if let Some(ext) = &m.corrected_extension {
+            planned.correct_extension(ext);
+        }
+        if self.write_tags {
+            planned.tag_changes = tag_changes(&m.source, meta);
+        }
+        Some(planned)
+    }
+
+    /// The template for regular albums and the one for compilations.
+    fn templates(&self) -> (Template, Template) {
+        let template = self
+            .template
+            .clone()
+            .unwrap_or_else(|| self.disc_layout.template());
+        let compilation_template = self
+            .compilation_template
+            .clone()
+            .unwrap_or_else(|| self.disc_layout.compilation_template());
+        (template, compilation_template)
+    }
+
+    /// Tags (through the tag cache) and, when sniffing, extension
+    /// corrections for every file.
+    fn read_files(&self, files: &[PathBuf]) -> Vec<(TagsRead, Option<&'static str>)> {
+        let mut cache = self.tag_cache.as_deref().map(TagCache::load);
+
+        // Reading files is most of the work. The results come back in path
+        // order, so the plan does not depend on which thread was faster
+        let read = jobs::map(files, self.jobs, |file| {
+            let cached = cache.as_ref().and_then(|c| c.get(file)).cloned();
+            let fresh = cached.is_none();
+            let meta = cached.unwrap_or_else(|| read_tags(file));
+            let corrected = if self.sniff {
+                corrected_extension(file)
+            } else {
+                None
+            };
+            (meta, corrected, fresh)
+        });
+
+        if let Some(cache) = cache.as_mut() {
+            for (file, (meta, _, fresh)) in files.iter().zip(&read) {
+                if *fresh {
+                    cache.insert(file, meta.clone());
+                }
+            }
+            // Like the hash cache, only a shortcut
+            let _ = cache.save();
+        }
+        read.into_iter()
+            .map(|(meta, corrected, _)| (meta, corrected))
+            .collect()
+    }
+
+    /// What is wrong with each unreadable file and, when validating, with
+    /// each damaged one. Damaged files count as unreadable from here on.
+    fn find_damage(&self, files: &[PathBuf], metas: &mut [TagsRead]) -> Vec<Option<Damage>> {
+        let checks: Vec<(&PathBuf, bool)> = files
+            .iter()
+            .zip(metas.iter())
+            .map(|(file, meta)| (file, matches!(meta, Err(UnsortedReason::Corrupt))))
+            .collect();
+        let found = jobs::map(&checks, self.jobs, |&(file, unreadable)| {
+            let damage = (self.validate || unreadable)
+                .then(|| validate(file))
+                .flatten();
+            match damage {
+                None if unreadable => Some(Damage::new(UnsortedReason::Corrupt)),
+                damage => damage,
+            }
+        });
+
+        for (meta, damage) in metas.iter_mut().zip(&found) {
+            if damage.is_some() {
+                *meta = Err(UnsortedReason::Corrupt);
+            }
+        }
+        found
+    }
+
+    /// Compare the planned files with each other and with the audio files
+    /// already under `target`, and mark (or quarantine) exact duplicates.
+    fn mark_duplicates(
+        &self,
+        target: &Path,
+        moves: &mut [PlannedMove],
+        action: DedupeAction,
+    ) -> Result<()> {
+        let library = if target.is_dir() {
+            scan_files(
+                target,
+                true,
+                &self.extensions,
+                &[&self.layout.duplicates_folder, &self.layout.corrupt_folder],
+                self.jobs,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        let mut cache = match &self.hash_cache {
+            Some(path) => HashCache::load(path),
+            None => HashCache::in_memory(),
+        };
+        let found = find_duplicates(moves, &library, &mut cache);
+        // The cache is only a shortcut; failing to save it costs time, not
+        // correctness
+        let _ = cache.save();
+
+        for (m, original) in moves.iter_mut().zip(found) {
+            let Some(path) = original else { continue };
+            let quarantined = action == DedupeAction::Quarantine;
+            if quarantined {
+                self.layout.quarantine(target, m);
+            }
+            m.duplicate_of = Some(DuplicateOf { path, quarantined });
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the `--dest` library root. It may not exist yet; it is created on
+/// the first move.
+fn resolve_target(dest: &Path) -> Result<PathBuf> {
+    if dest.exists() {
+        let target = std::fs::canonicalize(dest)
+            .with_context(|| format!("Cannot resolve path: {}", dest.display()))?;
+        if !target.is_dir() {
+            bail!("Destination is not a directory: {}", target.display());
+        }
+        return Ok(target);
+    }
+
+    if dest.is_absolute() {
+        Ok(dest.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()
+            .context("Could not determine current directory")?
+            .join(dest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sorting::{MoveReason, CORRUPT_FOLDER, UNSORTED_FOLDER};
+    use crate::test_util::{mpeg_frames, temp_dir};
+    use std::fs;
+
+    #[test]
+    fn plans_untagged_and_unreadable_files() {
+        let tmp = temp_dir("planner");
+        fs::create_dir_all(tmp.join("sub")).unwrap();
+        fs::write(tmp.join("b.mp3"), mpeg_frames()).unwrap();
+        fs::write(tmp.join("a.flac"), "not audio").unwrap();
+        fs::write(tmp.join("notes.txt"), "text").unwrap();
+        fs::write(tmp.join("sub/c.mp3"), mpeg_frames()).unwrap();
+
+        let plan = Planner::new(&tmp)
+            .tag_cache(None)
+            .dest(tmp.join("lib"))
+            .mode(TransferMode::Copy)
+            .plan()
+            .unwrap();
+        let root = fs::canonicalize(&tmp).unwrap();
+        assert_eq!(plan.root, root);
+        assert_eq!(plan.target, root.join("lib"));
+
+        let names: Vec<&str> = plan.moves.iter().map(|m| m.file_name.as_str()).collect();
+        assert_eq!(names, vec!["a.flac", "b.mp3"]);
+        let folders: Vec<&str> = plan.moves.iter().map(|m| m.folder_name.as_str()).collect();
+        assert_eq!(folders, vec![CORRUPT_FOLDER, UNSORTED_FOLDER]);
+        assert!(plan.moves.iter().all(|m| m.mode == TransferMode::Copy));
+
+        let recursive = Planner::new(&tmp)
+            .tag_cache(None)
+            .recursive(true)
+            .plan()
+            .unwrap();
+        assert_eq!(recursive.moves.len(), 3);
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn tags_come_from_the_cache_until_the_file_changes() {
+        let tmp = temp_dir("planner_tag_cache");
+        let song = fs::canonicalize(&tmp).unwrap().join("song.mp3");
+        fs::write(&song, "not audio").unwrap();
+
+        let cache_path = tmp.join("cache/tags.json");
+        let mut cache = TagCache::load(&cache_path);
+        let meta = TrackMetadata {
+            artist: "Cached".to_string(),
+            album: "Album".to_string(),
+            ..Default::default()
+        };
+        cache.insert(&song, Ok(meta));
+        cache.save().unwrap();
+
+        let folder = || {
+            let plan = Planner::new(&tmp)
+                .tag_cache(Some(cache_path.clone()))
+                .plan()
+                .unwrap();
+            plan.moves[0].folder_name.clone()
+        };
+        assert_eq!(folder(), "Cached - Album");
+
+        fs::write(&song, "changed, still not audio").unwrap();
+        assert_eq!(folder(), CORRUPT_FOLDER);
+        // The new result was cached in its place
+        let cached = TagCache::load(&cache_path).get(&song).cloned();
+        assert_eq!(cached.and_then(Result::err), Some(UnsortedReason::Corrupt));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn sniffing_finds_misnamed_files() {
+        let tmp = temp_dir("planner_sniff");
+        let flac = b"fLaC\x80\x00\x00\x22";
+        fs::write(tmp.join("voice.bin"), flac).unwrap();
+        fs::write(tmp.join("song.mp3"), flac).unwrap();
+        fs::write(tmp.join("notes.txt"), "text").unwrap();
+
+        let names =
+            |plan: Plan| -> Vec<String> { plan.moves.into_iter().map(|m| m.file_name).collect() };
+        let plain = Planner::new(&tmp).tag_cache(None).plan().unwrap();
+        assert_eq!(names(plain), vec!["song.mp3"]);
+
+        let sniffed = Planner::new(&tmp)
+            .tag_cache(None)
+            .sniff(true)
+            .plan()
+            .unwrap();
+        assert!(sniffed
+            .moves
+            .iter()
+            .all(|m| m.corrected_extension.as_deref() == Some("flac")));
+        assert_eq!(names(sniffed), vec!["song.flac", "voice.flac"]);
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn untagged_files_are_inferred_from_their_path() {
+        let tmp = temp_dir("planner_infer");
+        let album = tmp.join("Artist - Album");
+        fs::create_dir_all(&album).unwrap();
+        let untagged = mpeg_frames();
+        fs::write(album.join("01 - First.mp3"), &untagged).unwrap();
+        fs::write(album.join("02 - Broken.mp3"), "not audio").unwrap();
+        fs::write(album.join("Second.mp3"), &untagged).unwrap();
+        // Directly under the root, so the pattern would need the
+        // directories above it
+        fs::write(tmp.join("04 Intro.mp3"), &untagged).unwrap();
+        // Partly tagged: the tags it has are kept
+        let partial = album.join("03 - Third.mp3");
+        fs::write(&partial, &untagged).unwrap();
+        let change = |field, new: &str| crate::tags::TagChange {
+            field,
+            old: None,
+            new: new.to_string(),
+        };
+        crate::tags::write_tags(&partial, &[change("title", "Real"), change("track", "9")])
+            .unwrap();
+
+        let plan = Planner::new(&tmp)
+            .tag_cache(None)
+            .recursive(true)
+            .infer(crate::infer::default_patterns())
+            .plan()
+            .unwrap();
+        let intro = &plan.moves[0];
+        assert!(intro.meta.is_none() && intro.inferred_from.is_none());
+        let first = &plan.moves[1];
+        assert_eq!(
+            first.dest,
+            plan.target.join("Artist - Album/01 - First.mp3")
+        );
+        assert_eq!(
+            first.inferred_from.as_deref(),
+            Some("{artist} - {album}/{track} - {title}")
+        );
+        // Unreadable files are left alone, and without a track number no
+        // pattern matches
+        let broken = &plan.moves[2];
+        assert!(broken.meta.is_none() && broken.inferred_from.is_none());
+        assert_eq!(broken.reason(), MoveReason::Corrupt);
+        let third = &plan.moves[3];
+        assert_eq!(third.dest, plan.target.join("Artist - Album/09 - Real.mp3"));
+        let second = &plan.moves[4];
+        assert!(second.meta.is_none() && second.inferred_from.is_none());
+        assert_eq!(second.unsorted, Some(UnsortedReason::NoTags));
+        assert_eq!(plan.summary().inferred, 2);
+
+        // A pattern without the album only helps files tagged with one
+        let plan = Planner::new(&tmp)
+            .tag_cache(None)
+            .recursive(true)
+            .infer(vec!["{track} - {title}".parse().unwrap()])
+            .plan()
+            .unwrap();
+        let first = &plan.moves[1];
+        assert!(first.meta.is_none() && first.inferred_from.is_none());
+        assert_eq!(first.unsorted, Some(UnsortedReason::NoTags));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn companions_never_replace_or_wait_on_library_files() {
+        let tmp = temp_dir("planner_companions");
+        let album = tmp.join("in/Artist - Album");
+        fs::create_dir_all(&album).unwrap();
+        fs::create_dir_all(tmp.join("lib/Artist - Album")).unwrap();
+        fs::write(album.join("01 - Song.mp3"), mpeg_frames()).unwrap();
+        fs::write(album.join("cover.jpg"), "new").unwrap();
+        fs::write(tmp.join("lib/Artist - Album/cover.jpg"), "old").unwrap();
+
+        for policy in [ConflictPolicy::Ask, ConflictPolicy::Overwrite] {
+            let plan = Planner::new(tmp.join("in"))
+                .tag_cache(None)
+                .recursive(true)
+                .dest(tmp.join("lib"))
+                .infer(crate::infer::default_patterns())
+                .layout(Layout {
+                    on_conflict: policy,
+                    ..Default::default()
+                })
+                .plan()
+                .unwrap();
+            let cover = plan.moves.iter().find(|m| m.companion).unwrap();
+            assert_eq!(cover.conflict, Some(Conflict::Skipped), "{:?}", policy);
+            assert!(!cover.needs_transfer());
+        }
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn relayout_files_planned_moves_by_corrected_tags() {
+        let tmp = temp_dir("planner_relayout");
+        fs::write(tmp.join("song.mp3"), mpeg_frames()).unwrap();
+
+        let planner = Planner::new(&tmp)
+            .tag_cache(None)
+            .mode(TransferMode::Copy)
+            .write_tags(true);
+        let plan = planner.plan().unwrap();
+        let untagged = &plan.moves[0];
+        assert_eq!(untagged.folder_name, UNSORTED_FOLDER);
+
+        let meta = TrackMetadata {
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            title: Some("Song".to_string()),
+            ..Default::default()
+        };
+        let relaid = planner.relayout(&plan.target, untagged, &meta).unwrap();
+        assert_eq!(relaid.dest, plan.target.join("Artist - Album/Song.mp3"));
+        assert_eq!(relaid.mode, TransferMode::Copy);
+        let fields: Vec<&str> = relaid.tag_changes.iter().map(|c| c.field).collect();
+        assert_eq!(fields, vec!["artist", "album", "title"]);
+
+        let by_genre = planner.template("{genre}/{title}.{ext}".parse().unwrap());
+        assert!(by_genre.relayout(&plan.target, untagged, &meta).is_none());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn corrupt_files_are_quarantined_left_or_unsorted() {
+        let tmp = temp_dir("planner_corrupt");
+        fs::write(tmp.join("broken.mp3"), "not audio").unwrap();
+        // Tags read fine from a truncated file
+        fs::write(tmp.join("cut.mp3"), &mpeg_frames()[..1500]).unwrap();
+
+        let plan = |planner: Planner| planner.tag_cache(None).plan().unwrap().moves;
+        let moves = plan(Planner::new(&tmp));
+        let (broken, cut) = (&moves[0], &moves[1]);
+        assert_eq!(broken.reason(), MoveReason::Corrupt);
+        assert_eq!(
+            broken.dest.parent().unwrap().file_name().unwrap(),
+            CORRUPT_FOLDER
+        );
+        assert_eq!(
+            broken.damage.as_ref().unwrap().error,
+            "no MPEG frames found"
+        );
+        assert_eq!(cut.unsorted, Some(UnsortedReason::NoTags));
+        assert!(cut.damage.is_none());
+
+        let validated = plan(Planner::new(&tmp).validate(true));
+        assert_eq!(validated[1].folder_name, CORRUPT_FOLDER);
+        assert_eq!(validated[1].damage.as_ref().unwrap().offset, Some(1251));
+
+        let left = plan(Planner::new(&tmp).on_corrupt(CorruptAction::Leave));
+        assert!(left[0].is_in_place() && left[0].damage.is_some());
+
+        let unsorted = plan(Planner::new(&tmp).on_corrupt(CorruptAction::Unsorted));
+        assert_eq!(unsorted[0].folder_name, UNSORTED_FOLDER);
+        assert_eq!(unsorted[0].unsorted, Some(UnsortedReason::Corrupt));
+        assert!(unsorted[0].damage.is_some());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
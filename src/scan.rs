@@ -0,0 +1,201 @@
+use crate::companions::is_companion_file;
+use crate::jobs;
+use crate::tags::detect_format;
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions scanned when no `extensions` are configured: the formats
+/// lofty reads tags from, plus WMA and DSF, which can only be filed as
+/// unsorted. Which format a file really is is decided by its content when
+/// the tags are read.
+pub const AUDIO_EXTENSIONS: &[&str] = &[
+    "mp3", "mp2", "m4a", "m4b", "aac", "flac", "ogg", "oga", "opus", "spx", "wav", "aif", "aiff",
+    "aifc", "ape", "wv", "mpc", "wma", "dsf",
+];
+
+/// Extensions of files that turn up next to music but never hold audio,
+/// besides the companion files: playlists, checksums, documents and
+/// archives. `--sniff` does not open them.
+pub const NON_AUDIO_EXTENSIONS: &[&str] = &[
+    "m3u", "m3u8", "pls", "sfv", "md5", "ffp", "accurip", "pdf", "htm", "html", "md", "ini", "db",
+    "url", "zip", "rar", "7z", "torrent", "bmp", "tif", "tiff",
+];
+
+/// True if `path` has one of `extensions` (compared case-insensitively).
+pub fn is_audio_file(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// True if `--sniff` should look inside `path` for audio: it has no
+/// extension, or one that is neither a companion's nor one of the
+/// [`NON_AUDIO_EXTENSIONS`].
+fn may_be_audio(path: &Path) -> bool {
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+    !is_companion_file(path)
+        && !NON_AUDIO_EXTENSIONS
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(ext))
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Files in `dir` with one of `extensions`, sorted by path. Hidden files and
+/// directories and the folders tagmv files into itself (`skip_dirs`, e.g. the
+/// unsorted folder) are skipped.
+pub fn scan_files(
+    dir: &Path,
+    recursive: bool,
+    extensions: &[String],
+    skip_dirs: &[&str],
+    jobs: usize,
+) -> Result<Vec<PathBuf>> {
+    walk(dir, recursive, skip_dirs, jobs, |path| {
+        is_audio_file(path, extensions)
+    })
+}
+
+/// Like [`scan_files`], but files without one of `extensions` are kept too
+/// if their content is a format lofty reads (`--sniff`). Files known to hold
+/// something else (cover art, logs, playlists, ...) are not opened; the
+/// others are checked on up to `jobs` threads.
+pub fn sniff_files(
+    dir: &Path,
+    recursive: bool,
+    extensions: &[String],
+    skip_dirs: &[&str],
+    jobs: usize,
+) -> Result<Vec<PathBuf>> {
+    let files = walk(dir, recursive, skip_dirs, jobs, |path| {
+        is_audio_file(path, extensions) || may_be_audio(path)
+    })?;
+    let audio = jobs::map(&files, jobs, |path| {
+        is_audio_file(path, extensions) || detect_format(path).is_some()
+    });
+    Ok(files
+        .into_iter()
+        .zip(audio)
+        .filter_map(|(path, audio)| audio.then_some(path))
+        .collect())
+}
+
+/// The files `keep` accepts and the subdirectories to descend into, in one
+/// directory. Symlinked directories are not followed.
+fn list_dir(
+    dir: &Path,
+    skip_dirs: &[&str],
+    keep: &(impl Fn(&Path) -> bool + Sync),
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    let mut files = Vec::new();
+    let mut subdirs = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_hidden(&name) {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            if !skip_dirs.contains(&name.as_str()) {
+                subdirs.push(path);
+            }
+        } else if path.is_file() && keep(&path) {
+            files.push(path);
+        }
+    }
+    Ok((files, subdirs))
+}
+
+/// On a NAS, listing directories is mostly waiting on the network, so the
+/// tree is walked a level at a time, with the directories of each level
+/// listed on up to `jobs` threads. Unreadable subdirectories are skipped.
+fn walk(
+    dir: &Path,
+    recursive: bool,
+    skip_dirs: &[&str],
+    jobs: usize,
+    keep: impl Fn(&Path) -> bool + Sync,
+) -> Result<Vec<PathBuf>> {
+    let (mut files, mut level) = list_dir(dir, skip_dirs, &keep)?;
+    if !recursive {
+        level.clear();
+    }
+    while !level.is_empty() {
+        let listed = jobs::map(&level, jobs, |dir| list_dir(dir, skip_dirs, &keep));
+        level = Vec::new();
+        for (found, subdirs) in listed.into_iter().filter_map(|l| l.ok()) {
+            files.extend(found);
+            level.extend(subdirs);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn default_extensions() {
+        let extensions: Vec<String> = AUDIO_EXTENSIONS.iter().map(|e| e.to_string()).collect();
+        for name in ["a.opus", "b.AIFF", "c.wv", "d.mpc", "e.spx", "f.ape"] {
+            assert!(is_audio_file(Path::new(name), &extensions), "{}", name);
+        }
+        for name in ["cover.jpg", "notes", "song.mp3.part"] {
+            assert!(!is_audio_file(Path::new(name), &extensions), "{}", name);
+        }
+    }
+
+    #[test]
+    fn sniffing_skips_files_known_not_to_be_audio() {
+        for name in ["voice.bin", "download", "track.mp4", "x.dat"] {
+            assert!(may_be_audio(Path::new(name)), "{}", name);
+        }
+        for name in ["cover.JPG", "rip.log", "album.cue", "list.m3u", "notes.pdf"] {
+            assert!(!may_be_audio(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn walks_the_tree_the_same_on_any_number_of_threads() {
+        let tmp = temp_dir("scan_walk");
+        for dir in ["A/B/C", "A/D", "E", ".hidden", "_Unsorted/X"] {
+            fs::create_dir_all(tmp.join(dir)).unwrap();
+        }
+        for file in [
+            "top.mp3",
+            "A/B/C/deep.flac",
+            "A/D/song.mp3",
+            "A/D/cover.jpg",
+            "A/D/.partial.mp3",
+            "E/other.mp3",
+            ".hidden/secret.mp3",
+            "_Unsorted/X/old.mp3",
+        ] {
+            fs::write(tmp.join(file), b"").unwrap();
+        }
+        let extensions: Vec<String> = AUDIO_EXTENSIONS.iter().map(|e| e.to_string()).collect();
+        let expected: Vec<PathBuf> = ["A/B/C/deep.flac", "A/D/song.mp3", "E/other.mp3", "top.mp3"]
+            .iter()
+            .map(|f| tmp.join(f))
+            .collect();
+        for jobs in [1, 4] {
+            let found = scan_files(&tmp, true, &extensions, &["_Unsorted"], jobs).unwrap();
+            assert_eq!(found, expected);
+            let found = scan_files(&tmp, false, &extensions, &["_Unsorted"], jobs).unwrap();
+            assert_eq!(found, vec![tmp.join("top.mp3")]);
+        }
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::tags::TrackMetadata;
+use crate::albums::CompilationRule;
+use crate::tags::{
+    read_quality, read_tags, AudioQuality, TagChange, TrackMetadata, UnsortedReason,
+};
+use crate::template::Template;
+use crate::validate::Damage;
 use anyhow::{bail, Context, Result};
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -12,139 +18,746 @@ const RESERVED_NAMES: &[&str] = &[
 
 const MAX_CONFLICT_ATTEMPTS: u32 = 10_000;
 
+/// Folder that files without usable tags are collected in.
+pub const UNSORTED_FOLDER: &str = "_Unsorted";
+
+/// Folder that the losers of a quality comparison are moved to.
+pub const DUPLICATES_FOLDER: &str = "_Duplicates";
+
+/// Folder that unreadable and damaged files are quarantined in.
+pub const CORRUPT_FOLDER: &str = "_Corrupt";
+
+/// Characters dropped from path components by default.
+const REMOVED_CHARS: &str = ":*?\"<>|";
+
 /// Sanitize a string for safe use in filenames.
 /// Mirrors `slugify_for_filename` from rename_audio_by_tags.py.
 pub fn sanitize(s: &str) -> String {
-    let mut out = String::with_capacity(s.len());
-
-    for c in s.chars() {
-        match c {
-            '/' | '\\' => out.push('-'),
-            ':' | '*' | '?' | '"' | '<' | '>' | '|' => {}
-            c if c.is_control() => {}
-            _ => out.push(c),
+    SanitizeRules::default().apply(s)
+}
+
+/// How tag values are cleaned up before they become path components.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SanitizeRules {
+    /// Replacement for `/` and `\`.
+    pub separator: String,
+    /// Characters that are dropped.
+    pub remove: String,
+    /// Per-character replacements, applied before the other rules.
+    pub replace: BTreeMap<char, String>,
+}
+
+impl Default for SanitizeRules {
+    fn default() -> Self {
+        SanitizeRules {
+            separator: "-".to_string(),
+            remove: REMOVED_CHARS.to_string(),
+            replace: BTreeMap::new(),
         }
     }
+}
 
-    // Collapse whitespace
-    let collapsed: String = out.split_whitespace().collect::<Vec<_>>().join(" ");
+impl SanitizeRules {
+    pub fn apply(&self, s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
 
-    // Trim dots and spaces
-    let trimmed = collapsed
-        .trim_matches(|c: char| c == '.' || c == ' ')
-        .to_string();
+        for c in s.chars() {
+            match c {
+                c if self.replace.contains_key(&c) => out.push_str(&self.replace[&c]),
+                '/' | '\\' => out.push_str(&self.separator),
+                c if self.remove.contains(c) => {}
+                c if c.is_control() => {}
+                _ => out.push(c),
+            }
+        }
 
-    if trimmed.is_empty() {
-        return "Unknown".to_string();
+        // Replacements must not introduce path separators of their own
+        out.retain(|c| c != '/' && c != '\\');
+
+        // Collapse whitespace
+        let collapsed: String = out.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        // Trim dots and spaces
+        let trimmed = collapsed
+            .trim_matches(|c: char| c == '.' || c == ' ')
+            .to_string();
+
+        if trimmed.is_empty() {
+            return "Unknown".to_string();
+        }
+
+        // Guard against reserved device names (for FAT32/exFAT compatibility)
+        if RESERVED_NAMES
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(&trimmed))
+        {
+            return format!("_{}", trimmed);
+        }
+
+        trimmed
     }
+}
 
-    // Guard against reserved device names (for FAT32/exFAT compatibility)
-    if RESERVED_NAMES
-        .iter()
-        .any(|r| r.eq_ignore_ascii_case(&trimmed))
-    {
-        return format!("_{}", trimmed);
+/// Library layout rules that are not part of the template: where untagged
+/// files go, how path components are sanitized, and how destination
+/// conflicts are settled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub unsorted_folder: String,
+    pub duplicates_folder: String,
+    pub corrupt_folder: String,
+    pub sanitize: SanitizeRules,
+    pub on_conflict: ConflictPolicy,
+    pub max_conflict_attempts: u32,
+    /// Sort unsorted files into a subfolder per [`UnsortedReason`].
+    pub unsorted_by_reason: bool,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            unsorted_folder: UNSORTED_FOLDER.to_string(),
+            duplicates_folder: DUPLICATES_FOLDER.to_string(),
+            corrupt_folder: CORRUPT_FOLDER.to_string(),
+            sanitize: SanitizeRules::default(),
+            on_conflict: ConflictPolicy::default(),
+            max_conflict_attempts: MAX_CONFLICT_ATTEMPTS,
+            unsorted_by_reason: false,
+        }
     }
+}
 
-    trimmed
+/// What happens when a destination is already taken, on disk or by an
+/// earlier file of the same run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Leave the file where it is
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Append " (N)" to the file name
+    #[default]
+    Rename,
+    /// Keep the bigger file, move the other one to the duplicates folder
+    KeepLarger,
+    /// Keep the file with the higher audio bitrate
+    KeepHigherBitrate,
+    /// Keep a lossless file over a lossy one, then the higher bitrate
+    KeepLossless,
+    /// Ask for every conflict (left undecided when nobody is asked)
+    Ask,
 }
 
-/// A planned file move operation.
+impl ConflictPolicy {
+    /// True for the policies that compare the two files.
+    pub fn compares_quality(self) -> bool {
+        matches!(
+            self,
+            ConflictPolicy::KeepLarger
+                | ConflictPolicy::KeepHigherBitrate
+                | ConflictPolicy::KeepLossless
+        )
+    }
+
+    /// For the quality policies: true if `incoming` beats `existing`. Ties
+    /// keep the existing file.
+    pub fn prefers(self, incoming: &AudioQuality, existing: &AudioQuality) -> bool {
+        let bitrate = |q: &AudioQuality| q.bitrate.unwrap_or(0);
+        match self {
+            ConflictPolicy::KeepLarger => incoming.size > existing.size,
+            ConflictPolicy::KeepHigherBitrate => bitrate(incoming) > bitrate(existing),
+            ConflictPolicy::KeepLossless => {
+                (incoming.lossless, bitrate(incoming)) > (existing.lossless, bitrate(existing))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Rename => "rename",
+            ConflictPolicy::KeepLarger => "keep-larger",
+            ConflictPolicy::KeepHigherBitrate => "keep-higher-bitrate",
+            ConflictPolicy::KeepLossless => "keep-lossless",
+            ConflictPolicy::Ask => "ask",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One conflict, as shown to the [`ConflictPolicy::Ask`] callback.
 #[derive(Debug)]
+pub struct ConflictCase<'a> {
+    /// The destination both files want.
+    pub dest: &'a Path,
+    /// The file that has the destination: either already there, or the
+    /// source of an earlier move.
+    pub existing: &'a Path,
+    /// The file that wants it too.
+    pub incoming: &'a Path,
+}
+
+/// Picks the policy for one conflict under [`ConflictPolicy::Ask`].
+pub type AskFn<'a> = &'a dyn Fn(&ConflictCase<'_>) -> ConflictPolicy;
+
+/// How a file is transferred to its destination.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMode {
+    /// Rename, or copy+delete across devices
+    #[default]
+    Move,
+    /// Copy, leaving the source untouched
+    Copy,
+    /// Hard link, or copy across devices
+    Hardlink,
+    /// Symbolic link to the absolute source path
+    Symlink,
+    /// Copy-on-write clone, or copy if unsupported
+    Reflink,
+}
+
+impl TransferMode {
+    /// Past-tense verb for summaries ("Moved 3 files").
+    pub fn verb(self) -> &'static str {
+        match self {
+            TransferMode::Move => "moved",
+            TransferMode::Copy => "copied",
+            TransferMode::Hardlink => "hardlinked",
+            TransferMode::Symlink => "symlinked",
+            TransferMode::Reflink => "reflinked",
+        }
+    }
+}
+
+impl std::fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TransferMode::Move => "move",
+            TransferMode::Copy => "copy",
+            TransferMode::Hardlink => "hardlink",
+            TransferMode::Symlink => "symlink",
+            TransferMode::Reflink => "reflink",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a file ends up where the plan puts it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MoveReason {
+    /// Destination computed from the file's tags
+    Tagged,
+    /// No usable tags, collected in the unsorted folder
+    Unsorted,
+    /// Unreadable or damaged, quarantined in the corrupt folder
+    Corrupt,
+    /// Already at its destination, nothing to do
+    InPlace,
+    /// Destination was taken, a ` (N)` suffix was appended
+    RenamedForConflict,
+    /// Destination was taken, the file is left where it is
+    SkippedForConflict,
+    /// Destination was taken and `--on-conflict ask` will ask what to do
+    Undecided,
+    /// Replaces the file at the destination
+    Overwrites,
+    /// Beat a conflicting copy in a quality comparison
+    Preferred,
+    /// Lost a quality comparison, filed into the duplicates folder
+    Duplicate,
+    /// Was at the destination already, moved to the duplicates folder to
+    /// make room for a better copy
+    Displaced,
+    /// Same audio as a file that is kept, filed into the duplicates folder
+    ExactDuplicate,
+    /// Cover art, cue sheet, log or lyrics that goes along with the audio
+    Companion,
+}
+
+/// How `resolve_conflicts` settled a taken destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    Renamed,
+    Skipped,
+    Overwrites,
+    Preferred,
+    Duplicate,
+    Displaced,
+    /// Left to the [`ConflictPolicy::Ask`] prompt, which this plan did not
+    /// have; the file stays where it is.
+    Undecided,
+}
+
+/// A planned file move operation.
+#[derive(Debug, Default)]
 pub struct PlannedMove {
     pub source: PathBuf,
     pub dest: PathBuf,
     pub folder_name: String,
     pub file_name: String,
+    pub mode: TransferMode,
+    /// Set when the file was laid out with the compilation template.
+    pub compilation: Option<CompilationRule>,
+    /// Tags the destination was computed from (`None` for unsorted files).
+    pub meta: Option<TrackMetadata>,
+    /// Why the file goes to the unsorted folder.
+    pub unsorted: Option<UnsortedReason>,
+    /// Set when the file cannot be read, or `--validate` found it damaged.
+    /// Unless such files go to the unsorted folder, `unsorted` is `None`.
+    pub damage: Option<Damage>,
+    /// Set by `resolve_conflicts` when the destination was taken.
+    pub conflict: Option<Conflict>,
+    /// Set by the dedupe pass when the audio matches a file that is kept.
+    pub duplicate_of: Option<DuplicateOf>,
+    /// A non-audio file that follows the audio files of its directory.
+    pub companion: bool,
+    /// Set when the destination got this extension instead of the source's,
+    /// to match the detected format (`--sniff`).
+    pub corrected_extension: Option<String>,
+    /// Set when the file had no usable tags and `meta` was read from its
+    /// path with this pattern.
+    pub inferred_from: Option<String>,
+    /// Tags to write into the file once it is at its destination
+    /// (`--write-tags`).
+    pub tag_changes: Vec<TagChange>,
+    /// Set by `resolve_conflicts` when a copy or reflink destination already
+    /// holds the same bytes, e.g. from an earlier run.
+    pub already_copied: bool,
+    /// Set by `resolve_conflicts` when a hardlink or symlink destination
+    /// already links to the source (in the link mode planned).
+    pub already_linked: bool,
 }
 
-/// Compute destination path for a file with known tags.
-pub fn compute_destination(base_dir: &Path, source: &Path, meta: &TrackMetadata) -> PlannedMove {
-    let artist = sanitize(&meta.artist);
-    let album = sanitize(&meta.album);
-    let folder_name = format!("{} - {}", artist, album);
-
-    let ext = source
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or_else(|| {
-            source
-                .file_name()
-                .and_then(|n| n.to_str())
-                .map(|n| n.rsplit_once('.').map_or("", |(_, e)| e))
-                .unwrap_or("")
-        });
-
-    let title = meta
-        .title
-        .as_deref()
-        .map(sanitize)
-        .unwrap_or_else(|| {
-            source
-                .file_stem()
-                .and_then(|s| s.to_str())
-                .unwrap_or("Unknown")
-                .to_string()
-        });
-
-    let file_name = match meta.track_number {
-        Some(n) => format!("{:02} - {}.{}", n, title, ext),
-        None => format!("{}.{}", title, ext),
-    };
+/// The kept copy of an exact duplicate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateOf {
+    /// A file already in the library, or the source of an earlier move.
+    pub path: PathBuf,
+    /// Filed into the duplicates folder rather than its own destination.
+    pub quarantined: bool,
+}
 
-    let dest = base_dir.join(&folder_name).join(&file_name);
+impl PlannedMove {
+    /// True if nothing needs to happen: the file is already at its
+    /// destination, or the destination already links to it or holds a copy
+    /// (see `already_linked` and `already_copied`). Decided when the plan is
+    /// made, so it stays the same once the plan has run.
+    pub fn is_in_place(&self) -> bool {
+        self.source == self.dest || self.already_copied || self.already_linked
+    }
 
-    PlannedMove {
-        source: source.to_path_buf(),
-        dest,
-        folder_name,
-        file_name,
+    /// True if the file has to be transferred: it is neither in place nor
+    /// skipped because of a conflict.
+    pub fn needs_transfer(&self) -> bool {
+        !self.is_skipped() && !self.is_in_place()
     }
-}
 
-/// Compute destination for unsorted files.
-pub fn compute_unsorted_destination(base_dir: &Path, source: &Path) -> PlannedMove {
-    let file_name = source
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("unknown")
-        .to_string();
+    /// True if executing the plan acts on the file: it is transferred, or
+    /// its tags are written where it is.
+    pub fn is_pending(&self) -> bool {
+        self.needs_transfer() || (!self.is_skipped() && !self.tag_changes.is_empty())
+    }
+
+    /// True if a conflict leaves the file where it is, whether skipped or
+    /// undecided.
+    pub fn is_skipped(&self) -> bool {
+        matches!(self.conflict, Some(Conflict::Skipped | Conflict::Undecided))
+    }
 
-    let folder_name = "_Unsorted".to_string();
-    let dest = base_dir.join(&folder_name).join(&file_name);
+    /// Give the destination the extension `ext` in place of the source's.
+    pub fn correct_extension(&mut self, ext: &str) {
+        let old = self.source.extension().and_then(|e| e.to_str());
+        let stem = old
+            .and_then(|old| self.file_name.strip_suffix(old))
+            .and_then(|stem| stem.strip_suffix('.'))
+            .unwrap_or(&self.file_name);
+        self.file_name = format!("{}.{}", stem, ext);
+        self.dest.set_file_name(&self.file_name);
+        self.corrected_extension = Some(ext.to_string());
+    }
 
-    PlannedMove {
-        source: source.to_path_buf(),
-        dest,
-        folder_name,
-        file_name,
+    pub fn reason(&self) -> MoveReason {
+        if self.is_in_place() {
+            return MoveReason::InPlace;
+        }
+        match self.conflict {
+            Some(Conflict::Renamed) => MoveReason::RenamedForConflict,
+            Some(Conflict::Skipped) => MoveReason::SkippedForConflict,
+            Some(Conflict::Undecided) => MoveReason::Undecided,
+            Some(Conflict::Overwrites) => MoveReason::Overwrites,
+            Some(Conflict::Preferred) => MoveReason::Preferred,
+            Some(Conflict::Duplicate) => MoveReason::Duplicate,
+            Some(Conflict::Displaced) => MoveReason::Displaced,
+            None if self.duplicate_of.as_ref().is_some_and(|d| d.quarantined) => {
+                MoveReason::ExactDuplicate
+            }
+            None if self.companion => MoveReason::Companion,
+            None if self.damage.is_some() && self.unsorted.is_none() => MoveReason::Corrupt,
+            None if self.meta.is_none() => MoveReason::Unsorted,
+            None => MoveReason::Tagged,
+        }
     }
 }
 
-/// Resolve conflicts: both on-disk and intra-batch duplicates.
-pub fn resolve_conflicts(moves: &mut [PlannedMove]) {
-    let mut claimed: HashSet<PathBuf> = HashSet::new();
+/// Compute destination path for a file with known tags, using the default
+/// layout. See [`Layout::destination`].
+pub fn compute_destination(
+    base_dir: &Path,
+    source: &Path,
+    meta: &TrackMetadata,
+    template: &Template,
+) -> Option<PlannedMove> {
+    Layout::default().destination(base_dir, source, meta, template)
+}
+
+/// Compute destination for unsorted files, using the default layout.
+pub fn compute_unsorted_destination(
+    base_dir: &Path,
+    source: &Path,
+    reason: UnsortedReason,
+) -> PlannedMove {
+    Layout::default().unsorted_destination(base_dir, source, reason)
+}
+
+/// Resolve conflicts with the default layout. See [`Layout::resolve_conflicts`].
+pub fn resolve_conflicts(base_dir: &Path, moves: &mut Vec<PlannedMove>) {
+    Layout::default().resolve_conflicts(base_dir, moves, None)
+}
+
+impl Layout {
+    /// Compute destination path for a file with known tags, laid out according
+    /// to `template`. Returns `None` if the template references a field that the
+    /// file does not have (outside of an optional section).
+    pub fn destination(
+        &self,
+        base_dir: &Path,
+        source: &Path,
+        meta: &TrackMetadata,
+        template: &Template,
+    ) -> Option<PlannedMove> {
+        let ext = source
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_else(|| {
+                source
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.rsplit_once('.').map_or("", |(_, e)| e))
+                    .unwrap_or("")
+            });
+
+        let stem = source
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("Unknown");
+
+        let components = template.render(|field| match field {
+            "filename" => Some(stem.to_string()),
+            "ext" => Some(ext.to_string()),
+            _ => meta.field(field),
+        })?;
+
+        let mut components: Vec<String> =
+            components.iter().map(|c| self.sanitize.apply(c)).collect();
+        let file_name = components.pop()?;
+        let folder_name = components.join("/");
+
+        let dest = components
+            .iter()
+            .fold(base_dir.to_path_buf(), |p, c| p.join(c))
+            .join(&file_name);
+
+        Some(PlannedMove {
+            source: source.to_path_buf(),
+            dest,
+            folder_name,
+            file_name,
+            mode: TransferMode::Move,
+            compilation: None,
+            meta: Some(meta.clone()),
+            unsorted: None,
+            damage: None,
+            conflict: None,
+            duplicate_of: None,
+            companion: false,
+            corrected_extension: None,
+            inferred_from: None,
+            tag_changes: Vec::new(),
+            already_copied: false,
+            already_linked: false,
+        })
+    }
+
+    /// Compute destination for unsorted files: `<unsorted folder>/<file name>`,
+    /// or `<unsorted folder>/<reason>/<file name>` with `unsorted_by_reason`.
+    pub fn unsorted_destination(
+        &self,
+        base_dir: &Path,
+        source: &Path,
+        reason: UnsortedReason,
+    ) -> PlannedMove {
+        let file_name = source
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let mut folder_name = self.unsorted_folder.clone();
+        if self.unsorted_by_reason {
+            folder_name = format!("{}/{}", folder_name, reason.folder_name());
+        }
+        let dest = base_dir.join(&folder_name).join(&file_name);
+
+        PlannedMove {
+            source: source.to_path_buf(),
+            dest,
+            folder_name,
+            file_name,
+            mode: TransferMode::Move,
+            compilation: None,
+            meta: None,
+            unsorted: Some(reason),
+            damage: None,
+            conflict: None,
+            duplicate_of: None,
+            companion: false,
+            corrected_extension: None,
+            inferred_from: None,
+            tag_changes: Vec::new(),
+            already_copied: false,
+            already_linked: false,
+        }
+    }
+
+    /// Compute destination for unreadable or damaged files:
+    /// `<corrupt folder>/<file name>`.
+    pub fn corrupt_destination(
+        &self,
+        base_dir: &Path,
+        source: &Path,
+        damage: Damage,
+    ) -> PlannedMove {
+        let file_name = file_name_of(source);
+        let folder_name = self.corrupt_folder.clone();
+        let dest = base_dir.join(&folder_name).join(&file_name);
+
+        PlannedMove {
+            source: source.to_path_buf(),
+            dest,
+            folder_name,
+            file_name,
+            mode: TransferMode::Move,
+            compilation: None,
+            meta: None,
+            unsorted: None,
+            damage: Some(damage),
+            conflict: None,
+            duplicate_of: None,
+            companion: false,
+            corrected_extension: None,
+            inferred_from: None,
+            tag_changes: Vec::new(),
+            already_copied: false,
+            already_linked: false,
+        }
+    }
+
+    /// Resolve conflicts: both on-disk and intra-batch duplicates, settled
+    /// according to `on_conflict`. `ask` decides conflicts under
+    /// [`ConflictPolicy::Ask`]; without it they are left
+    /// [`Conflict::Undecided`].
+    ///
+    /// The quality policies only apply to tagged files (untagged files with
+    /// the same name are rarely the same track) and fall back to renaming
+    /// otherwise. When an incoming file beats one that is already at the
+    /// destination, a move of that file into the duplicates folder is
+    /// inserted right before it.
+    pub fn resolve_conflicts(
+        &self,
+        base_dir: &Path,
+        moves: &mut Vec<PlannedMove>,
+        ask: Option<AskFn<'_>>,
+    ) {
+        // Destination -> index into `resolved` of the move that claimed it
+        let mut claimed: HashMap<PathBuf, usize> = HashMap::new();
+        let mut resolved: Vec<PlannedMove> = Vec::with_capacity(moves.len());
+        let mut displaced: HashSet<PathBuf> = HashSet::new();
+
+        for mut m in moves.drain(..) {
+            m.already_linked = m.source != m.dest
+                && match m.mode {
+                    TransferMode::Hardlink => same_file(&m.source, &m.dest),
+                    TransferMode::Symlink => links_to(&m.dest, &m.source),
+                    _ => false,
+                };
+            if m.is_in_place() {
+                resolved.push(m);
+                continue;
+            }
+
+            let earlier = claimed.get(&m.dest).copied();
+            if earlier.is_none() && !m.dest.exists() {
+                claimed.insert(m.dest.clone(), resolved.len());
+                resolved.push(m);
+                continue;
+            }
+            // A copy an earlier run made is left as it is
+            if earlier.is_none()
+                && matches!(m.mode, TransferMode::Copy | TransferMode::Reflink)
+                && same_content(&m.source, &m.dest)
+            {
+                m.already_copied = true;
+                resolved.push(m);
+                continue;
+            }
+
+            let existing = match earlier {
+                Some(i) => resolved[i].source.clone(),
+                None => m.dest.clone(),
+            };
+            // Quarantined files are already set aside; there is nothing to
+            // decide about them
+            let quarantined = m.duplicate_of.as_ref().is_some_and(|d| d.quarantined);
+            let on_conflict = if quarantined {
+                ConflictPolicy::Rename
+            } else {
+                self.on_conflict
+            };
+            let mut policy = match (on_conflict, ask) {
+                (ConflictPolicy::Ask, Some(ask)) => ask(&ConflictCase {
+                    dest: &m.dest,
+                    existing: &existing,
+                    incoming: &m.source,
+                }),
+                (policy, _) => policy,
+            };
+            if policy == ConflictPolicy::Ask {
+                m.conflict = Some(Conflict::Undecided);
+                resolved.push(m);
+                continue;
+            }
+            // Quality policies only compare tagged files with each other; an
+            // earlier loser already sits in the duplicates folder
+            let earlier_conflict = earlier.and_then(|i| resolved[i].conflict);
+            if policy.compares_quality()
+                && (m.meta.is_none()
+                    || matches!(
+                        earlier_conflict,
+                        Some(Conflict::Duplicate | Conflict::Displaced)
+                    ))
+            {
+                policy = ConflictPolicy::Rename;
+            }
+
+            // The on-disk outcome of an earlier claim carries over when this
+            // move takes the destination from it
+            let inherited = earlier_conflict
+                .filter(|c| matches!(c, Conflict::Overwrites | Conflict::Preferred));
+
+            match policy {
+                ConflictPolicy::Skip => m.conflict = Some(Conflict::Skipped),
+                ConflictPolicy::Overwrite => match earlier {
+                    Some(i) => {
+                        resolved[i].conflict = Some(Conflict::Skipped);
+                        m.conflict = inherited;
+                    }
+                    None => m.conflict = Some(Conflict::Overwrites),
+                },
+                policy if policy.compares_quality() => {
+                    let better = policy.prefers(&read_quality(&m.source), &read_quality(&existing));
+                    if !better {
+                        self.file_as_duplicate(base_dir, &mut m, &claimed);
+                        m.conflict = Some(Conflict::Duplicate);
+                    } else if let Some(i) = earlier {
+                        let loser = &mut resolved[i];
+                        self.file_as_duplicate(base_dir, loser, &claimed);
+                        loser.conflict = Some(Conflict::Duplicate);
+                        claimed.insert(loser.dest.clone(), i);
+                        m.conflict = inherited.or(Some(Conflict::Preferred));
+                    } else {
+                        let mut loser = PlannedMove {
+                            source: existing.clone(),
+                            dest: m.dest.clone(),
+                            folder_name: m.folder_name.clone(),
+                            file_name: m.file_name.clone(),
+                            meta: read_tags(&existing).ok(),
+                            ..Default::default()
+                        };
+                        self.file_as_duplicate(base_dir, &mut loser, &claimed);
+                        loser.conflict = Some(Conflict::Displaced);
+                        claimed.insert(loser.dest.clone(), resolved.len());
+                        displaced.insert(existing);
+                        resolved.push(loser);
+                        m.conflict = Some(Conflict::Preferred);
+                    }
+                }
+                _ => {
+                    let candidate = self.unique_path(&m.dest, &claimed);
+                    m.file_name = file_name_of(&candidate);
+                    m.dest = candidate;
+                    m.conflict = Some(Conflict::Renamed);
+                }
+            }
 
-    for m in moves.iter_mut() {
-        if m.source == m.dest {
-            continue;
+            if m.conflict != Some(Conflict::Skipped) {
+                claimed.insert(m.dest.clone(), resolved.len());
+            }
+            resolved.push(m);
         }
 
-        let mut candidate = m.dest.clone();
+        // A displaced file that was in place is now covered by its move to
+        // the duplicates folder
+        resolved.retain(|m| !(m.is_in_place() && displaced.contains(&m.source)));
+        *moves = resolved;
+    }
+
+    /// Re-file `m` under the duplicates folder, keeping its folder and file
+    /// name.
+    pub fn quarantine(&self, base_dir: &Path, m: &mut PlannedMove) {
+        let folder_name = if m.folder_name.is_empty() {
+            self.duplicates_folder.clone()
+        } else {
+            format!("{}/{}", self.duplicates_folder, m.folder_name)
+        };
+        m.dest = folder_name
+            .split('/')
+            .fold(base_dir.to_path_buf(), |p, c| p.join(c))
+            .join(&m.file_name);
+        m.folder_name = folder_name;
+    }
+
+    /// [`Layout::quarantine`], with a destination that is still free.
+    fn file_as_duplicate(
+        &self,
+        base_dir: &Path,
+        m: &mut PlannedMove,
+        claimed: &HashMap<PathBuf, usize>,
+    ) {
+        self.quarantine(base_dir, m);
+        m.dest = self.unique_path(&m.dest, claimed);
+        m.file_name = file_name_of(&m.dest);
+    }
+
+    /// `path`, or the first ` (N)` variant of it that is neither on disk nor
+    /// claimed.
+    fn unique_path(&self, path: &Path, claimed: &HashMap<PathBuf, usize>) -> PathBuf {
+        let mut candidate = path.to_path_buf();
         let mut counter = 1u32;
 
-        while candidate.exists() || claimed.contains(&candidate) {
-            let stem = m
-                .dest
-                .file_stem()
-                .and_then(|s| s.to_str())
-                .unwrap_or("file");
-            let ext = m
-                .dest
-                .extension()
-                .and_then(|e| e.to_str())
-                .unwrap_or("");
-            let parent = m.dest.parent().unwrap();
+        while candidate.exists() || claimed.contains_key(&candidate) {
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("file");
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            let parent = path.parent().unwrap();
 
             let new_name = if ext.is_empty() {
                 format!("{} ({})", stem, counter)
@@ -155,7 +768,7 @@ pub fn resolve_conflicts(moves: &mut [PlannedMove]) {
             candidate = parent.join(&new_name);
 
             counter = match counter.checked_add(1) {
-                Some(n) if n <= MAX_CONFLICT_ATTEMPTS => n,
+                Some(n) if n <= self.max_conflict_attempts => n,
                 _ => {
                     // Give up -- keep the last candidate and let execute_move
                     // report an error if it actually collides at runtime
@@ -163,26 +776,24 @@ pub fn resolve_conflicts(moves: &mut [PlannedMove]) {
                 }
             };
         }
-
-        if candidate != m.dest {
-            m.file_name = candidate
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("unknown")
-                .to_string();
-            m.dest = candidate.clone();
-        }
-
-        claimed.insert(candidate);
+        candidate
     }
 }
 
-/// Execute a file move. Creates directories as needed.
-/// Checks for conflicts at move time, uses rename first, falls back to
-/// copy+delete only for cross-device moves (verifying file size).
-pub fn execute_move(planned: &PlannedMove) -> Result<()> {
-    if planned.source == planned.dest {
-        return Ok(());
+fn file_name_of(path: &Path) -> String {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// Execute a planned transfer. Creates directories as needed.
+/// Checks for conflicts at move time, then dispatches on `planned.mode`.
+/// Returns the mode that was actually applied, which differs from the
+/// requested one when a fallback kicked in (e.g. reflink -> copy).
+pub fn execute_move(planned: &PlannedMove) -> Result<TransferMode> {
+    if !planned.needs_transfer() {
+        return Ok(planned.mode);
     }
 
     if let Some(parent) = planned.dest.parent() {
@@ -190,23 +801,62 @@ pub fn execute_move(planned: &PlannedMove) -> Result<()> {
             .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
     }
 
+    if planned.conflict == Some(Conflict::Overwrites) && planned.dest.symlink_metadata().is_ok() {
+        return transfer_overwrite(planned);
+    }
+
     // Re-check at move time: if destination appeared since planning, bail
-    if planned.dest.exists() {
+    if planned.dest.symlink_metadata().is_ok() {
         bail!(
             "Destination already exists (appeared after planning): {}",
             planned.dest.display()
         );
     }
 
+    match planned.mode {
+        TransferMode::Move => transfer_move(planned),
+        TransferMode::Copy => {
+            copy_verified(&planned.source, &planned.dest)?;
+            Ok(TransferMode::Copy)
+        }
+        TransferMode::Hardlink => transfer_hardlink(planned),
+        TransferMode::Symlink => transfer_symlink(planned),
+        TransferMode::Reflink => transfer_reflink(planned),
+    }
+}
+
+/// Transfer next to the destination, then rename over it, so the existing
+/// file is only replaced once the new one is complete.
+fn transfer_overwrite(planned: &PlannedMove) -> Result<TransferMode> {
+    let staged = PlannedMove {
+        source: planned.source.clone(),
+        dest: planned
+            .dest
+            .with_file_name(format!(".{}.tagmv-tmp", planned.file_name)),
+        mode: planned.mode,
+        ..Default::default()
+    };
+    let applied = execute_move(&staged)?;
+
+    if let Err(e) = fs::rename(&staged.dest, &planned.dest) {
+        // Put a moved source back; anything else was a copy or a link
+        if applied == TransferMode::Move {
+            let _ = fs::rename(&staged.dest, &planned.source);
+        } else {
+            let _ = fs::remove_file(&staged.dest);
+        }
+        return Err(e).with_context(|| format!("Failed to replace {}", planned.dest.display()));
+    }
+    Ok(applied)
+}
+
+/// Rename first, falling back to copy+delete only for cross-device moves.
+fn transfer_move(planned: &PlannedMove) -> Result<TransferMode> {
     match fs::rename(&planned.source, &planned.dest) {
-        Ok(()) => Ok(()),
+        Ok(()) => Ok(TransferMode::Move),
         Err(e) => {
             // Only fall back to copy+delete for cross-device errors
-            let dominated_by_cross_device = matches!(
-                e.raw_os_error(),
-                Some(libc::EXDEV)
-            );
-            if !dominated_by_cross_device {
+            if !is_cross_device(&e) {
                 return Err(e).with_context(|| {
                     format!(
                         "Failed to move {} -> {}",
@@ -216,42 +866,247 @@ pub fn execute_move(planned: &PlannedMove) -> Result<()> {
                 });
             }
 
-            let source_len = fs::metadata(&planned.source)
-                .with_context(|| {
-                    format!("Failed to read source metadata: {}", planned.source.display())
-                })?
-                .len();
+            copy_verified(&planned.source, &planned.dest)?;
 
-            let bytes_copied = fs::copy(&planned.source, &planned.dest).with_context(|| {
-                format!(
-                    "Failed to copy {} -> {}",
-                    planned.source.display(),
-                    planned.dest.display()
-                )
+            fs::remove_file(&planned.source).with_context(|| {
+                format!("Failed to remove source: {}", planned.source.display())
             })?;
+            Ok(TransferMode::Move)
+        }
+    }
+}
+
+/// Hard links cannot span filesystems; fall back to a verified copy there.
+fn transfer_hardlink(planned: &PlannedMove) -> Result<TransferMode> {
+    match fs::hard_link(&planned.source, &planned.dest) {
+        Ok(()) => {
+            if !same_file(&planned.source, &planned.dest) {
+                let _ = fs::remove_file(&planned.dest);
+                bail!(
+                    "Hardlink verification failed for {}",
+                    planned.dest.display()
+                );
+            }
+            Ok(TransferMode::Hardlink)
+        }
+        Err(e) if is_cross_device(&e) => {
+            copy_verified(&planned.source, &planned.dest)?;
+            Ok(TransferMode::Copy)
+        }
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to hardlink {} -> {}",
+                planned.source.display(),
+                planned.dest.display()
+            )
+        }),
+    }
+}
+
+/// Symlinks always point at the absolute source path. No fallback: a copy
+/// would silently defeat the purpose of a link-based view.
+fn transfer_symlink(planned: &PlannedMove) -> Result<TransferMode> {
+    let target = if planned.source.is_absolute() {
+        planned.source.clone()
+    } else {
+        std::env::current_dir()?.join(&planned.source)
+    };
+
+    symlink_file(&target, &planned.dest).with_context(|| {
+        format!(
+            "Failed to symlink {} -> {}",
+            planned.dest.display(),
+            target.display()
+        )
+    })?;
+
+    if !planned.dest.exists() {
+        let _ = fs::remove_file(&planned.dest);
+        bail!("Symlink verification failed for {}", planned.dest.display());
+    }
+    Ok(TransferMode::Symlink)
+}
 
-            if bytes_copied != source_len {
-                // Remove incomplete copy, keep source intact
+/// Copy-on-write clone where the filesystem supports it, verified copy
+/// otherwise.
+fn transfer_reflink(planned: &PlannedMove) -> Result<TransferMode> {
+    match reflink(&planned.source, &planned.dest) {
+        Ok(()) => {
+            let source_len = fs::metadata(&planned.source)?.len();
+            let dest_len = fs::metadata(&planned.dest)?.len();
+            if source_len != dest_len {
                 let _ = fs::remove_file(&planned.dest);
                 bail!(
-                    "Copy verification failed for {}: expected {} bytes, copied {}",
+                    "Reflink verification failed for {}: expected {} bytes, got {}",
                     planned.source.display(),
                     source_len,
-                    bytes_copied
+                    dest_len
                 );
             }
+            Ok(TransferMode::Reflink)
+        }
+        Err(e) if is_reflink_unsupported(&e) => {
+            copy_verified(&planned.source, &planned.dest)?;
+            Ok(TransferMode::Copy)
+        }
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to reflink {} -> {}",
+                planned.source.display(),
+                planned.dest.display()
+            )
+        }),
+    }
+}
 
-            fs::remove_file(&planned.source).with_context(|| {
-                format!("Failed to remove source: {}", planned.source.display())
-            })?;
-            Ok(())
+/// Copy `source` to `dest`, verifying the byte count. An incomplete copy is
+/// removed and the source is left intact.
+fn copy_verified(source: &Path, dest: &Path) -> Result<()> {
+    let source_len = fs::metadata(source)
+        .with_context(|| format!("Failed to read source metadata: {}", source.display()))?
+        .len();
+
+    let bytes_copied = fs::copy(source, dest).with_context(|| {
+        format!("Failed to copy {} -> {}", source.display(), dest.display())
+    })?;
+
+    if bytes_copied != source_len {
+        let _ = fs::remove_file(dest);
+        bail!(
+            "Copy verification failed for {}: expected {} bytes, copied {}",
+            source.display(),
+            source_len,
+            bytes_copied
+        );
+    }
+    Ok(())
+}
+
+fn is_cross_device(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EXDEV))
+}
+
+fn is_reflink_unsupported(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::Unsupported
+        || matches!(
+            e.raw_os_error(),
+            Some(libc::EXDEV) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ENOTTY)
+        )
+}
+
+/// True if `a` and `b` refer to the same file on disk (same device and
+/// inode). A symlink at `b` is not followed.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(a), fs::symlink_metadata(b)) {
+            (Ok(ma), Ok(mb)) => ma.dev() == mb.dev() && ma.ino() == mb.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// True if `link` is a symlink pointing at `target`.
+fn links_to(link: &Path, target: &Path) -> bool {
+    fs::read_link(link).is_ok_and(|t| t == target)
+}
+
+/// True if `b` is a regular file with the same bytes as `a`.
+fn same_content(a: &Path, b: &Path) -> bool {
+    let (Ok(ma), Ok(mb)) = (fs::metadata(a), fs::symlink_metadata(b)) else {
+        return false;
+    };
+    if !mb.is_file() || ma.len() != mb.len() {
+        return false;
+    }
+    let (Ok(mut fa), Ok(mut fb)) = (fs::File::open(a), fs::File::open(b)) else {
+        return false;
+    };
+    let (mut ba, mut bb) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        match (fill(&mut fa, &mut ba), fill(&mut fb, &mut bb)) {
+            (Ok(0), Ok(0)) => return true,
+            (Ok(na), Ok(nb)) if na == nb && ba[..na] == bb[..nb] => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Read into `buf` until it is full or the file ends.
+fn fill(file: &mut fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    use std::io::Read;
+
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
         }
     }
+    Ok(n)
+}
+
+#[cfg(unix)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = fs::File::open(source)?;
+    let dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)?;
+
+    // SAFETY: both descriptors are valid for the duration of the call
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret == -1 {
+        let err = std::io::Error::last_os_error();
+        drop(dst);
+        let _ = fs::remove_file(dest);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(source: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let src = CString::new(source.as_os_str().as_bytes())?;
+    let dst = CString::new(dest.as_os_str().as_bytes())?;
+
+    // SAFETY: both pointers are valid NUL-terminated strings
+    let ret = unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_source: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::temp_dir;
     use std::fs;
 
     #[test]
@@ -322,8 +1177,9 @@ mod tests {
             album: "Album".to_string(),
             title: Some("Song Title".to_string()),
             track_number: Some(1),
+            ..Default::default()
         };
-        let result = compute_destination(&base, &source, &meta);
+        let result = compute_destination(&base, &source, &meta, &Template::default()).unwrap();
         assert_eq!(result.folder_name, "Artist - Album");
         assert_eq!(result.file_name, "01 - Song Title.m4a");
         assert_eq!(result.dest, PathBuf::from("/music/Artist - Album/01 - Song Title.m4a"));
@@ -338,8 +1194,9 @@ mod tests {
             album: "B".to_string(),
             title: Some("Title".to_string()),
             track_number: None,
+            ..Default::default()
         };
-        let result = compute_destination(&base, &source, &meta);
+        let result = compute_destination(&base, &source, &meta, &Template::default()).unwrap();
         assert_eq!(result.file_name, "Title.mp3");
     }
 
@@ -352,8 +1209,9 @@ mod tests {
             album: "Y".to_string(),
             title: None,
             track_number: Some(3),
+            ..Default::default()
         };
-        let result = compute_destination(&base, &source, &meta);
+        let result = compute_destination(&base, &source, &meta, &Template::default()).unwrap();
         assert_eq!(result.file_name, "03 - 03 Original Name.flac");
     }
 
@@ -366,21 +1224,163 @@ mod tests {
             album: "Back in Black".to_string(),
             title: Some("Hells Bells".to_string()),
             track_number: Some(1),
+            ..Default::default()
         };
-        let result = compute_destination(&base, &source, &meta);
+        let result = compute_destination(&base, &source, &meta, &Template::default()).unwrap();
         assert_eq!(result.folder_name, "AC-DC - Back in Black");
     }
 
+    #[test]
+    fn compute_destination_custom_template_sanitizes_components() {
+        let base = PathBuf::from("/music");
+        let source = PathBuf::from("/downloads/song.mp3");
+        let meta = TrackMetadata {
+            artist: "AC/DC".to_string(),
+            album: "Live: 1992".to_string(),
+            title: Some("Thunderstruck".to_string()),
+            track_number: Some(7),
+            ..Default::default()
+        };
+        let template = Template::parse("{artist}/{album}/{track:03}. {title}.{ext}").unwrap();
+        let result = compute_destination(&base, &source, &meta, &template).unwrap();
+        assert_eq!(result.folder_name, "AC-DC/Live 1992");
+        assert_eq!(result.file_name, "007. Thunderstruck.mp3");
+        assert_eq!(
+            result.dest,
+            PathBuf::from("/music/AC-DC/Live 1992/007. Thunderstruck.mp3")
+        );
+    }
+
+    #[test]
+    fn compute_destination_extended_fields() {
+        let base = PathBuf::from("/music");
+        let source = PathBuf::from("/downloads/song.flac");
+        let meta = TrackMetadata {
+            artist: "Band feat. Guest".to_string(),
+            album: "Album".to_string(),
+            title: Some("Song".to_string()),
+            track_number: Some(4),
+            album_artist: Some("Band".to_string()),
+            disc_number: Some(2),
+            year: Some(2001),
+            ..Default::default()
+        };
+        let template =
+            Template::parse("{albumartist}/{year} - {album}/{disc}-{track:02} {title}.{ext}")
+                .unwrap();
+        let result = compute_destination(&base, &source, &meta, &template).unwrap();
+        assert_eq!(result.folder_name, "Band/2001 - Album");
+        assert_eq!(result.file_name, "2-04 Song.flac");
+    }
+
+    #[test]
+    fn compute_destination_multi_disc_layouts() {
+        use crate::template::DiscLayout;
+
+        let base = PathBuf::from("/music");
+        let source = PathBuf::from("/downloads/song.flac");
+        let meta = TrackMetadata {
+            artist: "A".to_string(),
+            album: "B".to_string(),
+            title: Some("Song".to_string()),
+            track_number: Some(3),
+            disc_number: Some(2),
+            disc_total: Some(2),
+            ..Default::default()
+        };
+
+        let prefix = compute_destination(&base, &source, &meta, &DiscLayout::Prefix.template());
+        assert_eq!(prefix.unwrap().file_name, "2-03 - Song.flac");
+
+        let folder = compute_destination(&base, &source, &meta, &DiscLayout::Folder.template())
+            .unwrap();
+        assert_eq!(folder.folder_name, "A - B/Disc 2");
+        assert_eq!(folder.file_name, "03 - Song.flac");
+
+        let single = TrackMetadata {
+            disc_total: Some(1),
+            ..meta
+        };
+        let result = compute_destination(&base, &source, &single, &Template::default());
+        assert_eq!(result.unwrap().file_name, "03 - Song.flac");
+    }
+
+    #[test]
+    fn compute_destination_missing_required_field() {
+        let base = PathBuf::from("/music");
+        let source = PathBuf::from("/downloads/song.mp3");
+        let meta = TrackMetadata {
+            artist: "A".to_string(),
+            album: "B".to_string(),
+            title: None,
+            track_number: None,
+            ..Default::default()
+        };
+        let template = Template::parse("{artist}/{title}.{ext}").unwrap();
+        assert!(compute_destination(&base, &source, &meta, &template).is_none());
+    }
+
     #[test]
     fn compute_unsorted_preserves_filename() {
         let base = PathBuf::from("/music");
         let source = PathBuf::from("/downloads/weird file.m4a");
-        let result = compute_unsorted_destination(&base, &source);
+        let result = compute_unsorted_destination(&base, &source, UnsortedReason::NoTags);
         assert_eq!(result.folder_name, "_Unsorted");
         assert_eq!(result.file_name, "weird file.m4a");
         assert_eq!(result.dest, PathBuf::from("/music/_Unsorted/weird file.m4a"));
     }
 
+    #[test]
+    fn custom_layout_rules() {
+        let layout = Layout {
+            unsorted_folder: "Inbox".to_string(),
+            sanitize: SanitizeRules {
+                separator: "+".to_string(),
+                remove: "?".to_string(),
+                replace: BTreeMap::from([(':', " -".to_string()), ('*', "/".to_string())]),
+            },
+            max_conflict_attempts: 1,
+            ..Layout::default()
+        };
+        assert_eq!(layout.sanitize.apply("AC/DC: Live?"), "AC+DC - Live");
+        assert_eq!(layout.sanitize.apply("a*b\"c"), "ab\"c");
+
+        let unsorted = layout.unsorted_destination(
+            Path::new("/music"),
+            Path::new("/in/x.mp3"),
+            UnsortedReason::Corrupt,
+        );
+        assert_eq!(unsorted.dest, PathBuf::from("/music/Inbox/x.mp3"));
+        assert_eq!(unsorted.unsorted, Some(UnsortedReason::Corrupt));
+
+        let by_reason = Layout {
+            unsorted_by_reason: true,
+            ..layout.clone()
+        };
+        let unsorted = by_reason.unsorted_destination(
+            Path::new("/music"),
+            Path::new("/in/x.mp3"),
+            UnsortedReason::MissingAlbum,
+        );
+        assert_eq!(unsorted.folder_name, "Inbox/missing-album");
+        assert_eq!(
+            unsorted.dest,
+            PathBuf::from("/music/Inbox/missing-album/x.mp3")
+        );
+
+        let mut moves: Vec<PlannedMove> = (0..3)
+            .map(|i| PlannedMove {
+                source: PathBuf::from(format!("/in/{}.mp3", i)),
+                dest: PathBuf::from("/music/A/song.mp3"),
+                ..Default::default()
+            })
+            .collect();
+        layout.resolve_conflicts(Path::new("/music"), &mut moves, None);
+        assert_eq!(moves[1].dest, PathBuf::from("/music/A/song (1).mp3"));
+        // Out of attempts: the last candidate is kept and fails at execution
+        assert_eq!(moves[2].dest, PathBuf::from("/music/A/song (1).mp3"));
+    }
+
     #[test]
     fn resolve_conflicts_intra_batch() {
         let mut moves = vec![
@@ -389,17 +1389,20 @@ mod tests {
                 dest: PathBuf::from("/b/song.mp3"),
                 folder_name: "folder".to_string(),
                 file_name: "song.mp3".to_string(),
+                ..Default::default()
             },
             PlannedMove {
                 source: PathBuf::from("/a/file2.mp3"),
                 dest: PathBuf::from("/b/song.mp3"),
                 folder_name: "folder".to_string(),
                 file_name: "song.mp3".to_string(),
+                ..Default::default()
             },
         ];
-        resolve_conflicts(&mut moves);
+        resolve_conflicts(Path::new("/b"), &mut moves);
         assert_eq!(moves[0].file_name, "song.mp3");
         assert_eq!(moves[1].file_name, "song (1).mp3");
+        assert_eq!(moves[1].reason(), MoveReason::RenamedForConflict);
         assert_ne!(moves[0].dest, moves[1].dest);
     }
 
@@ -411,16 +1414,130 @@ mod tests {
             dest: same.clone(),
             folder_name: "Artist - Album".to_string(),
             file_name: "01 - Song.m4a".to_string(),
+            ..Default::default()
         }];
-        resolve_conflicts(&mut moves);
+        resolve_conflicts(Path::new("/music"), &mut moves);
         assert_eq!(moves[0].dest, same);
+        assert_eq!(moves[0].reason(), MoveReason::InPlace);
+    }
+
+    fn tagged_move(source: &Path, dest: &Path) -> PlannedMove {
+        PlannedMove {
+            source: source.to_path_buf(),
+            dest: dest.to_path_buf(),
+            folder_name: "A - B".to_string(),
+            file_name: "01 - Song.flac".to_string(),
+            meta: Some(TrackMetadata::default()),
+            ..Default::default()
+        }
     }
 
     #[test]
-    fn execute_move_creates_dirs_and_moves() {
-        let tmp = std::env::temp_dir().join("tagmv_test_move");
+    fn resolve_conflicts_skip_and_overwrite() {
+        let dest = Path::new("/music/A - B/01 - Song.flac");
+        let batch = || {
+            vec![
+                tagged_move(Path::new("/in/1.flac"), dest),
+                tagged_move(Path::new("/in/2.flac"), dest),
+            ]
+        };
+        let layout = |on_conflict| Layout {
+            on_conflict,
+            ..Layout::default()
+        };
+
+        let mut moves = batch();
+        layout(ConflictPolicy::Skip).resolve_conflicts(Path::new("/music"), &mut moves, None);
+        assert!(moves[0].needs_transfer());
+        assert_eq!(moves[1].reason(), MoveReason::SkippedForConflict);
+        assert!(!moves[1].needs_transfer());
+
+        // Within a batch the last file wins
+        let mut moves = batch();
+        layout(ConflictPolicy::Overwrite).resolve_conflicts(Path::new("/music"), &mut moves, None);
+        assert_eq!(moves[0].conflict, Some(Conflict::Skipped));
+        assert_eq!(moves[1].conflict, None);
+
+        // Without anyone to ask, the conflict stays undecided
+        let mut moves = batch();
+        let ask = |_: &ConflictCase<'_>| ConflictPolicy::Rename;
+        layout(ConflictPolicy::Ask).resolve_conflicts(Path::new("/music"), &mut moves, Some(&ask));
+        assert_eq!(moves[1].file_name, "01 - Song (1).flac");
+        let mut moves = batch();
+        layout(ConflictPolicy::Ask).resolve_conflicts(Path::new("/music"), &mut moves, None);
+        assert_eq!(moves[1].conflict, Some(Conflict::Undecided));
+        assert_eq!(moves[1].reason(), MoveReason::Undecided);
+        assert!(!moves[1].needs_transfer());
+    }
+
+    #[test]
+    fn resolve_conflicts_keeps_the_better_copy() {
+        let tmp = temp_dir("keep_better");
+        fs::create_dir_all(tmp.join("in")).unwrap();
+        fs::create_dir_all(tmp.join("lib/A - B")).unwrap();
+        let lib = tmp.join("lib");
+        let dest = lib.join("A - B/01 - Song.flac");
+        fs::write(&dest, "old rip").unwrap();
+        fs::write(tmp.join("in/big.flac"), "a better, bigger rip").unwrap();
+        fs::write(tmp.join("in/small.flac"), "tiny").unwrap();
+        let layout = Layout {
+            on_conflict: ConflictPolicy::KeepLarger,
+            ..Layout::default()
+        };
+
+        // The bigger file displaces the library copy, the smaller one loses
+        let mut moves = vec![
+            tagged_move(&tmp.join("in/big.flac"), &dest),
+            tagged_move(&tmp.join("in/small.flac"), &dest),
+        ];
+        layout.resolve_conflicts(&lib, &mut moves, None);
+        let reasons: Vec<MoveReason> = moves.iter().map(|m| m.reason()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                MoveReason::Displaced,
+                MoveReason::Preferred,
+                MoveReason::Duplicate
+            ]
+        );
+        assert_eq!(moves[0].source, dest);
+        assert_eq!(moves[0].dest, lib.join("_Duplicates/A - B/01 - Song.flac"));
+        assert_eq!(moves[0].folder_name, "_Duplicates/A - B");
+        assert_eq!(moves[1].dest, dest);
+        assert_eq!(
+            moves[2].dest,
+            lib.join("_Duplicates/A - B/01 - Song (1).flac")
+        );
+
+        // Untagged files are never compared
+        let mut moves = vec![PlannedMove {
+            meta: None,
+            ..tagged_move(&tmp.join("in/big.flac"), &dest)
+        }];
+        layout.resolve_conflicts(&lib, &mut moves, None);
+        assert_eq!(moves[0].reason(), MoveReason::RenamedForConflict);
+
         let _ = fs::remove_dir_all(&tmp);
-        fs::create_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn execute_move_overwrites_when_planned() {
+        let (tmp, mut planned) = transfer_fixture("overwrite", TransferMode::Move);
+        fs::create_dir_all(planned.dest.parent().unwrap()).unwrap();
+        fs::write(&planned.dest, "old").unwrap();
+        assert!(execute_move(&planned).is_err());
+
+        planned.conflict = Some(Conflict::Overwrites);
+        assert_eq!(execute_move(&planned).unwrap(), TransferMode::Move);
+        assert!(!planned.source.exists());
+        assert_eq!(fs::read_to_string(&planned.dest).unwrap(), "test content");
+        assert_eq!(fs::read_dir(tmp.join("lib")).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn execute_move_creates_dirs_and_moves() {
+        let tmp = temp_dir("move");
 
         let source = tmp.join("source.txt");
         fs::write(&source, "test content").unwrap();
@@ -431,6 +1548,7 @@ mod tests {
             dest: dest.clone(),
             folder_name: "subdir".to_string(),
             file_name: "dest.txt".to_string(),
+            ..Default::default()
         };
 
         execute_move(&planned).unwrap();
@@ -449,6 +1567,7 @@ mod tests {
             dest: same,
             folder_name: "f".to_string(),
             file_name: "same.mp3".to_string(),
+            ..Default::default()
         };
         // Should not error even though path doesn't exist
         execute_move(&planned).unwrap();
@@ -456,9 +1575,7 @@ mod tests {
 
     #[test]
     fn execute_move_refuses_existing_dest() {
-        let tmp = std::env::temp_dir().join("tagmv_test_conflict");
-        let _ = fs::remove_dir_all(&tmp);
-        fs::create_dir_all(&tmp).unwrap();
+        let tmp = temp_dir("conflict");
 
         let source = tmp.join("a.txt");
         let dest = tmp.join("b.txt");
@@ -470,6 +1587,7 @@ mod tests {
             dest: dest.clone(),
             folder_name: "f".to_string(),
             file_name: "b.txt".to_string(),
+            ..Default::default()
         };
 
         let result = execute_move(&planned);
@@ -481,4 +1599,114 @@ mod tests {
 
         let _ = fs::remove_dir_all(&tmp);
     }
+
+    fn transfer_fixture(name: &str, mode: TransferMode) -> (PathBuf, PlannedMove) {
+        let tmp = temp_dir(name);
+
+        let source = tmp.join("source.txt");
+        fs::write(&source, "test content").unwrap();
+
+        let planned = PlannedMove {
+            source,
+            dest: tmp.join("lib/dest.txt"),
+            folder_name: "lib".to_string(),
+            file_name: "dest.txt".to_string(),
+            mode,
+            ..Default::default()
+        };
+        (tmp, planned)
+    }
+
+    #[test]
+    fn execute_copy_keeps_source() {
+        let (tmp, planned) = transfer_fixture("copy", TransferMode::Copy);
+        assert_eq!(execute_move(&planned).unwrap(), TransferMode::Copy);
+        assert!(planned.source.exists());
+        assert_eq!(fs::read_to_string(&planned.dest).unwrap(), "test content");
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    /// The move `planned` becomes when it is planned again in `mode`.
+    fn replan(tmp: &Path, planned: &PlannedMove, mode: TransferMode) -> PlannedMove {
+        let mut moves = vec![PlannedMove {
+            source: planned.source.clone(),
+            dest: planned.dest.clone(),
+            mode,
+            ..Default::default()
+        }];
+        resolve_conflicts(tmp, &mut moves);
+        moves.pop().unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_hardlink_shares_inode_and_is_in_place_afterwards() {
+        let (tmp, planned) = transfer_fixture("hardlink", TransferMode::Hardlink);
+        assert!(!planned.is_in_place());
+        assert_eq!(execute_move(&planned).unwrap(), TransferMode::Hardlink);
+        assert!(planned.source.exists());
+        assert!(same_file(&planned.source, &planned.dest));
+        assert!(replan(&tmp, &planned, TransferMode::Hardlink).is_in_place());
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_symlink_points_at_source() {
+        let (tmp, planned) = transfer_fixture("symlink", TransferMode::Symlink);
+        assert_eq!(execute_move(&planned).unwrap(), TransferMode::Symlink);
+        assert_eq!(fs::read_link(&planned.dest).unwrap(), planned.source);
+        assert_eq!(fs::read_to_string(&planned.dest).unwrap(), "test content");
+        assert!(replan(&tmp, &planned, TransferMode::Symlink).is_in_place());
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn rerunning_a_copy_finds_it_in_place() {
+        let (tmp, planned) = transfer_fixture("copy_rerun", TransferMode::Copy);
+        execute_move(&planned).unwrap();
+
+        let rerun = |content: &str| {
+            fs::write(&planned.source, content).unwrap();
+            let mut moves = vec![PlannedMove {
+                source: planned.source.clone(),
+                dest: planned.dest.clone(),
+                file_name: "dest.txt".to_string(),
+                mode: TransferMode::Copy,
+                ..Default::default()
+            }];
+            resolve_conflicts(&tmp, &mut moves);
+            moves.pop().unwrap()
+        };
+        let same = rerun("test content");
+        assert!(same.is_in_place() && same.conflict.is_none());
+        assert_eq!(same.dest, planned.dest);
+        // Edited since: a conflict like any other
+        let changed = rerun("test CONTENT");
+        assert!(!changed.is_in_place());
+        assert_eq!(changed.conflict, Some(Conflict::Renamed));
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_only_count_in_their_own_mode() {
+        let (tmp, planned) = transfer_fixture("link_modes", TransferMode::Symlink);
+        execute_move(&planned).unwrap();
+        // Still what was planned after the plan has run
+        assert!(!planned.is_in_place());
+        assert!(replan(&tmp, &planned, TransferMode::Symlink).is_in_place());
+        assert!(!replan(&tmp, &planned, TransferMode::Hardlink).is_in_place());
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn execute_reflink_clones_or_falls_back_to_copy() {
+        let (tmp, planned) = transfer_fixture("reflink", TransferMode::Reflink);
+        let applied = execute_move(&planned).unwrap();
+        assert!(matches!(applied, TransferMode::Reflink | TransferMode::Copy));
+        assert!(planned.source.exists());
+        assert_eq!(fs::read_to_string(&planned.dest).unwrap(), "test content");
+        let _ = fs::remove_dir_all(&tmp);
+    }
 }
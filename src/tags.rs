@@ -1,35 +1,517 @@
+use anyhow::Context;
+use lofty::config::WriteOptions;
+use lofty::file::FileType;
 use lofty::prelude::*;
 use lofty::probe::Probe;
+use lofty::tag::{ItemKey, Tag};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TrackMetadata {
     pub artist: String,
     pub album: String,
     pub title: Option<String>,
     pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub album_artist: Option<String>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub year: Option<u32>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub compilation: bool,
+    pub musicbrainz: MusicBrainzIds,
 }
 
-pub fn read_tags(path: &Path) -> Option<TrackMetadata> {
-    let tagged_file = Probe::open(path).ok()?.read().ok()?;
+/// MusicBrainz identifiers, as written by Picard and similar taggers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MusicBrainzIds {
+    pub recording_id: Option<String>,
+    pub track_id: Option<String>,
+    pub release_id: Option<String>,
+    pub release_group_id: Option<String>,
+    pub artist_id: Option<String>,
+    pub release_artist_id: Option<String>,
+}
 
-    let tag = tagged_file
-        .primary_tag()
-        .or_else(|| tagged_file.first_tag())?;
+impl TrackMetadata {
+    /// True if this track belongs to an album with more than one disc.
+    pub fn is_multi_disc(&self) -> bool {
+        self.disc_total.is_some_and(|n| n > 1)
+    }
 
-    let artist = tag.artist()?.to_string();
-    let album = tag.album()?.to_string();
+    /// Value of a template field (see `template::FIELDS`), if present.
+    /// File-derived fields (`filename`, `ext`) are not handled here.
+    pub fn field(&self, name: &str) -> Option<String> {
+        let number = |n: Option<u32>| n.map(|n| n.to_string());
+        match name {
+            "artist" => Some(self.artist.clone()),
+            "album" => Some(self.album.clone()),
+            "albumartist" => self.album_artist.clone(),
+            "title" => self.title.clone(),
+            "track" => number(self.track_number),
+            "tracktotal" => number(self.track_total),
+            "disc" => number(self.disc_number),
+            "disctotal" => number(self.disc_total),
+            "multidisc" => number(self.disc_number).filter(|_| self.is_multi_disc()),
+            "year" => number(self.year),
+            "date" => self.date.clone(),
+            "genre" => self.genre.clone(),
+            "composer" => self.composer.clone(),
+            "compilation" => self.compilation.then(|| "Compilations".to_string()),
+            "mb_recordingid" => self.musicbrainz.recording_id.clone(),
+            "mb_trackid" => self.musicbrainz.track_id.clone(),
+            "mb_albumid" => self.musicbrainz.release_id.clone(),
+            "mb_releasegroupid" => self.musicbrainz.release_group_id.clone(),
+            "mb_artistid" => self.musicbrainz.artist_id.clone(),
+            "mb_albumartistid" => self.musicbrainz.release_artist_id.clone(),
+            _ => None,
+        }
+    }
+}
 
-    if artist.is_empty() || album.is_empty() {
+/// Open `path` for reading. The format is sniffed from the content and only
+/// taken from the extension if the content is not recognized, so `.oga`
+/// files and misnamed files are read too.
+pub(crate) fn probe(path: &Path) -> Option<Probe<BufReader<File>>> {
+    Probe::open(path).ok()?.guess_file_type().ok()
+}
+
+/// The format of `path` judged by its content alone, if lofty reads it.
+pub fn detect_format(path: &Path) -> Option<FileType> {
+    let file = File::open(path).ok()?;
+    Probe::new(BufReader::new(file))
+        .guess_file_type()
+        .ok()?
+        .file_type()
+}
+
+/// Extensions that fit each format, the usual one first.
+fn format_extensions(format: FileType) -> &'static [&'static str] {
+    match format {
+        FileType::Mpeg => &["mp3", "mp2", "mp1"],
+        FileType::Mp4 => &["m4a", "m4b", "mp4", "m4p", "m4r"],
+        FileType::Aac => &["aac"],
+        FileType::Flac => &["flac"],
+        FileType::Vorbis => &["ogg", "oga"],
+        FileType::Opus => &["opus", "ogg", "oga"],
+        FileType::Speex => &["spx", "ogg", "oga"],
+        FileType::Wav => &["wav", "wave"],
+        FileType::Aiff => &["aiff", "aif", "aifc", "afc"],
+        FileType::Ape => &["ape"],
+        FileType::WavPack => &["wv"],
+        FileType::Mpc => &["mpc", "mp+", "mpp"],
+        _ => &[],
+    }
+}
+
+/// The extension `path` should have according to its content, if its
+/// current one (or the lack of one) does not fit.
+pub fn corrected_extension(path: &Path) -> Option<&'static str> {
+    let extensions = format_extensions(detect_format(path)?);
+    let current = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if extensions.iter().any(|e| e.eq_ignore_ascii_case(current)) {
         return None;
     }
+    extensions.first().copied()
+}
+
+/// Why a file cannot be sorted by its tags, so it goes to the unsorted
+/// folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnsortedReason {
+    /// The content is not an audio format that tags can be read from.
+    Unsupported,
+    /// The format is known, but the file could not be read: truncated,
+    /// corrupt, or not what its extension claims.
+    Corrupt,
+    /// No tag, or one with neither artist nor album.
+    NoTags,
+    MissingArtist,
+    MissingAlbum,
+    /// Artist and album are there, but the template needs a field the file
+    /// does not have.
+    MissingFields,
+}
+
+impl UnsortedReason {
+    /// Subfolder of the unsorted folder for files of this reason.
+    pub fn folder_name(self) -> &'static str {
+        match self {
+            UnsortedReason::Unsupported => "unsupported",
+            UnsortedReason::Corrupt => "corrupt",
+            UnsortedReason::NoTags => "no-tags",
+            UnsortedReason::MissingArtist => "missing-artist",
+            UnsortedReason::MissingAlbum => "missing-album",
+            UnsortedReason::MissingFields => "missing-fields",
+        }
+    }
+}
+
+impl fmt::Display for UnsortedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnsortedReason::Unsupported => "unsupported format",
+            UnsortedReason::Corrupt => "unreadable",
+            UnsortedReason::NoTags => "no tags",
+            UnsortedReason::MissingArtist => "no artist tag",
+            UnsortedReason::MissingAlbum => "no album tag",
+            UnsortedReason::MissingFields => "tags missing for the template",
+        })
+    }
+}
+
+/// The tags of `path`, or why they are not enough to sort it. Artist and
+/// album must be present and not empty.
+pub fn read_tags(path: &Path) -> Result<TrackMetadata, UnsortedReason> {
+    sortable(read_partial_tags(path)?)
+}
+
+/// `meta` if it has an artist and an album to be sorted by, or why not.
+pub fn sortable(meta: TrackMetadata) -> Result<TrackMetadata, UnsortedReason> {
+    match (meta.artist.is_empty(), meta.album.is_empty()) {
+        (false, false) => Ok(meta),
+        (true, true) => Err(UnsortedReason::NoTags),
+        (true, false) => Err(UnsortedReason::MissingArtist),
+        (false, true) => Err(UnsortedReason::MissingAlbum),
+    }
+}
+
+/// Whatever tags `path` has; artist and album are empty where missing.
+pub fn read_partial_tags(path: &Path) -> Result<TrackMetadata, UnsortedReason> {
+    let probe = probe(path).ok_or(UnsortedReason::Corrupt)?;
+    if probe.file_type().is_none() {
+        return Err(UnsortedReason::Unsupported);
+    }
+    let tagged_file = probe.read().map_err(|_| UnsortedReason::Corrupt)?;
+
+    let tag = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag())
+        .ok_or(UnsortedReason::NoTags)?;
+
+    let present = |value: Option<std::borrow::Cow<'_, str>>| {
+        value.map(|v| v.to_string()).filter(|v| !v.is_empty())
+    };
+    let artist = present(tag.artist()).unwrap_or_default();
+    let album = present(tag.album()).unwrap_or_default();
 
     let title = tag.title().map(|t| t.to_string()).filter(|t| !t.is_empty());
     let track_number = tag.track();
 
-    Some(TrackMetadata {
+    Ok(TrackMetadata {
         artist,
         album,
         title,
         track_number,
+        track_total: tag.track_total(),
+        album_artist: text(tag, &ItemKey::AlbumArtist),
+        disc_number: tag.disk(),
+        disc_total: tag.disk_total(),
+        year: tag.year(),
+        date: text(tag, &ItemKey::RecordingDate).or_else(|| text(tag, &ItemKey::Year)),
+        genre: tag.genre().map(|g| g.to_string()).filter(|g| !g.is_empty()),
+        composer: text(tag, &ItemKey::Composer),
+        compilation: text(tag, &ItemKey::FlagCompilation).is_some_and(|v| is_truthy(&v)),
+        musicbrainz: MusicBrainzIds {
+            recording_id: text(tag, &ItemKey::MusicBrainzRecordingId),
+            track_id: text(tag, &ItemKey::MusicBrainzTrackId),
+            release_id: text(tag, &ItemKey::MusicBrainzReleaseId),
+            release_group_id: text(tag, &ItemKey::MusicBrainzReleaseGroupId),
+            artist_id: text(tag, &ItemKey::MusicBrainzArtistId),
+            release_artist_id: text(tag, &ItemKey::MusicBrainzReleaseArtistId),
+        },
     })
 }
+
+/// Template fields that [`write_tags`] can write.
+pub const WRITABLE_FIELDS: &[&str] = &[
+    "artist",
+    "album",
+    "albumartist",
+    "title",
+    "track",
+    "disc",
+    "disctotal",
+    "year",
+    "genre",
+];
+
+/// One tag value that `--write-tags` changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TagChange {
+    /// Template field name (one of [`WRITABLE_FIELDS`]).
+    pub field: &'static str,
+    /// The value in the file now, if any.
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// The changes that bring the tags of `path` in line with `meta`. Fields
+/// that `meta` leaves empty are kept as they are, never removed.
+pub fn tag_changes(path: &Path, meta: &TrackMetadata) -> Vec<TagChange> {
+    let file = probe(path).and_then(|p| p.read().ok());
+    let tag = file
+        .as_ref()
+        .and_then(|f| f.primary_tag().or_else(|| f.first_tag()));
+
+    WRITABLE_FIELDS
+        .iter()
+        .filter_map(|&field| {
+            let new = meta.field(field).filter(|v| !v.trim().is_empty())?;
+            let old = tag.and_then(|tag| tag_value(tag, field));
+            (old.as_ref() != Some(&new)).then_some(TagChange { field, old, new })
+        })
+        .collect()
+}
+
+/// Write `changes` into the tags of `path`. A file without a tag of its
+/// format's primary type gets one, starting from the tag it has, if any.
+pub fn write_tags(path: &Path, changes: &[TagChange]) -> anyhow::Result<()> {
+    let mut file = probe(path)
+        .context("Unrecognized audio format")?
+        .read()
+        .context("Failed to read the file's tags")?;
+
+    let tag_type = file.primary_tag_type();
+    if file.tag(tag_type).is_none() {
+        let mut tag = file
+            .first_tag()
+            .cloned()
+            .unwrap_or_else(|| Tag::new(tag_type));
+        tag.re_map(tag_type);
+        file.insert_tag(tag);
+    }
+    let Some(tag) = file.tag_mut(tag_type) else {
+        anyhow::bail!("The format cannot hold {:?} tags", tag_type);
+    };
+
+    for change in changes {
+        let value = change.new.clone();
+        let number = value.parse::<u32>();
+        match (change.field, number) {
+            ("artist", _) => tag.set_artist(value),
+            ("album", _) => tag.set_album(value),
+            ("albumartist", _) => {
+                tag.insert_text(ItemKey::AlbumArtist, value);
+            }
+            ("title", _) => tag.set_title(value),
+            ("track", Ok(n)) => tag.set_track(n),
+            ("disc", Ok(n)) => tag.set_disk(n),
+            ("disctotal", Ok(n)) => tag.set_disk_total(n),
+            ("year", Ok(n)) => tag.set_year(n),
+            ("genre", _) => tag.set_genre(value),
+            _ => {}
+        }
+    }
+
+    file.save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("Failed to write tags: {}", path.display()))
+}
+
+/// The value of a [`WRITABLE_FIELDS`] field in `tag`, as `field()` would
+/// render it.
+fn tag_value(tag: &Tag, field: &str) -> Option<String> {
+    let number = |n: Option<u32>| n.map(|n| n.to_string());
+    let value = match field {
+        "artist" => tag.artist().map(|v| v.to_string()),
+        "album" => tag.album().map(|v| v.to_string()),
+        "albumartist" => text(tag, &ItemKey::AlbumArtist),
+        "title" => tag.title().map(|v| v.to_string()),
+        "track" => number(tag.track()),
+        "disc" => number(tag.disk()),
+        "disctotal" => number(tag.disk_total()),
+        "year" => number(tag.year()),
+        "genre" => tag.genre().map(|v| v.to_string()),
+        _ => None,
+    };
+    value.filter(|v| !v.is_empty())
+}
+
+/// Audio properties used to pick the better of two copies of a track.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AudioQuality {
+    /// File size in bytes.
+    pub size: u64,
+    /// Audio bitrate in kbps, if known.
+    pub bitrate: Option<u32>,
+    pub lossless: bool,
+}
+
+/// Read the audio properties of `path`. Unreadable files get the lowest
+/// quality, so they never win a comparison on bitrate or losslessness.
+pub fn read_quality(path: &Path) -> AudioQuality {
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let Some(tagged_file) = probe(path).and_then(|p| p.read().ok()) else {
+        return AudioQuality {
+            size,
+            ..Default::default()
+        };
+    };
+
+    let properties = tagged_file.properties();
+    // Of the MP4 codecs only the lossless ones (ALAC, FLAC) report a bit depth
+    let lossless = match tagged_file.file_type() {
+        FileType::Flac | FileType::Wav | FileType::Aiff | FileType::Ape | FileType::WavPack => true,
+        FileType::Mp4 => properties.bit_depth().is_some(),
+        _ => false,
+    };
+
+    AudioQuality {
+        size,
+        bitrate: properties.audio_bitrate(),
+        lossless,
+    }
+}
+
+/// Non-empty, trimmed text value for `key`.
+fn text(tag: &Tag, key: &ItemKey) -> Option<String> {
+    tag.get_string(key)
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Compilation flags are stored as "1", "true", etc. depending on the format.
+fn is_truthy(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mpeg_frames, temp_dir};
+
+    #[test]
+    fn field_maps_template_names() {
+        let meta = TrackMetadata {
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            album_artist: Some("Band".to_string()),
+            disc_number: Some(2),
+            year: Some(1999),
+            ..Default::default()
+        };
+        assert_eq!(meta.field("albumartist").as_deref(), Some("Band"));
+        assert_eq!(meta.field("disc").as_deref(), Some("2"));
+        assert_eq!(meta.field("multidisc"), None);
+        assert_eq!(meta.field("year").as_deref(), Some("1999"));
+        assert_eq!(meta.field("genre"), None);
+        assert_eq!(meta.field("compilation"), None);
+        assert_eq!(meta.field("nonsense"), None);
+    }
+
+    #[test]
+    fn reads_tags_by_content() {
+        let tmp = temp_dir("tags_by_content");
+
+        // An MP3 (ID3v2.4 tag, then MPEG frames) under an Ogg extension
+        let mut frames = Vec::new();
+        for (id, text) in [(b"TPE1", "Artist"), (b"TALB", "Album")] {
+            frames.extend_from_slice(id);
+            frames.extend_from_slice(&[0, 0, 0, text.len() as u8 + 1, 0, 0, 3]);
+            frames.extend_from_slice(text.as_bytes());
+        }
+        let mut file = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        file.push(frames.len() as u8);
+        file.extend(frames);
+        file.extend(mpeg_frames());
+        let path = tmp.join("song.ogg");
+        std::fs::write(&path, file).unwrap();
+
+        let meta = read_tags(&path).unwrap();
+        assert_eq!(meta.artist, "Artist");
+        assert_eq!(meta.album, "Album");
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn tells_why_tags_are_unusable() {
+        let tmp = temp_dir("unsorted_reasons");
+
+        // An ID3v2.4 tag with only an artist, then MPEG frames
+        let mut file =
+            b"ID3\x04\x00\x00\x00\x00\x00\x11TPE1\x00\x00\x00\x07\x00\x00\x03Artist".to_vec();
+        file.extend(mpeg_frames());
+        std::fs::write(tmp.join("artist-only.mp3"), file).unwrap();
+        std::fs::write(tmp.join("text.mp3"), "not audio").unwrap();
+        std::fs::write(tmp.join("text.wma"), "not audio").unwrap();
+
+        let reason = |name: &str| read_tags(&tmp.join(name)).unwrap_err();
+        assert_eq!(reason("artist-only.mp3"), UnsortedReason::MissingAlbum);
+        assert_eq!(reason("text.mp3"), UnsortedReason::Corrupt);
+        assert_eq!(reason("text.wma"), UnsortedReason::Unsupported);
+        assert_eq!(reason("missing.mp3"), UnsortedReason::Corrupt);
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn corrects_extensions_by_content() {
+        let tmp = temp_dir("corrected_extension");
+        let flac = b"fLaC\x80\x00\x00\x22".to_vec();
+        for name in ["a.flac", "b.FLAC", "c.mp3", "d", "e.txt"] {
+            let content = if name == "e.txt" {
+                b"text".to_vec()
+            } else {
+                flac.clone()
+            };
+            std::fs::write(tmp.join(name), content).unwrap();
+        }
+
+        let corrected = |name: &str| corrected_extension(&tmp.join(name));
+        assert_eq!(corrected("a.flac"), None);
+        assert_eq!(corrected("b.FLAC"), None);
+        assert_eq!(corrected("c.mp3"), Some("flac"));
+        assert_eq!(corrected("d"), Some("flac"));
+        assert_eq!(corrected("e.txt"), None);
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn writes_tags_into_untagged_files() {
+        let tmp = temp_dir("write_tags");
+        let path = tmp.join("song.mp3");
+        std::fs::write(&path, mpeg_frames()).unwrap();
+        assert_eq!(read_tags(&path).unwrap_err(), UnsortedReason::NoTags);
+
+        let meta = TrackMetadata {
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            album_artist: Some("Band".to_string()),
+            track_number: Some(3),
+            ..Default::default()
+        };
+        let changes = tag_changes(&path, &meta);
+        let fields: Vec<&str> = changes.iter().map(|c| c.field).collect();
+        assert_eq!(fields, vec!["artist", "album", "albumartist", "track"]);
+        assert!(changes.iter().all(|c| c.old.is_none()));
+
+        write_tags(&path, &changes).unwrap();
+        let written = read_tags(&path).unwrap();
+        assert_eq!(written.artist, "Artist");
+        assert_eq!(written.album_artist.as_deref(), Some("Band"));
+        assert_eq!(written.track_number, Some(3));
+        assert!(tag_changes(&path, &meta).is_empty());
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn compilation_flag_values() {
+        assert!(is_truthy("1"));
+        assert!(is_truthy(" True "));
+        assert!(!is_truthy("0"));
+        assert!(!is_truthy(""));
+    }
+}
@@ -0,0 +1,410 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// Layout used when no `--template` is given: `Artist - Album/01 - Title.ext`,
+/// grouped by album artist where known. Multi-disc albums get a `1-01` prefix.
+pub const DEFAULT_TEMPLATE: &str =
+    "{albumartist|artist} - {album}/[{multidisc}-][{track:02} - ]{title|filename}.{ext}";
+
+/// Layout for compilations (see `albums::detect_compilations`).
+pub const DEFAULT_COMPILATION_TEMPLATE: &str =
+    "Various Artists - {album}/[{multidisc}-][{track:02} - ]{artist} - {title|filename}.{ext}";
+
+/// Default layouts with a `Disc N/` subfolder for multi-disc albums.
+const FOLDER_TEMPLATE: &str =
+    "{albumartist|artist} - {album}/[Disc {multidisc}/][{track:02} - ]{title|filename}.{ext}";
+const FOLDER_COMPILATION_TEMPLATE: &str =
+    "Various Artists - {album}/[Disc {multidisc}/][{track:02} - ]{artist} - {title|filename}.{ext}";
+
+/// How the default templates lay out albums with more than one disc.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscLayout {
+    /// `Album/1-01 - Title.ext`
+    #[default]
+    Prefix,
+    /// `Album/Disc 1/01 - Title.ext`
+    Folder,
+}
+
+impl DiscLayout {
+    pub fn template(self) -> Template {
+        let source = match self {
+            DiscLayout::Prefix => DEFAULT_TEMPLATE,
+            DiscLayout::Folder => FOLDER_TEMPLATE,
+        };
+        Template::parse(source).expect("default template is valid")
+    }
+
+    pub fn compilation_template(self) -> Template {
+        let source = match self {
+            DiscLayout::Prefix => DEFAULT_COMPILATION_TEMPLATE,
+            DiscLayout::Folder => FOLDER_COMPILATION_TEMPLATE,
+        };
+        Template::parse(source).expect("default template is valid")
+    }
+}
+
+/// Field names a template may reference.
+pub const FIELDS: &[&str] = &[
+    "artist",
+    "album",
+    "albumartist",
+    "title",
+    "track",
+    "tracktotal",
+    "disc",
+    "disctotal",
+    "multidisc",
+    "year",
+    "date",
+    "genre",
+    "composer",
+    "compilation",
+    "mb_recordingid",
+    "mb_trackid",
+    "mb_albumid",
+    "mb_releasegroupid",
+    "mb_artistid",
+    "mb_albumartistid",
+    "filename",
+    "ext",
+];
+
+/// A parsed destination path template.
+///
+/// Syntax:
+/// - `{field}` substitutes a tag value, e.g. `{artist}`
+/// - `{field:02}` zero-pads numeric values to the given width
+/// - `{a|b|"text"}` tries each alternative in turn, quoted text is a literal
+/// - `[...]` is an optional section, dropped when any field inside is missing
+/// - `/` separates path components; `\` escapes the next character
+///
+/// A field that is missing outside of an optional section makes the whole
+/// template fail to render.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    source: String,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(String),
+    Separator,
+    Field(Field),
+    Optional(Vec<Node>),
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    alternatives: Vec<Alternative>,
+    width: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Alternative {
+    Field(String),
+    Literal(String),
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template> {
+        let mut chars = source.chars().peekable();
+        let nodes = parse_nodes(&mut chars, false)?;
+        if nodes.is_empty() {
+            bail!("Template is empty");
+        }
+        if matches!(nodes.last(), Some(Node::Separator)) {
+            bail!("Template must end with a file name, not '/'");
+        }
+        Ok(Template {
+            source: source.to_string(),
+            nodes,
+        })
+    }
+
+    /// Render the template into raw (unsanitized) path components.
+    /// Returns `None` if a required field is missing.
+    pub fn render<F>(&self, lookup: F) -> Option<Vec<String>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut components = vec![String::new()];
+        render_nodes(&self.nodes, &lookup, &mut components)?;
+        Some(components)
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DiscLayout::default().template()
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Template::parse(s)
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Template::parse(&s)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Parsing
+// ---------------------------------------------------------------------------
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn parse_nodes(chars: &mut Chars, in_optional: bool) -> Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut literal = String::new();
+
+    loop {
+        let Some(c) = chars.next() else {
+            if in_optional {
+                bail!("Unclosed '[' in template");
+            }
+            break;
+        };
+
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => literal.push(escaped),
+                None => bail!("Template ends with a dangling '\\'"),
+            },
+            '/' => {
+                flush_literal(&mut literal, &mut nodes);
+                nodes.push(Node::Separator);
+            }
+            '{' => {
+                flush_literal(&mut literal, &mut nodes);
+                nodes.push(Node::Field(parse_field(chars)?));
+            }
+            '}' => bail!("Unmatched '}}' in template"),
+            '[' => {
+                flush_literal(&mut literal, &mut nodes);
+                nodes.push(Node::Optional(parse_nodes(chars, true)?));
+            }
+            ']' if in_optional => break,
+            ']' => bail!("Unmatched ']' in template"),
+            _ => literal.push(c),
+        }
+    }
+
+    flush_literal(&mut literal, &mut nodes);
+    Ok(nodes)
+}
+
+fn flush_literal(literal: &mut String, nodes: &mut Vec<Node>) {
+    if !literal.is_empty() {
+        nodes.push(Node::Literal(std::mem::take(literal)));
+    }
+}
+
+fn parse_field(chars: &mut Chars) -> Result<Field> {
+    let mut body = String::new();
+    let mut in_quotes = false;
+    loop {
+        match chars.next() {
+            Some('"') => {
+                in_quotes = !in_quotes;
+                body.push('"');
+            }
+            Some('}') if !in_quotes => break,
+            Some(c) => body.push(c),
+            None => bail!("Unclosed '{{' in template"),
+        }
+    }
+
+    // Split off a trailing `:width` spec (only outside of quotes)
+    let (alts, width) = match split_unquoted(&body, ':').as_slice() {
+        [alts] => (alts.to_string(), None),
+        [alts, spec] => {
+            let width = spec
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("Invalid padding '{}' in {{{}}}", spec, body))?;
+            (alts.to_string(), Some(width))
+        }
+        _ => bail!("Too many ':' in {{{}}}", body),
+    };
+
+    let mut alternatives = Vec::new();
+    for alt in split_unquoted(&alts, '|') {
+        let alt = alt.trim();
+        if alt.len() >= 2 && alt.starts_with('"') && alt.ends_with('"') {
+            alternatives.push(Alternative::Literal(alt[1..alt.len() - 1].to_string()));
+        } else if FIELDS.contains(&alt) {
+            alternatives.push(Alternative::Field(alt.to_string()));
+        } else if alt.is_empty() {
+            bail!("Empty field in {{{}}}", body);
+        } else {
+            bail!(
+                "Unknown template field '{}' (known fields: {})",
+                alt,
+                FIELDS.join(", ")
+            );
+        }
+    }
+
+    Ok(Field {
+        alternatives,
+        width,
+    })
+}
+
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// ---------------------------------------------------------------------------
+// Rendering
+// ---------------------------------------------------------------------------
+
+fn render_nodes<F>(nodes: &[Node], lookup: &F, components: &mut Vec<String>) -> Option<()>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for node in nodes {
+        match node {
+            Node::Literal(s) => components.last_mut()?.push_str(s),
+            Node::Separator => components.push(String::new()),
+            Node::Field(field) => {
+                let value = resolve_field(field, lookup)?;
+                components.last_mut()?.push_str(&value);
+            }
+            Node::Optional(inner) => {
+                let mut scratch = components.clone();
+                if render_nodes(inner, lookup, &mut scratch).is_some() {
+                    *components = scratch;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+fn resolve_field<F>(field: &Field, lookup: &F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let value = field.alternatives.iter().find_map(|alt| match alt {
+        Alternative::Field(name) => lookup(name).filter(|v| !v.trim().is_empty()),
+        Alternative::Literal(text) => Some(text.clone()),
+    })?;
+
+    match (field.width, value.parse::<u64>()) {
+        (Some(width), Ok(n)) => Some(format!("{:0width$}", n, width = width)),
+        _ => Some(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "artist" => Some("Artist".to_string()),
+            "album" => Some("Album".to_string()),
+            "track" => Some("3".to_string()),
+            "filename" => Some("orig".to_string()),
+            "ext" => Some("flac".to_string()),
+            "title" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn default_template_renders_original_layout() {
+        let t = Template::default();
+        assert_eq!(
+            t.render(lookup).unwrap(),
+            vec!["Artist - Album", "03 - orig.flac"]
+        );
+    }
+
+    #[test]
+    fn default_templates_parse() {
+        for layout in [DiscLayout::Prefix, DiscLayout::Folder] {
+            layout.template();
+            layout.compilation_template();
+        }
+    }
+
+    #[test]
+    fn optional_section_dropped_when_field_missing() {
+        let t = Template::parse("[{title} - ]{album}").unwrap();
+        assert_eq!(t.render(lookup).unwrap(), vec!["Album"]);
+    }
+
+    #[test]
+    fn optional_section_with_separator_rolls_back() {
+        let t = Template::parse("{artist}/[{title}/]{album}.{ext}").unwrap();
+        assert_eq!(t.render(lookup).unwrap(), vec!["Artist", "Album.flac"]);
+    }
+
+    #[test]
+    fn required_field_missing_fails() {
+        let t = Template::parse("{title}.{ext}").unwrap();
+        assert!(t.render(lookup).is_none());
+    }
+
+    #[test]
+    fn fallback_to_literal() {
+        let t = Template::parse("{title|\"Untitled\"}").unwrap();
+        assert_eq!(t.render(lookup).unwrap(), vec!["Untitled"]);
+    }
+
+    #[test]
+    fn padding_only_applies_to_numbers() {
+        let t = Template::parse("{track:03} {artist:03}").unwrap();
+        assert_eq!(t.render(lookup).unwrap(), vec!["003 Artist"]);
+    }
+
+    #[test]
+    fn escapes_and_errors() {
+        let t = Template::parse("\\[{album}\\]").unwrap();
+        assert_eq!(t.render(lookup).unwrap(), vec!["[Album]"]);
+
+        assert!(Template::parse("{album").is_err());
+        assert!(Template::parse("[{album}").is_err());
+        assert!(Template::parse("{album}/").is_err());
+        assert!(Template::parse("{track:xx}").is_err());
+        assert!(Template::parse("").is_err());
+        assert!(Template::parse("{nonsense}")
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown template field"));
+    }
+}
@@ -0,0 +1,24 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory `tagmv_test_<name>` in the system temp directory,
+/// cleared of whatever an earlier run left there.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tagmv_test_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Four MPEG-1 layer III frames of 417 bytes, without a tag: the smallest
+/// MP3 that lofty reads and writes tags into.
+pub fn mpeg_frames() -> Vec<u8> {
+    let mut data = Vec::new();
+    for _ in 0..4 {
+        data.extend_from_slice(b"\xff\xfb\x90\x64");
+        data.resize(data.len() + 413, 0);
+    }
+    data
+}
@@ -0,0 +1,791 @@
+use crate::dedupe::{read_at, read_exact_at, skip_id3v2, strip_trailing_tags};
+use crate::tags::probe;
+use anyhow::Result;
+use lofty::file::FileType;
+use lofty::probe::Probe;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+/// How far into the audio the first MPEG frame is looked for.
+const MPEG_SYNC_WINDOW: usize = 64 * 1024;
+
+/// More than the longest MPEG frame (2881 bytes, MPEG-2.5 layer II).
+const MPEG_MAX_FRAME: usize = 4096;
+
+/// How much of a file the MPEG and Ogg walks read at a time.
+const CHUNK: usize = 256 * 1024;
+
+/// Bit rates in kbit/s by bit rate index, for MPEG-1 layers I, II, III and
+/// MPEG-2/2.5 layers I, II and III. Index 0 (free format) is not supported.
+const MPEG_BITRATES: [[u32; 15]; 5] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// MPEG-1 sample rates; MPEG-2 halves them and MPEG-2.5 quarters them.
+const MPEG_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// Smallest stretch at the end of a FLAC file searched for the last frame.
+const FLAC_MIN_TAIL: u64 = 64 * 1024;
+
+const OGG_CRC_TABLE: [u32; 256] = crc_table(0x04c1_1db7);
+
+/// What happens to files that cannot be read, or that `--validate` finds
+/// damaged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CorruptAction {
+    /// File them into the corrupt folder
+    #[default]
+    Quarantine,
+    /// Leave them where they are
+    Leave,
+    /// File them into the unsorted folder, like untagged files
+    Unsorted,
+}
+
+impl fmt::Display for CorruptAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CorruptAction::Quarantine => "quarantine",
+            CorruptAction::Leave => "leave",
+            CorruptAction::Unsorted => "unsorted",
+        })
+    }
+}
+
+/// What is wrong with a file that cannot be played in full.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Damage {
+    /// Byte offset of the problem, when it is known.
+    pub offset: Option<u64>,
+    pub error: String,
+}
+
+impl Damage {
+    fn at(offset: u64, error: impl Into<String>) -> Damage {
+        Damage {
+            offset: Some(offset),
+            error: error.into(),
+        }
+    }
+
+    /// Damage at an unknown offset.
+    pub fn new(error: impl ToString) -> Damage {
+        Damage {
+            offset: None,
+            error: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} (at byte {})", self.error, offset),
+            None => f.write_str(&self.error),
+        }
+    }
+}
+
+/// Why `path` cannot be read, if it is in a supported format and cannot.
+fn read_error(path: &Path) -> Option<Damage> {
+    let probe = match Probe::open(path) {
+        Ok(probe) => probe,
+        Err(e) => return Some(Damage::new(e)),
+    };
+    let probe = match probe.guess_file_type() {
+        Ok(probe) => probe,
+        Err(e) => return Some(Damage::new(e)),
+    };
+    probe.file_type()?;
+    probe.read().err().map(Damage::new)
+}
+
+/// Walk the stream of `path` to find damage that reading the tags does not
+/// notice, truncated downloads above all, then check that it can be read at
+/// all. The walk comes first, as it tells where a file is damaged:
+/// - MPEG: every frame must follow the previous one, and the last one must
+///   be complete
+/// - FLAC: the last frame must match its checksum, and end the stream at
+///   the sample count in STREAMINFO
+/// - Ogg: pages must be complete, match their checksums, follow each other
+///   without gaps and end with an end-of-stream page
+/// - MP4, WAV and AIFF: atoms and chunks must fit in the file
+///
+/// Other formats only get the read check.
+pub fn validate(path: &Path) -> Option<Damage> {
+    match check_stream(path) {
+        Ok(None) => read_error(path),
+        Ok(damage) => damage,
+        Err(e) => Some(Damage::new(e)),
+    }
+}
+
+fn check_stream(path: &Path) -> Result<Option<Damage>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let start = skip_id3v2(&mut file, len)?;
+    let mut magic = [0u8; 12];
+    let n = read_at(&mut file, start, &mut magic)?;
+    let magic = &magic[..n];
+
+    if magic.starts_with(b"fLaC") {
+        check_flac(&mut file, start, len)
+    } else if magic.starts_with(b"OggS") {
+        check_ogg(&mut Chunks::new(&mut file, len), start)
+    } else if magic.len() >= 8 && &magic[4..8] == b"ftyp" {
+        check_mp4(&mut file, start, len)
+    } else if magic.len() >= 12 && &magic[..4] == b"RIFF" && &magic[8..12] == b"WAVE" {
+        check_chunks(&mut file, start + 12, len, b"data", u32::from_le_bytes)
+    } else if magic.len() >= 12
+        && &magic[..4] == b"FORM"
+        && matches!(&magic[8..12], b"AIFF" | b"AIFC")
+    {
+        check_chunks(&mut file, start + 12, len, b"SSND", u32::from_be_bytes)
+    } else if probe(path).and_then(|p| p.file_type()) == Some(FileType::Mpeg) {
+        let end = strip_trailing_tags(&mut file, start, len)?;
+        check_mpeg(&mut Chunks::new(&mut file, end), start)
+    } else {
+        Ok(None)
+    }
+}
+
+/// A file up to `end`, read a chunk at a time, so walking small frames does
+/// not cost a read each and large files are never read whole.
+struct Chunks<'a> {
+    file: &'a mut File,
+    end: u64,
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(file: &'a mut File, end: u64) -> Chunks<'a> {
+        Chunks {
+            file,
+            end,
+            buf: Vec::new(),
+            buf_start: 0,
+        }
+    }
+
+    /// The `len` bytes at `pos`, or fewer at the end.
+    fn get(&mut self, pos: u64, len: usize) -> Result<&[u8]> {
+        let want_end = self.end.min(pos.saturating_add(len as u64));
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if pos < self.buf_start || want_end > buf_end {
+            let size = len.max(CHUNK).min(self.end.saturating_sub(pos) as usize);
+            self.buf.resize(size, 0);
+            let read = read_at(self.file, pos, &mut self.buf)?;
+            self.buf.truncate(read);
+            self.buf_start = pos;
+        }
+        let from = (pos - self.buf_start) as usize;
+        let to = (from + len).min(self.buf.len());
+        Ok(&self.buf[from.min(to)..to])
+    }
+
+    /// True if there is nothing but zeros from `pos` to the end.
+    fn zeros_from(&mut self, mut pos: u64) -> Result<bool> {
+        while pos < self.end {
+            let chunk = self.get(pos, CHUNK)?;
+            if chunk.is_empty() {
+                break;
+            }
+            if chunk.iter().any(|&b| b != 0) {
+                return Ok(false);
+            }
+            pos += chunk.len() as u64;
+        }
+        Ok(true)
+    }
+}
+
+/// Frames from `start` to the end of `data`. Decoders skip junk before the
+/// first frame, so the stream starts at the first two frames in a row;
+/// after that, each frame must start where the previous one ends. Zero
+/// padding after the last frame is fine.
+fn check_mpeg(data: &mut Chunks, start: u64) -> Result<Option<Damage>> {
+    let end = data.end;
+    let window = data.get(start, MPEG_SYNC_WINDOW + MPEG_MAX_FRAME)?;
+    let first = (0..window.len().min(MPEG_SYNC_WINDOW)).find(|&i| {
+        mpeg_frame_len(&window[i..]).is_some_and(|n| {
+            start + (i + n) as u64 == end || window.get(i + n..).and_then(mpeg_frame_len).is_some()
+        })
+    });
+    let Some(first) = first else {
+        return Ok(Some(Damage::at(start, "no MPEG frames found")));
+    };
+
+    let mut pos = start + first as u64;
+    while pos < end {
+        let left = end - pos;
+        let Some(len) = mpeg_frame_len(data.get(pos, 4)?) else {
+            if data.zeros_from(pos)? {
+                break;
+            }
+            if left < 4 {
+                return Ok(Some(Damage::at(pos, "last frame header is cut off")));
+            }
+            return Ok(Some(Damage::at(pos, "lost MPEG frame sync")));
+        };
+        if len as u64 > left {
+            let error = format!("last frame is cut off: {} of {} bytes", left, len);
+            return Ok(Some(Damage::at(pos, error)));
+        }
+        pos += len as u64;
+    }
+    Ok(None)
+}
+
+/// Length of the MPEG audio frame whose header starts `data`, if it is a
+/// valid header.
+fn mpeg_frame_len(data: &[u8]) -> Option<usize> {
+    let header: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    // Version 0 is MPEG-2.5, 1 is reserved, 2 is MPEG-2 and 3 is MPEG-1;
+    // layer 1 is layer III and 3 is layer I
+    let version = (header[1] >> 3) & 3;
+    let layer = (header[1] >> 1) & 3;
+    let bitrate_index = usize::from(header[2] >> 4);
+    let rate_index = usize::from((header[2] >> 2) & 3);
+    if version == 1 || layer == 0 || matches!(bitrate_index, 0 | 15) || rate_index == 3 {
+        return None;
+    }
+
+    let table = match (version, layer) {
+        (3, layer) => 3 - usize::from(layer),
+        (_, 3) => 3,
+        _ => 4,
+    };
+    let bitrate = MPEG_BITRATES[table][bitrate_index] * 1000;
+    let sample_rate = MPEG_SAMPLE_RATES[rate_index]
+        >> match version {
+            3 => 0,
+            2 => 1,
+            _ => 2,
+        };
+    let padding = u32::from((header[2] >> 1) & 1);
+
+    let len = match layer {
+        3 => (12 * bitrate / sample_rate + padding) * 4,
+        1 if version != 3 => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    };
+    Some(len as usize)
+}
+
+/// Metadata blocks, the first frame after them, and the last frame: it has
+/// to match its checksum up to the end of the audio (so it is not cut off),
+/// and end at the sample count in STREAMINFO (so no frames are missing).
+fn check_flac(file: &mut File, start: u64, len: u64) -> Result<Option<Damage>> {
+    let mut pos = start + 4;
+    let mut streaminfo = None;
+    loop {
+        if pos + 4 > len {
+            return Ok(Some(Damage::at(pos, "metadata is cut off")));
+        }
+        let header: [u8; 4] = read_exact_at(file, pos)?;
+        let size = u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+        let available = len - pos - 4;
+        if size > available {
+            let error = format!("metadata block is cut off: {} of {} bytes", available, size);
+            return Ok(Some(Damage::at(pos, error)));
+        }
+        if header[0] & 0x7f == 0 && size >= 34 {
+            streaminfo = Some(read_exact_at::<34>(file, pos + 4)?);
+        }
+        pos += 4 + size;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    let Some(info) = streaminfo else {
+        return Ok(Some(Damage::at(start, "no STREAMINFO block")));
+    };
+    let block_size = u64::from(u16::from_be_bytes([info[2], info[3]]));
+    let max_frame = u64::from(u32::from_be_bytes([0, info[7], info[8], info[9]]));
+    let total = (u64::from(info[13] & 0x0f) << 32)
+        | u64::from(u32::from_be_bytes([info[14], info[15], info[16], info[17]]));
+
+    let end = strip_trailing_tags(file, pos, len)?;
+    if pos == end {
+        return Ok((total > 0).then(|| Damage::at(pos, "no audio frames")));
+    }
+    let first: [u8; 2] = read_exact_at(file, pos)?;
+    if first[0] != 0xff || first[1] & 0xfe != 0xf8 {
+        return Ok(Some(Damage::at(pos, "no frame after the metadata")));
+    }
+
+    let tail_len = (max_frame * 2).max(FLAC_MIN_TAIL).min(end - pos);
+    let tail_start = end - tail_len;
+    let mut tail = vec![0u8; tail_len as usize];
+    read_at(file, tail_start, &mut tail)?;
+
+    // All frames of a stream have the same blocking strategy. Audio data
+    // can look like a frame header, so the last frame is the latest header
+    // whose frame checksum holds up to the end.
+    let mut headers = (0..tail.len()).rev().filter_map(|i| {
+        let (first_sample, samples) = flac_frame_header(&tail[i..], block_size)?;
+        let fits = tail[i + 1] == first[1] && (total == 0 || first_sample < total);
+        fits.then_some((i, first_sample + samples))
+    });
+    let Some((latest, _)) = headers.clone().next() else {
+        return Ok(Some(Damage::at(tail_start, "no frame header near the end")));
+    };
+    let last = headers.find(|&(i, _)| {
+        let (frame, footer) = tail[i..].split_at(tail.len() - i - 2);
+        crc16(frame).to_be_bytes() == footer
+    });
+    let Some((_, end_sample)) = last else {
+        let error = "last frame is cut off or damaged";
+        return Ok(Some(Damage::at(tail_start + latest as u64, error)));
+    };
+    if end_sample < total {
+        let error = format!("audio stops at sample {} of {}", end_sample, total);
+        return Ok(Some(Damage::at(end, error)));
+    }
+    Ok(None)
+}
+
+/// First sample and sample count of the FLAC frame whose header starts
+/// `data`, if it is a valid header. Fixed-blocksize streams number their
+/// frames rather than their samples, in blocks of `block_size`.
+fn flac_frame_header(data: &[u8], block_size: u64) -> Option<(u64, u64)> {
+    let header: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    let valid = header[0] == 0xff
+        && header[1] & 0xfe == 0xf8
+        && header[2] >> 4 != 0
+        && header[2] & 0x0f != 0x0f
+        && header[3] >> 4 <= 10
+        && (header[3] >> 1) & 7 != 3
+        && header[3] & 1 == 0;
+    if !valid {
+        return None;
+    }
+
+    // The frame or sample number is coded like UTF-8
+    let mut pos = 4;
+    let lead = *data.get(pos)?;
+    let (mut number, continuation) = match lead.leading_ones() {
+        0 => (u64::from(lead), 0),
+        n @ 2..=7 => (u64::from(lead & (0x7f >> n)), n - 1),
+        _ => return None,
+    };
+    pos += 1;
+    for _ in 0..continuation {
+        let byte = *data.get(pos)?;
+        if byte & 0xc0 != 0x80 {
+            return None;
+        }
+        number = (number << 6) | u64::from(byte & 0x3f);
+        pos += 1;
+    }
+
+    let samples = match header[2] >> 4 {
+        1 => 192,
+        n @ 2..=5 => 576u64 << (n - 2),
+        6 => {
+            pos += 1;
+            u64::from(*data.get(pos - 1)?) + 1
+        }
+        7 => {
+            pos += 2;
+            let bytes = data.get(pos - 2..pos)?;
+            u64::from(u16::from_be_bytes([bytes[0], bytes[1]])) + 1
+        }
+        n => 256u64 << (n - 8),
+    };
+    pos += match header[2] & 0x0f {
+        12 => 1,
+        13 | 14 => 2,
+        _ => 0,
+    };
+
+    if crc8(data.get(..pos)?) != *data.get(pos)? {
+        return None;
+    }
+    let first_sample = if header[1] & 1 == 0 {
+        number * block_size
+    } else {
+        number
+    };
+    Some((first_sample, samples))
+}
+
+/// Every page from `start` on: complete, with a matching checksum and the
+/// next sequence number of its logical stream. Every stream must end with
+/// a page flagged end-of-stream.
+fn check_ogg(data: &mut Chunks, start: u64) -> Result<Option<Damage>> {
+    // Logical stream serial number -> (next sequence number, ended)
+    let mut streams: HashMap<u32, (u32, bool)> = HashMap::new();
+    let mut pos = start;
+
+    while pos < data.end {
+        let header = data.get(pos, 27)?;
+        if !header.starts_with(b"OggS") {
+            if header.len() < 4 && b"OggS".starts_with(header) {
+                return Ok(Some(Damage::at(pos, "last page is cut off")));
+            }
+            return Ok(Some(Damage::at(pos, "lost Ogg page sync")));
+        }
+        let segments = header.get(26).map_or(0, |&n| usize::from(n));
+        let head = data.get(pos, 27 + segments)?;
+        let Some(table) = head.get(27..27 + segments) else {
+            return Ok(Some(Damage::at(pos, "last page header is cut off")));
+        };
+        let len = 27 + segments + table.iter().map(|&s| usize::from(s)).sum::<usize>();
+        let page = data.get(pos, len)?;
+        if page.len() < len {
+            let error = format!("last page is cut off: {} of {} bytes", page.len(), len);
+            return Ok(Some(Damage::at(pos, error)));
+        }
+
+        let field =
+            |at: usize| u32::from_le_bytes([page[at], page[at + 1], page[at + 2], page[at + 3]]);
+        if ogg_crc(page) != field(22) {
+            return Ok(Some(Damage::at(pos, "page checksum mismatch")));
+        }
+        let (serial, sequence, flags) = (field(14), field(18), page[5]);
+        // A chained file may start a new stream under a serial that ended
+        let restarts = flags & 0x02 != 0;
+        match streams.get(&serial) {
+            Some(&(_, true)) if restarts => {}
+            Some(&(expected, _)) if sequence < expected => {
+                return Ok(Some(Damage::at(pos, "page sequence goes backwards")));
+            }
+            Some(&(expected, _)) if sequence > expected => {
+                let error = format!("pages {} to {} are missing", expected, sequence - 1);
+                return Ok(Some(Damage::at(pos, error)));
+            }
+            _ => {}
+        }
+        streams.insert(serial, (sequence.wrapping_add(1), flags & 0x04 != 0));
+        pos += len as u64;
+    }
+
+    if streams.values().any(|&(_, ended)| !ended) {
+        let error = "stream ends without its last page";
+        return Ok(Some(Damage::at(data.end, error)));
+    }
+    Ok(None)
+}
+
+/// Top-level atoms: each must fit in the file, and one must be `mdat`.
+fn check_mp4(file: &mut File, start: u64, len: u64) -> Result<Option<Damage>> {
+    let mut pos = start;
+    let mut has_audio = false;
+    while pos + 8 <= len {
+        let header: [u8; 8] = read_exact_at(file, pos)?;
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into()?) {
+            0 => (len - pos, 8),
+            1 => (u64::from_be_bytes(read_exact_at(file, pos + 8)?), 16),
+            n => (u64::from(n), 8),
+        };
+        let name = String::from_utf8_lossy(&header[4..8]);
+        if size < header_len {
+            return Ok(Some(Damage::at(
+                pos,
+                format!("invalid size for {} atom", name),
+            )));
+        }
+        if size > len - pos {
+            let error = format!("{} atom is cut off: {} of {} bytes", name, len - pos, size);
+            return Ok(Some(Damage::at(pos, error)));
+        }
+        has_audio |= &header[4..8] == b"mdat";
+        pos += size;
+    }
+    Ok((!has_audio).then(|| Damage::new("no mdat atom")))
+}
+
+/// RIFF or AIFF chunks from `pos` on: each must fit in the file, and one
+/// must be the sample data chunk `id`.
+fn check_chunks(
+    file: &mut File,
+    mut pos: u64,
+    len: u64,
+    id: &[u8; 4],
+    size_of: fn([u8; 4]) -> u32,
+) -> Result<Option<Damage>> {
+    let mut has_audio = false;
+    while pos + 8 <= len {
+        let header: [u8; 8] = read_exact_at(file, pos)?;
+        let size = u64::from(size_of(header[4..8].try_into()?));
+        let available = len - pos - 8;
+        if size > available {
+            let name = String::from_utf8_lossy(&header[..4]);
+            let error = format!(
+                "{} chunk is cut off: {} of {} bytes",
+                name.trim_end(),
+                available,
+                size
+            );
+            return Ok(Some(Damage::at(pos, error)));
+        }
+        has_audio |= &header[..4] == id;
+        pos += 8 + size + (size & 1);
+    }
+    let error = format!("no {} chunk", String::from_utf8_lossy(id));
+    Ok((!has_audio).then(|| Damage::new(error)))
+}
+
+/// Table for an MSB-first CRC-32 with polynomial `poly`.
+const fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The Ogg page checksum, computed with the checksum field as zeros.
+fn ogg_crc(page: &[u8]) -> u32 {
+    page.iter().enumerate().fold(0, |crc, (i, &byte)| {
+        let byte = if (22..26).contains(&i) { 0 } else { byte };
+        (crc << 8) ^ OGG_CRC_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+/// FLAC frame header checksum: CRC-8, polynomial 0x07.
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// FLAC frame checksum: CRC-16, polynomial 0x8005.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mpeg_frames, temp_dir};
+    use std::fs;
+
+    type Walk = fn(&mut Chunks, u64) -> Result<Option<Damage>>;
+
+    /// `check` run over `data`, written to `path`.
+    fn walk(path: &Path, data: &[u8], check: Walk) -> Option<Damage> {
+        fs::write(path, data).unwrap();
+        let mut file = File::open(path).unwrap();
+        check(&mut Chunks::new(&mut file, data.len() as u64), 0).unwrap()
+    }
+
+    fn ogg_page(flags: u8, sequence: u32, data: &[u8]) -> Vec<u8> {
+        let mut page = vec![b'O', b'g', b'g', b'S', 0, flags];
+        page.extend_from_slice(&[0; 8]);
+        page.extend_from_slice(&[1, 0, 0, 0]);
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(1);
+        page.push(data.len() as u8);
+        page.extend_from_slice(data);
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    /// A fixed-blocksize FLAC frame of 4096 samples, with valid checksums
+    /// but no real audio.
+    fn flac_frame(number: u8) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xf8, 0xc9, 0x08, number];
+        frame.push(crc8(&frame));
+        frame.extend_from_slice(&[0x55; 40]);
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame
+    }
+
+    fn flac(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"fLaC\x80\x00\x00\x22".to_vec();
+        let mut info = [0u8; 34];
+        info[..4].copy_from_slice(&[0x10, 0x00, 0x10, 0x00]);
+        info[10..14].copy_from_slice(&[0x0a, 0xc4, 0x40, 0xf0]);
+        // 8192 samples in total
+        info[14..18].copy_from_slice(&8192u32.to_be_bytes());
+        data.extend_from_slice(&info);
+        data.extend(frames.concat());
+        data
+    }
+
+    #[test]
+    fn finds_cut_off_mpeg_frames() {
+        let tmp = temp_dir("validate_mpeg");
+        let check_mpeg = |data: &[u8]| walk(&tmp.join("a.mp3"), data, check_mpeg);
+        let data = mpeg_frames();
+        assert_eq!(mpeg_frame_len(&data), Some(417));
+        assert_eq!(check_mpeg(&data), None);
+
+        // Padding and junk before the first frame are fine
+        let mut padded = b"junk".to_vec();
+        padded.extend_from_slice(&data);
+        padded.extend_from_slice(&[0; 100]);
+        assert_eq!(check_mpeg(&padded), None);
+
+        let damage = check_mpeg(&data[..1500]).unwrap();
+        assert_eq!(damage.offset, Some(1251));
+        assert_eq!(damage.error, "last frame is cut off: 249 of 417 bytes");
+
+        let mut garbled = data.clone();
+        garbled[834] = 0;
+        assert_eq!(check_mpeg(&garbled).unwrap().offset, Some(834));
+        assert!(check_mpeg(&[0; 1000]).is_some());
+
+        // Longer than one chunk
+        let mut long = data.repeat(200);
+        assert_eq!(check_mpeg(&long), None);
+        long[700 * 417] = 0;
+        assert_eq!(check_mpeg(&long).unwrap().offset, Some(700 * 417));
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn checks_ogg_pages() {
+        let tmp = temp_dir("validate_ogg");
+        let check_ogg = |data: &[u8]| walk(&tmp.join("a.ogg"), data, check_ogg);
+        let mut data = ogg_page(0x02, 0, b"head");
+        data.extend(ogg_page(0, 1, b"audio"));
+        let end = ogg_page(0x04, 2, b"last");
+        let complete = [data.clone(), end.clone()].concat();
+        assert_eq!(check_ogg(&complete), None);
+
+        let damage = check_ogg(&complete[..complete.len() - 2]).unwrap();
+        assert_eq!(damage.offset, Some(data.len() as u64));
+        assert!(damage.error.starts_with("last page is cut off"));
+
+        let unfinished = check_ogg(&data).unwrap();
+        assert_eq!(unfinished.error, "stream ends without its last page");
+
+        let mut corrupted = complete.clone();
+        corrupted[30] ^= 1;
+        assert_eq!(check_ogg(&corrupted).unwrap().offset, Some(0));
+
+        let gap = [ogg_page(0x02, 0, b"head"), ogg_page(0x04, 3, b"last")].concat();
+        assert_eq!(check_ogg(&gap).unwrap().error, "pages 1 to 2 are missing");
+
+        let backwards = [ogg_page(0x02, 0, b"head"), ogg_page(0x04, 0, b"last")].concat();
+        assert_eq!(
+            check_ogg(&backwards).unwrap().error,
+            "page sequence goes backwards"
+        );
+        // Chained streams may reuse a serial number
+        let chained = [complete.clone(), complete.clone()].concat();
+        assert_eq!(check_ogg(&chained), None);
+
+        // Longer than one chunk
+        let long = complete.repeat(4000);
+        assert_eq!(check_ogg(&long), None);
+        assert!(check_ogg(&long[..long.len() - 1]).is_some());
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn finds_truncated_flac_streams() {
+        let tmp = temp_dir("validate_flac");
+        let check = |name: &str, data: &[u8]| {
+            let path = tmp.join(name);
+            fs::write(&path, data).unwrap();
+            let mut file = File::open(&path).unwrap();
+            check_flac(&mut file, 0, data.len() as u64).unwrap()
+        };
+
+        let complete = flac(&[flac_frame(0), flac_frame(1)]);
+        assert_eq!(check("complete.flac", &complete), None);
+
+        let cut = check("cut.flac", &complete[..complete.len() - 10]).unwrap();
+        assert_eq!(cut.error, "last frame is cut off or damaged");
+        assert_eq!(cut.offset, Some(42 + 48));
+
+        let short = check("short.flac", &flac(&[flac_frame(0)])).unwrap();
+        assert_eq!(short.error, "audio stops at sample 4096 of 8192");
+
+        // A frame header inside the last frame's audio is not taken for it
+        let mut fake = vec![0xff, 0xf8, 0xc9, 0x08, 1];
+        fake.push(crc8(&fake));
+        let mut last = flac_frame(1);
+        last.truncate(last.len() - 2);
+        last[10..16].copy_from_slice(&fake);
+        let crc = crc16(&last);
+        last.extend_from_slice(&crc.to_be_bytes());
+        let healthy = flac(&[flac_frame(0), last]);
+        assert_eq!(check("false_sync.flac", &healthy), None);
+
+        let header = check("header.flac", &complete[..30]).unwrap();
+        assert_eq!(header.offset, Some(4));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn validates_files() {
+        let tmp = temp_dir("validate");
+        let data = mpeg_frames();
+        fs::write(tmp.join("whole.mp3"), &data).unwrap();
+        fs::write(tmp.join("cut.mp3"), &data[..1500]).unwrap();
+        fs::write(tmp.join("text.mp3"), "not audio").unwrap();
+        fs::write(tmp.join("text.wma"), "not audio").unwrap();
+
+        assert_eq!(validate(&tmp.join("whole.mp3")), None);
+        let cut = validate(&tmp.join("cut.mp3")).unwrap();
+        assert_eq!(
+            cut.to_string(),
+            "last frame is cut off: 249 of 417 bytes (at byte 1251)"
+        );
+        // Reading the tags does not notice
+        assert_eq!(read_error(&tmp.join("cut.mp3")), None);
+
+        let unreadable = validate(&tmp.join("text.mp3")).unwrap();
+        assert_eq!(unreadable.to_string(), "no MPEG frames found (at byte 0)");
+        assert!(read_error(&tmp.join("text.mp3")).is_some());
+        assert_eq!(validate(&tmp.join("text.wma")), None);
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+}